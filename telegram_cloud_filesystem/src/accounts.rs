@@ -0,0 +1,317 @@
+//! Several Telegram accounts in one mount.
+//!
+//! Without an `[accounts]` section in the configuration, the daemon mounts one account and
+//! its chats are the top-level directories. With it, every account listed there becomes a
+//! top-level directory holding its chats (`~/mnt/telegram/<account>/<chat>/...`):
+//!
+//! ```toml
+//! [accounts.work]
+//! session = "work.session"
+//!
+//! [accounts.team-bot]
+//! session = "team-bot.session"
+//! bot_token_file = "~/.config/telegramfs/team-bot-token"
+//! ```
+//!
+//! Each account takes the keys of `[account]`, which provides the ones it leaves out; its
+//! session defaults to `<account>.session`. Sign in to one with
+//! `telegram_cloud_filesystem login --account <account>`.
+//!
+//! Each account has its own connection, update stream and refresh task, its own inode table
+//! and local names (`telegramfs.<account>.inodes` and `telegramfs.<account>.names`) and its
+//! own block cache in `<cache dir>/<account>`, with the configured sizes applying to every
+//! account. An account that cannot connect or is not signed in is left out with an error in
+//! the log, and one that fails later only fails operations on its own directory. Files cannot
+//! be moved or linked between accounts (EXDEV).
+//!
+//! The accounts still share the one thread the kernel's requests are handled on: while an
+//! operation waits for Telegram, operations on the other accounts wait as well. An account
+//! whose connection stalls holds up the whole mount until its calls are given up, after a
+//! minute, see the `deadline` module.
+//!
+//! Every account owns a range of inode numbers derived from its name, so they stay the same
+//! across mounts even as accounts are added or removed.
+
+use std::ffi::OsStr;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime};
+
+use fuser::{
+    FileType, Filesystem, Notifier, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyWrite,
+    Request, TimeOrNow,
+};
+use libc::{ENOENT, EPERM, EXDEV};
+
+use crate::config::{expand_home, Config};
+use crate::credentials::Credentials;
+use crate::inodes::ROOT_INO;
+use crate::{bot, dir_attr, Owner, TelegramFS};
+
+// Bits of an inode number below the account it belongs to
+pub const ACCOUNT_SHIFT: u32 = 40;
+
+// Number of inode ranges accounts are spread over
+const ACCOUNT_SLOTS: u64 = 1 << (64 - ACCOUNT_SHIFT);
+
+// How and as whom one account signs in
+#[derive(Clone)]
+pub struct Account {
+    // Name of its directory, None for the only account, whose chats are at the root
+    pub name: Option<String>,
+    pub credentials: Credentials,
+    pub session_file: PathBuf,
+    pub bot_token: Option<String>,
+}
+
+impl Account {
+    /* The account `name` from the `[accounts]` section of `config`, with the keys it leaves
+    out taken from `[account]`. `credentials_file` is the one given on the command line, which
+    comes before any other credentials.*/
+    pub fn configured(config: &Config, name: &str, credentials_file: Option<&std::path::Path>) -> Result<Self, String> {
+        let Some(account) = config.accounts.get(name) else {
+            return Err(format!("unknown account {name}, expected one of the [accounts] section of the configuration"));
+        };
+        if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\0']) {
+            return Err(format!("invalid account name {name:?}, it must be usable as a directory name"));
+        }
+        let defaults = &config.account;
+        let in_account = |e: String| format!("account {name}: {e}");
+
+        let configured = account.credentials.as_deref().or(defaults.credentials.as_deref()).map(expand_home);
+        let credentials = Credentials::find(
+            credentials_file,
+            account.api_id.or(defaults.api_id),
+            account.api_hash.as_deref().or(defaults.api_hash.as_deref()),
+            configured.as_deref(),
+        )
+        .map_err(in_account)?;
+        let session_file = account.session.as_deref().map_or_else(|| PathBuf::from(format!("{name}.session")), expand_home);
+        let bot_token = match &account.bot_token_file {
+            Some(path) => Some(bot::read_token(&expand_home(path)).map_err(|e| in_account(format!("failed to read bot_token_file {}: {e}", path.display())))?),
+            None => None,
+        };
+        Ok(Self { name: Some(name.to_string()), credentials, session_file, bot_token })
+    }
+
+    // Inode of the directory of the account; the mount root for the only account
+    pub fn root(&self) -> u64 {
+        match &self.name {
+            Some(name) => (slot(name) << ACCOUNT_SHIFT) | ROOT_INO,
+            None => ROOT_INO,
+        }
+    }
+
+    // Where the state kept in `file` (e.g. `telegramfs.inodes`) is kept for this account
+    pub fn state_file(&self, file: &str) -> PathBuf {
+        match (&self.name, file.split_once('.')) {
+            (Some(name), Some((stem, ext))) => PathBuf::from(format!("{stem}.{name}.{ext}")),
+            (Some(name), None) => PathBuf::from(format!("{file}.{name}")),
+            (None, _) => PathBuf::from(file),
+        }
+    }
+}
+
+// Fail if two of the accounts would share a range of inode numbers
+pub fn check_slots(accounts: &[Account]) -> Result<(), String> {
+    for (i, a) in accounts.iter().enumerate() {
+        for b in &accounts[i + 1..] {
+            if a.root() == b.root() {
+                return Err(format!("the accounts {} and {} cannot be mounted together, rename one of them", a.name.as_deref().unwrap_or_default(), b.name.as_deref().unwrap_or_default()));
+            }
+        }
+    }
+    Ok(())
+}
+
+// The range of inode numbers of the account `name`, never the one of the mount root
+fn slot(name: &str) -> u64 {
+    let digest = md5::compute(name.as_bytes());
+    let hash = u64::from_be_bytes([0, 0, 0, 0, 0, digest[0], digest[1], digest[2]]);
+    1 + hash % (ACCOUNT_SLOTS - 1)
+}
+
+/* The filesystem of several accounts: the root lists their directories, and everything
+else is handed to the account owning the inode it is about.*/
+pub struct Accounts {
+    accounts: Vec<(String, TelegramFS)>,
+    owner: Owner,
+    ttl: Duration,
+}
+
+impl Accounts {
+    pub fn new(accounts: Vec<(String, TelegramFS)>, owner: Owner, ttl: Duration) -> Self {
+        Self { accounts, owner, ttl }
+    }
+
+    // The channels each account tells the kernel about its changes through
+    pub fn notifiers(&self) -> Vec<Arc<OnceLock<Notifier>>> {
+        self.accounts.iter().map(|(_, fs)| Arc::clone(&fs.client.notifier)).collect()
+    }
+
+    // The account that owns inode `ino`
+    fn owner_of(&mut self, ino: u64) -> Option<&mut TelegramFS> {
+        self.accounts.iter_mut().map(|(_, fs)| fs).find(|fs| fs.client.root >> ACCOUNT_SHIFT == ino >> ACCOUNT_SHIFT)
+    }
+
+    fn root_attr(&self) -> fuser::FileAttr {
+        let mtime = self.accounts.iter().map(|(_, fs)| fs.root_attr().mtime).max().unwrap_or(SystemTime::UNIX_EPOCH);
+        dir_attr(ROOT_INO, mtime, self.owner)
+    }
+}
+
+impl Filesystem for Accounts {
+    fn destroy(&mut self) {
+        for (_, fs) in &mut self.accounts {
+            fs.destroy();
+        }
+    }
+
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        if parent == ROOT_INO {
+            match self.accounts.iter().find(|(account, _)| OsStr::new(account) == name) {
+                Some((_, fs)) => reply.entry(&self.ttl, &fs.root_attr(), 0),
+                None => reply.error(ENOENT),
+            }
+            return;
+        }
+        match self.owner_of(parent) {
+            Some(fs) => fs.lookup(req, parent, name, reply),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, req: &Request<'_>, ino: u64, fh: Option<u64>, reply: ReplyAttr) {
+        if ino == ROOT_INO {
+            reply.attr(&self.ttl, &self.root_attr());
+            return;
+        }
+        match self.owner_of(ino) {
+            Some(fs) => fs.getattr(req, ino, fh, reply),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn readdir(&mut self, req: &Request<'_>, ino: u64, fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        if ino != ROOT_INO {
+            match self.owner_of(ino) {
+                Some(fs) => fs.readdir(req, ino, fh, offset, reply),
+                None => reply.error(ENOENT),
+            }
+            return;
+        }
+        let mut entries = vec![(ROOT_INO, ".".to_string()), (ROOT_INO, "..".to_string())];
+        entries.extend(self.accounts.iter().map(|(name, fs)| (fs.client.root, name.clone())));
+        for (i, (ino, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(ino, i as i64 + 1, FileType::Directory, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn readlink(&mut self, req: &Request<'_>, ino: u64, reply: ReplyData) {
+        match self.owner_of(ino) {
+            Some(fs) => fs.readlink(req, ino, reply),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn read(&mut self, req: &Request, ino: u64, fh: u64, offset: i64, size: u32, flags: i32, lock: Option<u64>, reply: ReplyData) {
+        match self.owner_of(ino) {
+            Some(fs) => fs.read(req, ino, fh, offset, size, flags, lock, reply),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        match self.owner_of(ino) {
+            Some(fs) => fs.open(req, ino, flags, reply),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn create(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, umask: u32, flags: i32, reply: ReplyCreate) {
+        match self.owner_of(parent) {
+            Some(fs) => fs.create(req, parent, name, mode, umask, flags, reply),
+            None => reply.error(if parent == ROOT_INO { EPERM } else { ENOENT }),
+        }
+    }
+
+    fn write(&mut self, req: &Request<'_>, ino: u64, fh: u64, offset: i64, data: &[u8], write_flags: u32, flags: i32, lock_owner: Option<u64>, reply: ReplyWrite) {
+        match self.owner_of(ino) {
+            Some(fs) => fs.write(req, ino, fh, offset, data, write_flags, flags, lock_owner, reply),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn setattr(&mut self, req: &Request<'_>, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, atime: Option<TimeOrNow>, mtime: Option<TimeOrNow>, ctime: Option<SystemTime>, fh: Option<u64>, crtime: Option<SystemTime>, chgtime: Option<SystemTime>, bkuptime: Option<SystemTime>, flags: Option<u32>, reply: ReplyAttr) {
+        match self.owner_of(ino) {
+            Some(fs) => fs.setattr(req, ino, mode, uid, gid, size, atime, mtime, ctime, fh, crtime, chgtime, bkuptime, flags, reply),
+            None if ino == ROOT_INO => reply.attr(&self.ttl, &self.root_attr()),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.owner_of(parent) {
+            Some(fs) => fs.unlink(req, parent, name, reply),
+            None => reply.error(if parent == ROOT_INO { EPERM } else { ENOENT }),
+        }
+    }
+
+    fn link(&mut self, req: &Request<'_>, ino: u64, newparent: u64, newname: &OsStr, reply: ReplyEntry) {
+        if ino >> ACCOUNT_SHIFT != newparent >> ACCOUNT_SHIFT {
+            reply.error(if newparent == ROOT_INO { EPERM } else { EXDEV });
+            return;
+        }
+        match self.owner_of(ino) {
+            Some(fs) => fs.link(req, ino, newparent, newname, reply),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn mkdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, umask: u32, reply: ReplyEntry) {
+        match self.owner_of(parent) {
+            Some(fs) => fs.mkdir(req, parent, name, mode, umask, reply),
+            // Accounts come from the configuration
+            None => reply.error(if parent == ROOT_INO { EPERM } else { ENOENT }),
+        }
+    }
+
+    fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.owner_of(parent) {
+            Some(fs) => fs.rmdir(req, parent, name, reply),
+            None => reply.error(if parent == ROOT_INO { EPERM } else { ENOENT }),
+        }
+    }
+
+    fn rename(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, flags: u32, reply: ReplyEmpty) {
+        if parent == ROOT_INO || newparent == ROOT_INO {
+            reply.error(EPERM);
+            return;
+        }
+        if parent >> ACCOUNT_SHIFT != newparent >> ACCOUNT_SHIFT {
+            reply.error(EXDEV);
+            return;
+        }
+        match self.owner_of(parent) {
+            Some(fs) => fs.rename(req, parent, name, newparent, newname, flags, reply),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn flush(&mut self, req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        match self.owner_of(ino) {
+            Some(fs) => fs.flush(req, ino, fh, lock_owner, reply),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn release(&mut self, req: &Request<'_>, ino: u64, fh: u64, flags: i32, lock_owner: Option<u64>, flush: bool, reply: ReplyEmpty) {
+        match self.owner_of(ino) {
+            Some(fs) => fs.release(req, ino, fh, flags, lock_owner, flush, reply),
+            None => reply.error(ENOENT),
+        }
+    }
+}
@@ -0,0 +1,233 @@
+//! Disk-backed cache for blocks of downloaded file content.
+//!
+//! Every block is stored in its own file at `<dir>/<chat id>/<message id>/<block offset>`.
+//! A block file starts with the MD5 digest of the block, followed by the block bytes, so
+//! a block that was truncated or corrupted on disk is detected when it is read, removed,
+//! and downloaded again. The cache survives remounts: on startup the directory is scanned
+//! and blocks are ordered by their modification time, which is bumped on every hit.
+//! Once the total size exceeds the configured capacity, the least recently used
+//! blocks are evicted.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::persist;
+
+// Length of the MD5 digest stored in front of every block
+const DIGEST_LEN: usize = 16;
+
+/* Identifies one block of a file: the chat and message carrying the media,
+and the offset of the block within the file.*/
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub struct BlockKey {
+    pub chat_id: i64,
+    pub message_id: i32,
+    pub offset: u64,
+}
+
+// Size of a block file on disk and the tick of its last use
+struct Entry {
+    size: u64,
+    tick: u64,
+}
+
+pub struct BlockCache {
+    dir: PathBuf,
+    capacity: u64,
+    used: u64,
+    entries: HashMap<BlockKey, Entry>,
+    // Blocks ordered by last use, least recently used first
+    lru: BTreeMap<u64, BlockKey>,
+    tick: u64,
+}
+
+impl BlockCache {
+    /* Open the cache stored in `dir`, creating the directory if needed.
+    Blocks already on disk are picked up, and evicted right away if they
+    no longer fit into `capacity` bytes.*/
+    pub fn open(dir: &Path, capacity: u64) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let mut cache = Self {
+            dir: dir.to_path_buf(),
+            capacity,
+            used: 0,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+        };
+
+        // Register existing blocks from the oldest to the most recently used one
+        let mut found = cache.scan()?;
+        found.sort_by_key(|(_, _, modified)| *modified);
+        for (key, size, _) in found {
+            cache.insert_entry(key, size);
+        }
+        cache.evict();
+
+        log::info!(
+            "block cache at {} holds {} blocks ({} bytes)",
+            cache.dir.display(),
+            cache.entries.len(),
+            cache.used
+        );
+        Ok(cache)
+    }
+
+    /* Return the block stored under `key` if it is present and intact.
+    `expected_len` is the length the block must have; a block file that is
+    shorter or whose digest does not match is removed so it gets downloaded again.*/
+    pub fn get(&mut self, key: BlockKey, expected_len: usize) -> Option<Vec<u8>> {
+        if !self.entries.contains_key(&key) {
+            return None;
+        }
+
+        let path = self.block_path(key);
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) => {
+                log::warn!("dropping unreadable cache block {}: {e}", path.display());
+                self.remove(key);
+                return None;
+            }
+        };
+
+        // Verify the length and the digest before trusting the block
+        let valid = data.len() == DIGEST_LEN + expected_len
+            && md5::compute(&data[DIGEST_LEN..]).0 == data[..DIGEST_LEN];
+        if !valid {
+            log::warn!("dropping damaged cache block {}", path.display());
+            self.remove(key);
+            return None;
+        }
+
+        // Mark the block as recently used, in memory and on disk for the next mount
+        self.touch(key);
+        if let Ok(file) = fs::File::options().write(true).open(&path) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+
+        Some(data[DIGEST_LEN..].to_vec())
+    }
+
+    /* Store `data` as the block under `key`.
+    A crash never leaves a half-written block behind under its real name, see the `persist` module.*/
+    pub fn put(&mut self, key: BlockKey, data: &[u8]) {
+        if (DIGEST_LEN + data.len()) as u64 > self.capacity {
+            return;
+        }
+
+        let path = self.block_path(key);
+        let result = (|| {
+            fs::create_dir_all(path.parent().unwrap())?;
+            let mut contents = Vec::with_capacity(DIGEST_LEN + data.len());
+            contents.extend_from_slice(&md5::compute(data).0);
+            contents.extend_from_slice(data);
+            persist::write(&path, contents)
+        })();
+        if let Err(e) = result {
+            log::warn!("failed to store cache block {}: {e}", path.display());
+            return;
+        }
+
+        self.remove_entry(key);
+        self.insert_entry(key, (DIGEST_LEN + data.len()) as u64);
+        self.evict();
+    }
+
+    // Remove all blocks of the file carried by message `message_id` in chat `chat_id`
+    pub fn remove_message(&mut self, chat_id: i64, message_id: i32) {
+        let keys: Vec<BlockKey> = self
+            .entries
+            .keys()
+            .filter(|k| k.chat_id == chat_id && k.message_id == message_id)
+            .copied()
+            .collect();
+        for key in keys {
+            self.remove(key);
+        }
+        let _ = fs::remove_dir(self.dir.join(chat_id.to_string()).join(message_id.to_string()));
+    }
+
+    // Path of the file holding the block under `key`
+    fn block_path(&self, key: BlockKey) -> PathBuf {
+        self.dir
+            .join(key.chat_id.to_string())
+            .join(key.message_id.to_string())
+            .join(key.offset.to_string())
+    }
+
+    // Find all block files in the cache directory, with their size and modification time
+    fn scan(&self) -> io::Result<Vec<(BlockKey, u64, SystemTime)>> {
+        let mut found = vec![];
+        for chat in fs::read_dir(&self.dir)? {
+            let chat = chat?;
+            let Some(chat_id) = chat.file_name().to_str().and_then(|n| n.parse().ok()) else {
+                continue;
+            };
+            for message in fs::read_dir(chat.path())? {
+                let message = message?;
+                let Some(message_id) = message.file_name().to_str().and_then(|n| n.parse().ok()) else {
+                    continue;
+                };
+                for block in fs::read_dir(message.path())? {
+                    let block = block?;
+                    let name = block.file_name();
+                    // Leftover temporary files come from interrupted writes
+                    let Some(offset) = name.to_str().and_then(|n| n.parse().ok()) else {
+                        let _ = fs::remove_file(block.path());
+                        continue;
+                    };
+                    let metadata = block.metadata()?;
+                    let key = BlockKey { chat_id, message_id, offset };
+                    found.push((key, metadata.len(), metadata.modified()?));
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    // Evict the least recently used blocks until the cache fits its capacity again
+    fn evict(&mut self) {
+        while self.used > self.capacity {
+            let Some((_, &key)) = self.lru.first_key_value() else {
+                break;
+            };
+            self.remove(key);
+        }
+    }
+
+    // Remove the block under `key` from disk and from the index
+    fn remove(&mut self, key: BlockKey) {
+        let path = self.block_path(key);
+        if let Err(e) = fs::remove_file(&path)
+            && e.kind() != io::ErrorKind::NotFound
+        {
+            log::warn!("failed to remove cache block {}: {e}", path.display());
+        }
+        self.remove_entry(key);
+    }
+
+    fn touch(&mut self, key: BlockKey) {
+        if let Some(size) = self.remove_entry(key) {
+            self.insert_entry(key, size);
+        }
+    }
+
+    fn insert_entry(&mut self, key: BlockKey, size: u64) {
+        self.tick += 1;
+        self.used += size;
+        self.entries.insert(key, Entry { size, tick: self.tick });
+        self.lru.insert(self.tick, key);
+    }
+
+    // Drop `key` from the index, returning the size it accounted for
+    fn remove_entry(&mut self, key: BlockKey) -> Option<u64> {
+        let entry = self.entries.remove(&key)?;
+        self.lru.remove(&entry.tick);
+        self.used -= entry.size;
+        Some(entry.size)
+    }
+}
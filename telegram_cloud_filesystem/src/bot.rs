@@ -0,0 +1,123 @@
+//! Mounting the chats of a bot.
+//!
+//! Instead of a person's account, the daemon can sign in as a bot, with the token from
+//! @BotFather given with `--bot-token-file <file>` (the token on its first line),
+//! `account.bot_token_file` in the configuration or the `TG_BOT_TOKEN` environment variable.
+//! Signing in as a bot needs no code or password, so the daemon does it by itself on its first
+//! start and saves the session; use a session file of its own for it (`--session <file>`).
+//!
+//! Bots cannot list their dialogs or read the history of a chat, so:
+//! - their chats are the ones listed by id in `[chats] include` (in the `-100...` form of the
+//!   Bot API for channels and supergroups), and any chat the bot gets a message from
+//! - a chat is scanned by fetching its messages by id in batches, from its newest message
+//!   down to the first one, when it is first seen and again about once a day; updates keep
+//!   it current in between, and every `--refresh-interval` the ids above the newest one seen
+//!   are fetched, for messages the updates missed. In private chats and basic groups message
+//!   ids count up across all chats of the bot, so a full scan of each takes as many requests
+//!   as the bot has messages in all of them, over a hundred
+//! - forum topics are not shown as directories
+//! - `mkdir` at the mount root fails, as bots cannot create chats
+//!
+//! A bot only sees messages in groups if it is an admin there or its privacy mode is off.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use grammers_client::grammers_tl_types as tl;
+use grammers_client::types::{Message, PackedChat};
+use grammers_client::{Client, InvocationError};
+
+// How many message ids to ask for per request; Telegram returns at most 100 messages at once
+const BATCH: i32 = 100;
+
+// Whether the client is signed in as a bot
+pub fn is_bot(client: &Client) -> bool {
+    client.session().get_user().is_some_and(|user| user.bot)
+}
+
+// The bot token on the first line of the file at `path`
+pub fn read_token(path: &Path) -> io::Result<String> {
+    let contents = fs::read_to_string(path)?;
+    let token = contents.lines().next().unwrap_or_default().trim();
+    check_token(token).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(token.to_string())
+}
+
+// Whether `token` looks like a token from @BotFather: the bot id, a colon and a secret
+pub fn check_token(token: &str) -> Result<(), String> {
+    match token.split_once(':') {
+        Some((id, secret)) if !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit()) && !secret.is_empty() => Ok(()),
+        _ => Err("expected a bot token like 123456:ABC-DEF...".to_string()),
+    }
+}
+
+/* The id of the newest message of `chat` as Telegram tells it: from the chat list, which
+bots cannot read, or else for channels and supergroups from their read marks and pinned
+message, which may be short of the newest one. None if Telegram tells nothing.*/
+pub async fn top_message_id(client: &Client, chat: PackedChat) -> Result<Option<i32>, InvocationError> {
+    let request = tl::functions::messages::GetPeerDialogs {
+        peers: vec![tl::enums::InputDialogPeer::Peer(tl::types::InputDialogPeer { peer: chat.to_input_peer() })],
+    };
+    let error = match client.invoke(&request).await {
+        Ok(tl::enums::messages::PeerDialogs::Dialogs(found)) => {
+            let top = found.dialogs.iter().find_map(|dialog| match dialog {
+                tl::enums::Dialog::Dialog(dialog) => Some(dialog.top_message),
+                tl::enums::Dialog::Folder(_) => None,
+            });
+            return Ok(top);
+        }
+        Err(e) => e,
+    };
+    let Some(channel) = chat.try_to_input_channel() else {
+        return if error.is("BOT_METHOD_INVALID") { Ok(None) } else { Err(error) };
+    };
+    let tl::enums::messages::ChatFull::Full(full) = client.invoke(&tl::functions::channels::GetFullChannel { channel }).await?;
+    let top = match full.full_chat {
+        tl::enums::ChatFull::ChannelFull(full) => full.read_inbox_max_id.max(full.read_outbox_max_id).max(full.pinned_msg_id.unwrap_or(0)),
+        tl::enums::ChatFull::Full(full) => full.pinned_msg_id.unwrap_or(0),
+    };
+    Ok(Some(top).filter(|&top| top > 0))
+}
+
+/* The messages of a chat, fetched by id as bots cannot read its history. `top` is the newest
+id known to exist, 0 if none is. As it may be short of the newest message, the scan first asks
+for the ids above it, batch by batch until one comes back empty, then for every id from `top`
+down to the one above `floor`, so messages below any gap left by deleted ones are found all the
+same. A full scan has a `floor` of 0, one for new messages the newest id seen before.*/
+pub struct IdScan {
+    chat: PackedChat,
+    // First id of the next batch above `top`, None once one came back empty
+    above: Option<i32>,
+    // Highest id below `top` not asked for yet
+    below: i32,
+    // Highest id not to ask for
+    floor: i32,
+    pending: VecDeque<Message>,
+}
+
+impl IdScan {
+    pub fn new(chat: PackedChat, top: i32, floor: i32) -> Self {
+        Self { chat, above: Some(top.max(floor) + 1), below: top, floor, pending: VecDeque::new() }
+    }
+
+    pub async fn next(&mut self, client: &Client) -> Result<Option<Message>, InvocationError> {
+        loop {
+            if let Some(msg) = self.pending.pop_front() {
+                return Ok(Some(msg));
+            }
+            let ids: Vec<i32> = match self.above {
+                Some(first) => (first..first + BATCH).collect(),
+                None if self.below > self.floor => ((self.below - BATCH + 1).max(self.floor + 1)..=self.below).rev().collect(),
+                None => return Ok(None),
+            };
+            self.pending = client.get_messages_by_id(self.chat, &ids).await?.into_iter().flatten().collect();
+            match self.above {
+                Some(first) if !self.pending.is_empty() => self.above = Some(first + BATCH),
+                Some(_) => self.above = None,
+                None => self.below -= ids.len() as i32,
+            }
+        }
+    }
+}
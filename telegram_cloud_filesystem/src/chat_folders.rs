@@ -0,0 +1,119 @@
+//! Telegram chat folders, for choosing chats by the folder they are in with `[chats]`.
+//!
+//! Chat folders are the tabs of the chat list the account set up in its Telegram apps
+//! (Settings > Chat Folders). A chat is in a folder when the folder lists it, or when it is of
+//! a kind the folder takes in (contacts, non-contacts, groups, channels or bots) and not
+//! excluded for being muted, read or archived; chats the folder excludes never are. The
+//! folders are fetched again on every reconciliation pass, so changes show up with it.
+//!
+//! Bots have no chat folders, so their chats are in none.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use grammers_client::grammers_tl_types as tl;
+use grammers_client::types::{Chat, Dialog};
+use grammers_client::{Client, InvocationError};
+
+// The id of the archive among the peer folders of Telegram
+const ARCHIVE_FOLDER_ID: i32 = 1;
+
+// The state of a chat in the chat list that folders and `archived` rules depend on
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DialogState {
+    pub archived: bool,
+    pub muted: bool,
+    pub unread: bool,
+}
+
+impl DialogState {
+    pub fn of(dialog: &Dialog) -> Self {
+        let tl::enums::Dialog::Dialog(dialog) = &dialog.raw else {
+            return Self::default();
+        };
+        let tl::enums::PeerNotifySettings::Settings(settings) = &dialog.notify_settings;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+        Self {
+            archived: dialog.folder_id == Some(ARCHIVE_FOLDER_ID),
+            muted: settings.mute_until.is_some_and(|until| i64::from(until) > now),
+            unread: dialog.unread_count > 0 || dialog.unread_mark,
+        }
+    }
+}
+
+// A chat folder of the account
+#[derive(Clone, Debug)]
+pub struct ChatFolder {
+    pub title: String,
+    filter: tl::types::DialogFilter,
+}
+
+impl ChatFolder {
+    // Whether `chat`, in the state `state`, is in this folder
+    pub fn contains(&self, chat: &Chat, state: &DialogState) -> bool {
+        let filter = &self.filter;
+        if filter.exclude_peers.iter().any(|peer| refers_to(peer, chat)) {
+            return false;
+        }
+        if filter.pinned_peers.iter().chain(&filter.include_peers).any(|peer| refers_to(peer, chat)) {
+            return true;
+        }
+        let kind = match chat {
+            Chat::User(user) if user.is_bot() => filter.bots,
+            Chat::User(user) if user.is_self() => false,
+            Chat::User(user) if user.contact() => filter.contacts,
+            Chat::User(_) => filter.non_contacts,
+            Chat::Group(_) => filter.groups,
+            Chat::Channel(_) => filter.broadcasts,
+        };
+        kind && !(filter.exclude_muted && state.muted) && (state.unread || !filter.exclude_read) && !(filter.exclude_archived && state.archived)
+    }
+}
+
+// The chat folders of the account signed in with `client`
+pub async fn fetch(client: &Client) -> Result<Vec<ChatFolder>, InvocationError> {
+    let tl::enums::messages::DialogFilters::Filters(filters) = client.invoke(&tl::functions::messages::GetDialogFilters {}).await?;
+    Ok(filters
+        .filters
+        .into_iter()
+        .filter_map(|filter| match filter {
+            tl::enums::DialogFilter::Filter(filter) => Some(ChatFolder { title: filter.title.clone(), filter }),
+            // Shared folders only hold the chats they list
+            tl::enums::DialogFilter::Chatlist(list) => Some(ChatFolder {
+                title: list.title.clone(),
+                filter: tl::types::DialogFilter {
+                    contacts: false,
+                    non_contacts: false,
+                    groups: false,
+                    broadcasts: false,
+                    bots: false,
+                    exclude_muted: false,
+                    exclude_read: false,
+                    exclude_archived: false,
+                    id: list.id,
+                    title: list.title,
+                    emoticon: list.emoticon,
+                    color: list.color,
+                    pinned_peers: list.pinned_peers,
+                    include_peers: list.include_peers,
+                    exclude_peers: vec![],
+                },
+            }),
+            // "All chats" is not a folder of its own
+            tl::enums::DialogFilter::Default => None,
+        })
+        .collect())
+}
+
+// Whether `peer`, as a folder lists it, is `chat`
+fn refers_to(peer: &tl::enums::InputPeer, chat: &Chat) -> bool {
+    use tl::enums::InputPeer;
+    match (peer, chat) {
+        (InputPeer::PeerSelf, Chat::User(user)) => user.is_self(),
+        (InputPeer::User(peer), Chat::User(user)) => peer.user_id == user.id(),
+        (InputPeer::UserFromMessage(peer), Chat::User(user)) => peer.user_id == user.id(),
+        (InputPeer::Chat(peer), Chat::Group(group)) => peer.chat_id == group.id(),
+        (InputPeer::Channel(peer), Chat::Group(_) | Chat::Channel(_)) => peer.channel_id == chat.id(),
+        (InputPeer::ChannelFromMessage(peer), Chat::Group(_) | Chat::Channel(_)) => peer.channel_id == chat.id(),
+        _ => false,
+    }
+}
@@ -0,0 +1,62 @@
+//! Directory names for chats.
+//!
+//! A chat folder is named after the title of its chat, made safe for a file name like the
+//! names of files (see `naming::sanitize`), and cut if it is longer than a file name allows.
+//! Chats without a usable title (deleted accounts, `.` or `..`) are named after their
+//! @username, or else `chat-<id>`.
+//!
+//! Titles are not unique, so when another chat has the name already, the chat gets its
+//! @username appended (`Notes @notes_bot`), or else its id (`Notes (123456)`). Should another
+//! chat be titled like that too, the chat folder is `chat-<id>`, numbered if even that is
+//! taken (`chat-123456 (2)`). A chat keeps
+//! the name it was given while it is mounted, so a chat that joins later with the same title
+//! never renames an existing folder; which of two chats gets the plain name may differ between
+//! mounts, as it depends on the order they are seen in.
+//!
+//! When a chat is renamed, its folder moves to the new name and keeps its inode, so open files
+//! and directories in it stay usable. With `--by-id` (or `by_id = true` in `[mount]`), the
+//! mount also has a `by-id` directory with a symbolic link per chat folder, named after the
+//! chat id and pointing at the folder, for paths that do not change with titles.
+
+use std::collections::HashMap;
+
+use grammers_client::types::{Chat, PackedChat};
+
+use crate::naming::{cut, sanitize};
+
+// Name of the directory of links by chat id
+pub const BY_ID_DIR: &str = "by-id";
+
+// Longest title kept, in bytes, leaving room for a suffix within the 255 bytes of a file name
+const MAX_TITLE_LEN: usize = 200;
+
+/* The name of the folder of `chat`, given the names the other chats in `chats` have.
+`reserved` is taken by the mount itself. A name the chat has already is kept if it still fits
+its title.*/
+pub fn dir_name(chats: &HashMap<String, PackedChat>, chat: &Chat, reserved: Option<&str>) -> String {
+    let base = title(chat);
+    let mut candidates = vec![base.clone()];
+    candidates.extend(chat.username().map(|username| format!("{base} @{username}")));
+    candidates.push(format!("{base} ({})", chat.id()));
+    candidates.push(format!("chat-{}", chat.id()));
+
+    let taken = |name: &str| Some(name) == reserved || chats.get(name).is_some_and(|other| other.id != chat.id());
+    let current = chats.iter().find(|(_, packed)| packed.id == chat.id()).map(|(name, _)| name);
+    if let Some(current) = current.filter(|current| candidates.contains(current) && !taken(current)) {
+        return current.clone();
+    }
+    // Other chats may be titled like any of these, so the last one is numbered until it is free
+    let numbered = (2..).map(|n| format!("chat-{} ({n})", chat.id()));
+    candidates.into_iter().chain(numbered).find(|name| !taken(name)).unwrap()
+}
+
+// The title of `chat` as a file name, or what stands in for it
+fn title(chat: &Chat) -> String {
+    let Some(title) = sanitize(chat.name()) else {
+        return match chat.username() {
+            Some(username) => format!("@{username}"),
+            None => format!("chat-{}", chat.id()),
+        };
+    };
+    cut(&title, MAX_TITLE_LEN).to_string()
+}
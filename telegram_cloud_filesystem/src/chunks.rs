@@ -0,0 +1,104 @@
+//! Files larger than Telegram's per-file limit.
+//!
+//! Telegram caps a single document at 2 GB (4 GB for premium accounts). Larger files are
+//! split into parts of `--part-size` bytes, each uploaded as a document of its own named
+//! `<name>.part001`, `<name>.part002`, ... and marked in its caption:
+//!
+//! ```text
+//! tgfs: part=1/3
+//! ```
+//!
+//! Once all parts are sent, a manifest message lists them in order. The manifest is a text
+//! message whose caption is only the metadata line (see the `meta` module), and it is what
+//! the mount shows as the file, with the full size:
+//!
+//! ```text
+//! tgfs: name=backup.tar size=4500000000 part_size=2097152000 parts=101,102,103
+//! ```
+//!
+//! Telegram does not accept empty documents, so an empty file is a manifest without parts:
+//!
+//! ```text
+//! tgfs: name=empty.txt size=0 part_size=2097152000
+//! ```
+//!
+//! Parts are hidden from the listing. Reads are served from the part holding each block;
+//! if a part's message is gone, reading the affected range fails with EIO instead of
+//! returning truncated data.
+
+use grammers_client::types::Message;
+
+use crate::meta::Meta;
+
+/* The parts of a file split by its size:
+- the size of the whole file (`size`)
+- the size of every part but the last (`part_size`)
+- the ids of the messages carrying the parts, in order (`parts`)*/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Manifest {
+    pub size: u64,
+    pub part_size: u64,
+    pub parts: Vec<i32>,
+}
+
+impl Manifest {
+    // Read a manifest from caption metadata; None if it lacks a field or does not add up
+    pub fn parse(meta: &Meta) -> Option<Self> {
+        let size = meta.get("size")?.parse().ok()?;
+        let part_size: u64 = meta.get("part_size")?.parse().ok()?;
+        let parts: Vec<i32> = match meta.get("parts") {
+            Some(parts) => parts.split(',').map(|id| id.parse().ok()).collect::<Option<_>>()?,
+            None => vec![],
+        };
+        let count = parts.len() as u64;
+        let fits = part_size > 0
+            && match count {
+                0 => size == 0,
+                _ => size > (count - 1) * part_size && size <= count * part_size,
+            };
+        fits.then_some(Self { size, part_size, parts })
+    }
+
+    // Write the manifest into caption metadata, keeping the other fields
+    pub fn store(&self, meta: &mut Meta) {
+        let parts: Vec<String> = self.parts.iter().map(i32::to_string).collect();
+        let parts = parts.join(",");
+        meta.set("size", Some(&self.size.to_string()));
+        meta.set("part_size", Some(&self.part_size.to_string()));
+        meta.set("parts", (!parts.is_empty()).then_some(&parts));
+    }
+
+    // The index of the part holding byte `offset` of the file, and the offset inside that part
+    pub fn locate(&self, offset: u64) -> (usize, u64) {
+        ((offset / self.part_size) as usize, offset % self.part_size)
+    }
+}
+
+// The manifest `msg` carries, or None if it is not a manifest
+pub fn manifest(msg: &Message) -> Option<Manifest> {
+    if msg.media().is_some() {
+        return None;
+    }
+    let meta = Meta::parse(msg.text());
+    if !meta.text.is_empty() {
+        return None;
+    }
+    Manifest::parse(&meta)
+}
+
+// Whether `msg` carries a part of a split file
+pub fn is_part(msg: &Message) -> bool {
+    msg.media().is_some() && Meta::parse(msg.text()).get("part").is_some()
+}
+
+// Name of the document carrying part `index` (from zero) of the file `name`
+pub fn part_name(name: &str, index: usize) -> String {
+    format!("{name}.part{:03}", index + 1)
+}
+
+// Caption of the message carrying part `index` (from zero) of `count`
+pub fn part_caption(index: usize, count: usize) -> String {
+    let mut meta = Meta::default();
+    meta.set("part", Some(&format!("{}/{count}", index + 1)));
+    meta.to_caption()
+}
@@ -0,0 +1,362 @@
+//! The configuration file of the filesystem daemon.
+//!
+//! Settings are read from the file given with `--config <file>`, or else from
+//! `telegramfs.toml` in the working directory or `$XDG_CONFIG_HOME/telegramfs/telegramfs.toml`
+//! (`~/.config/telegramfs/telegramfs.toml`), whichever exists first. Without a file every
+//! setting has its default. Command line flags override the file. All sections and keys are
+//! optional; unknown keys are rejected, so typos do not go unnoticed:
+//!
+//! ```toml
+//! [account]
+//! api_id = 12345              # or in a credentials file, see the `credentials` module
+//! api_hash = "0123456789abcdef0123456789abcdef"
+//! credentials = "~/.config/telegramfs/credentials"
+//! session = "downloader.session"
+//! password_file = "~/.config/telegramfs/password"   # two-step verification, for `login`
+//! bot_token_file = "~/.config/telegramfs/bot-token" # sign in as a bot, see the `bot` module
+//!
+//! [accounts.work]             # mount several accounts, see the `accounts` module
+//! session = "work.session"
+//!
+//! [mount]
+//! mountpoint = "~/mnt/telegram"
+//! uid = 1000
+//! gid = 1000
+//! allow_other = true
+//! attr_timeout = 300          # seconds the kernel may cache attributes
+//! delete_for = "me"           # or "everyone"
+//! move_originals = "delete"   # or "keep"
+//! mkdir_creates = "channel"   # or "group"
+//! part_size = "2000M"
+//! by_id = true                # a by-id directory of links to chat folders, see `chat_names`
+//! media_dirs = true           # photos/, videos/, ... in every chat folder, see `media_views`
+//!
+//! [encryption]
+//! passphrase_file = "~/.config/telegramfs/passphrase"   # or key_file
+//!
+//! [chats]
+//! include = ["Backups", -1001234567890]   # titles, ids or rules; empty means all chats
+//! exclude = ["Noisy group", { type = "user" }, { archived = true }]
+//!
+//! [cache]
+//! dir = "~/.cache/telegramfs"
+//! size = "1G"
+//! memory = "64M"
+//! memory_policy = "lru"       # or "lfu"
+//!
+//! [refresh]
+//! interval = 300              # seconds between reconciliation passes
+//! stats_interval = 60         # seconds between memory cache statistics
+//!
+//! [naming]
+//! unnamed = "msg-{id}"        # media without a name; {id} is the message id
+//! numbered = "{stem} ({n})"   # later files with a taken name; {n} counts from 2
+//! ```
+//!
+//! Sizes are numbers of bytes or strings with a `K`, `M` or `G` suffix, and paths may start
+//! with `~/`. Values are checked when the daemon starts, see `Options` in `main`.
+//!
+//! A rule in `[chats]` is a table that matches the chats meeting all of its keys:
+//! - `id`: the chat id, as Telegram or the Bot API gives it
+//! - `type`: `"user"`, `"bot"`, `"group"` (including supergroups), `"channel"` or `"saved"`
+//!   (Saved Messages)
+//! - `folder`: the title of a Telegram chat folder the chat is in, see the `chat_folders` module
+//! - `name`: a glob the title has to match, `regex`: a regular expression found in the title,
+//!   see the `pattern` module
+//! - `archived`: whether the chat is in the archive
+//!
+//! For example, `include = [{ type = "channel", name = "Storage *" }, { folder = "Backups" }]`
+//! shows the channels whose title starts with `Storage ` and every chat in the folder `Backups`.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use grammers_client::session::{PackedChat, PackedType};
+use grammers_client::types::Chat;
+use serde::Deserialize;
+
+use crate::pattern::{Glob, Regex};
+
+// Name of the configuration file looked for when `--config` is not given
+const CONFIG_FILE: &str = "telegramfs.toml";
+
+// Added to the ids of channels and supergroups, negated, in the ids the Bot API uses
+const CHANNEL_ID_OFFSET: i64 = 1_000_000_000_000;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub account: AccountConfig,
+    pub accounts: BTreeMap<String, AccountConfig>,
+    pub mount: MountConfig,
+    pub encryption: EncryptionConfig,
+    pub chats: ChatFilter,
+    pub cache: CacheConfig,
+    pub refresh: RefreshConfig,
+    pub naming: NamingConfig,
+}
+
+// The Telegram application the daemon signs in with, and where the session is kept.
+// Also the accounts of `[accounts]`, see the `accounts` module.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccountConfig {
+    pub api_id: Option<i32>,
+    pub api_hash: Option<String>,
+    pub credentials: Option<PathBuf>,
+    pub session: Option<PathBuf>,
+    pub password_file: Option<PathBuf>,
+    pub bot_token_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MountConfig {
+    pub mountpoint: Option<PathBuf>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub allow_other: Option<bool>,
+    pub attr_timeout: Option<u64>,
+    pub delete_for: Option<String>,
+    pub move_originals: Option<String>,
+    pub mkdir_creates: Option<String>,
+    pub part_size: Option<Size>,
+    pub by_id: Option<bool>,
+    pub media_dirs: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncryptionConfig {
+    pub passphrase_file: Option<PathBuf>,
+    pub key_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub dir: Option<PathBuf>,
+    pub size: Option<Size>,
+    pub memory: Option<Size>,
+    pub memory_policy: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RefreshConfig {
+    pub interval: Option<u64>,
+    pub stats_interval: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NamingConfig {
+    pub unnamed: Option<String>,
+    pub numbered: Option<String>,
+}
+
+// A size in bytes, given as a number or as a string like `512M`
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Size {
+    Bytes(u64),
+    Text(String),
+}
+
+impl Size {
+    // The number of bytes, or None if the text is not a size
+    pub fn bytes(&self) -> Option<u64> {
+        match self {
+            Self::Bytes(bytes) => Some(*bytes),
+            Self::Text(text) => crate::parse_size(text),
+        }
+    }
+}
+
+/* A chat named in the configuration, by title, by id or by a rule. Ids are the ones Telegram
+uses or the ones of the Bot API, which are negative for groups and start with -100 for channels.*/
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "RawChatRef")]
+pub enum ChatRef {
+    Id(i64),
+    Title(String),
+    Rule(ChatRule),
+}
+
+// A chat as written in the file; rules are parsed on their own so their errors are not lost
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawChatRef {
+    Id(i64),
+    Title(String),
+    Rule(toml::Table),
+}
+
+impl TryFrom<RawChatRef> for ChatRef {
+    type Error = String;
+
+    fn try_from(raw: RawChatRef) -> Result<Self, String> {
+        Ok(match raw {
+            RawChatRef::Id(id) => Self::Id(id),
+            RawChatRef::Title(title) => Self::Title(title),
+            RawChatRef::Rule(table) => Self::Rule(toml::Value::Table(table).try_into().map_err(|e| format!("invalid chat rule: {e}"))?),
+        })
+    }
+}
+
+// The chats meeting every key that is given, see the module documentation
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChatRule {
+    pub id: Option<i64>,
+    #[serde(rename = "type")]
+    pub kind: Option<ChatType>,
+    pub folder: Option<String>,
+    pub name: Option<Glob>,
+    pub regex: Option<Regex>,
+    pub archived: Option<bool>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatType {
+    User,
+    Bot,
+    Group,
+    Channel,
+    Saved,
+}
+
+impl ChatType {
+    fn of(chat: &Chat) -> Self {
+        match chat {
+            Chat::User(user) if user.is_self() => Self::Saved,
+            Chat::User(user) if user.is_bot() => Self::Bot,
+            Chat::User(_) => Self::User,
+            Chat::Group(_) => Self::Group,
+            Chat::Channel(_) => Self::Channel,
+        }
+    }
+}
+
+// Where a chat is in the chat list of the account, which rules on folders and the archive check
+#[derive(Clone, Debug, Default)]
+pub struct Listing {
+    pub archived: bool,
+    // Titles of the chat folders the chat is in
+    pub folders: Vec<String>,
+}
+
+impl ChatRef {
+    fn matches(&self, chat: &Chat, listing: &Listing) -> bool {
+        match self {
+            Self::Id(id) => matches_id(chat, *id),
+            Self::Title(title) => chat.name() == title,
+            Self::Rule(rule) => {
+                rule.id.is_none_or(|id| matches_id(chat, id))
+                    && rule.kind.is_none_or(|kind| ChatType::of(chat) == kind)
+                    && rule.folder.as_ref().is_none_or(|folder| listing.folders.contains(folder))
+                    && rule.name.as_ref().is_none_or(|glob| glob.is_match(chat.name()))
+                    && rule.regex.as_ref().is_none_or(|regex| regex.is_match(chat.name()))
+                    && rule.archived.is_none_or(|archived| listing.archived == archived)
+            }
+        }
+    }
+
+    /* The chat to look up for an id in the form of the Bot API, without its access hash,
+    which only bots may leave out. None for titles and ids that do not say what chat they are.*/
+    pub fn packed(&self) -> Option<PackedChat> {
+        let id = match self {
+            Self::Id(id) => *id,
+            Self::Rule(ChatRule { id: Some(id), .. }) => *id,
+            _ => return None,
+        };
+        let (ty, id, access_hash) = if id < -CHANNEL_ID_OFFSET {
+            (PackedType::Broadcast, -id - CHANNEL_ID_OFFSET, Some(0))
+        } else if id < 0 {
+            (PackedType::Chat, -id, None)
+        } else {
+            return None;
+        };
+        Some(PackedChat { ty, id, access_hash })
+    }
+
+    // Whether matching needs the chat folders of the account
+    fn uses_folders(&self) -> bool {
+        matches!(self, Self::Rule(ChatRule { folder: Some(_), .. }))
+    }
+}
+
+fn matches_id(chat: &Chat, id: i64) -> bool {
+    chat.id() == id || bot_api_id(chat) == id
+}
+
+// The id the Bot API knows `chat` by
+fn bot_api_id(chat: &Chat) -> i64 {
+    match chat.pack().ty {
+        PackedType::User | PackedType::Bot => chat.id(),
+        PackedType::Chat => -chat.id(),
+        PackedType::Megagroup | PackedType::Broadcast | PackedType::Gigagroup => -CHANNEL_ID_OFFSET - chat.id(),
+    }
+}
+
+/* Which chats get a folder:
+- only those matching an entry of `include`, or all of them if it is empty
+- never those matching an entry of `exclude`*/
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChatFilter {
+    pub include: Vec<ChatRef>,
+    pub exclude: Vec<ChatRef>,
+}
+
+impl ChatFilter {
+    // Whether `chat`, listed as `listing`, is shown in the mount
+    pub fn allows(&self, chat: &Chat, listing: &Listing) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|c| c.matches(chat, listing)))
+            && !self.exclude.iter().any(|c| c.matches(chat, listing))
+    }
+
+    // Whether any entry chooses chats by chat folder, which then have to be fetched
+    pub fn uses_folders(&self) -> bool {
+        self.include.iter().chain(&self.exclude).any(ChatRef::uses_folders)
+    }
+}
+
+impl Config {
+    /* Load the configuration from `path`, or from the first default location that has a file.
+    Returns the configuration with the file it came from, None if there was none.
+    A file given explicitly must exist; any file must parse.*/
+    pub fn load(path: Option<&Path>) -> Result<(Self, Option<PathBuf>), String> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match default_paths().into_iter().find(|path| path.is_file()) {
+                Some(path) => path,
+                None => return Ok((Self::default(), None)),
+            },
+        };
+        let contents = fs::read_to_string(&path).map_err(|e: io::Error| format!("failed to read {}: {e}", path.display()))?;
+        let config = toml::from_str(&contents).map_err(|e| format!("invalid configuration in {}: {e}", path.display()))?;
+        Ok((config, Some(path)))
+    }
+}
+
+// Where the configuration file is looked for, in order
+fn default_paths() -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from(CONFIG_FILE)];
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+    paths.extend(base.map(|base| base.join("telegramfs").join(CONFIG_FILE)));
+    paths
+}
+
+// Expand a leading `~/` in `path` to the home directory
+pub fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), std::env::var_os("HOME")) {
+        (Ok(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => path.to_path_buf(),
+    }
+}
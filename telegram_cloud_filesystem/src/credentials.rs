@@ -0,0 +1,123 @@
+//! The Telegram API credentials the daemon signs in with.
+//!
+//! Every client needs an `api_id` and `api_hash`, obtained at
+//! https://core.telegram.org/api/obtaining_api_id#obtaining-api-id. They are read when the
+//! daemon starts, from the first of these that has them:
+//!
+//! 1. the credentials file given with `--credentials <file>`
+//! 2. the `TG_ID` and `TG_HASH` environment variables
+//! 3. `api_id` and `api_hash` in the `[account]` section of the configuration file
+//! 4. the credentials file given with `account.credentials` in the configuration, by default
+//!    `$XDG_CONFIG_HOME/telegramfs/credentials` (`~/.config/telegramfs/credentials`)
+//!
+//! The credentials file holds the same two keys:
+//!
+//! ```toml
+//! api_id = 12345
+//! api_hash = "0123456789abcdef0123456789abcdef"
+//! ```
+//!
+//! As it holds secrets, it is refused if anyone but its owner may read or write it
+//! (`chmod 600` fixes that). Missing or malformed credentials stop the daemon with an error
+//! saying where they were looked for.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+// Name of the credentials file looked for when none is given
+const CREDENTIALS_FILE: &str = "credentials";
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Credentials {
+    pub api_id: i32,
+    pub api_hash: String,
+}
+
+impl Credentials {
+    /* Find the credentials: in the credentials file `given` on the command line, else in the
+    environment, else `api_id` and `api_hash` from the configuration, else in the credentials
+    file at `file` from the configuration (or the default one).
+    Fails with a message for the user if none has them or they are not valid.*/
+    pub fn find(given: Option<&Path>, api_id: Option<i32>, api_hash: Option<&str>, file: Option<&Path>) -> Result<Self, String> {
+        if let Some(path) = given {
+            return Self::load(path).map_err(|e| format!("failed to read credentials from {}: {e}", path.display()));
+        }
+
+        match (env::var("TG_ID"), env::var("TG_HASH")) {
+            (Ok(id), Ok(hash)) => {
+                let api_id = id.trim().parse().map_err(|_| format!("invalid TG_ID: {id:?}, expected a number"))?;
+                return Self::checked(api_id, hash.trim().to_string()).map_err(|e| format!("{e} in TG_ID/TG_HASH"));
+            }
+            (Ok(_), Err(_)) | (Err(_), Ok(_)) => return Err("TG_ID and TG_HASH must be set together".to_string()),
+            _ => {}
+        }
+
+        match (api_id, api_hash) {
+            (Some(api_id), Some(api_hash)) => {
+                return Self::checked(api_id, api_hash.to_string()).map_err(|e| format!("{e} in the [account] section of the configuration"));
+            }
+            (Some(_), None) | (None, Some(_)) => {
+                return Err("api_id and api_hash must be given together in the [account] section of the configuration".to_string());
+            }
+            (None, None) => {}
+        }
+
+        let path = match file {
+            Some(file) => file.to_path_buf(),
+            None => default_path().ok_or(missing(None))?,
+        };
+        match Self::load(&path) {
+            Ok(credentials) => Ok(credentials),
+            Err(e) if e.kind() == io::ErrorKind::NotFound && file.is_none() => Err(missing(Some(&path))),
+            Err(e) => Err(format!("failed to read credentials from {}: {e}", path.display())),
+        }
+    }
+
+    // Read the credentials file at `path`, which only its owner may access
+    fn load(path: &Path) -> io::Result<Self> {
+        let mode = fs::metadata(path)?.permissions().mode();
+        if mode & 0o077 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("it is accessible by others (mode {:o}), restrict it with chmod 600", mode & 0o777),
+            ));
+        }
+        let contents = fs::read_to_string(path)?;
+        let credentials: Self = toml::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        Self::checked(credentials.api_id, credentials.api_hash).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    // The credentials, if they look like ones Telegram hands out
+    fn checked(api_id: i32, api_hash: String) -> Result<Self, String> {
+        if api_id <= 0 {
+            return Err(format!("invalid api_id {api_id}, expected a positive number"));
+        }
+        if api_hash.len() != 32 || !api_hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err("invalid api_hash, expected 32 hexadecimal digits".to_string());
+        }
+        Ok(Self { api_id, api_hash })
+    }
+}
+
+// The error for credentials found nowhere; `path` is the credentials file that was looked for
+fn missing(path: Option<&Path>) -> String {
+    let config = "api_id and api_hash in the [account] section of telegramfs.toml";
+    match path {
+        Some(path) => format!("missing Telegram API credentials: set TG_ID and TG_HASH, give {config}, or put them in {}", path.display()),
+        None => format!("missing Telegram API credentials: set TG_ID and TG_HASH, or give {config}"),
+    }
+}
+
+// The credentials file used when none is given
+fn default_path() -> Option<PathBuf> {
+    let base = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(base.join("telegramfs").join(CREDENTIALS_FILE))
+}
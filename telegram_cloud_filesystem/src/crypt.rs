@@ -0,0 +1,503 @@
+//! Client-side encryption of stored files.
+//!
+//! With `--passphrase-file <file>` or `--key-file <file>`, the content of every file written
+//! through the mount is encrypted before it is uploaded, and the names and directories kept
+//! in captions (see the `meta` module) are encrypted as well. Documents are uploaded under a
+//! neutral name, so the chat only ever sees ciphertext. Files uploaded without encryption
+//! stay readable as they are.
+//!
+//! Encrypted content starts with a header, followed by the plaintext in chunks of 64 KiB,
+//! each stored with an authentication tag, so any range can be read and checked without
+//! downloading the whole file. The last chunk is always shorter than 64 KiB, and empty if the
+//! plaintext fills the chunks before it, so every file ends with a tag marking its end:
+//!
+//! ```text
+//! "TGFSENC1" | salt (16 bytes) | nonce (16 bytes)
+//! chunk 0 ciphertext (64 KiB) | tag (32 bytes)
+//! chunk 1 ciphertext (64 KiB) | tag (32 bytes)
+//! ...
+//! last chunk ciphertext (0 to 64 KiB - 1) | tag (32 bytes)
+//! ```
+//!
+//! The master key is derived from the passphrase with PBKDF2-HMAC-SHA256, or from the key
+//! file with HMAC-SHA256, using the salt. Each file gets its own encryption and MAC keys,
+//! derived from the master key and its random nonce. Chunks are encrypted with AES-256-CTR
+//! and authenticated with HMAC-SHA256 over their index, whether they are the last one, and
+//! their ciphertext (encrypt-then-MAC), so chunks cannot be altered, reordered, swapped
+//! between files or cut off without the read failing with EIO. Reading at the end of a file
+//! checks its last chunk, so not even an empty file can be cut short unnoticed.
+//!
+//! Encrypted names are stored as `~` followed by the unpadded URL-safe base64 of the salt,
+//! the nonce, the ciphertext and the first 16 bytes of its tag.
+//!
+//! Only messages this account sent are taken for encrypted files and names. Deriving the key
+//! of a salt from a passphrase is slow on purpose, so anyone else in a chat could otherwise
+//! hold up the mount by posting items with salts of their own.
+//!
+//! The block caches only ever hold the encrypted content.
+
+use std::cmp::min;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::{fs, io};
+
+use aes::Aes256;
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockEncrypt, KeyInit};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+type Key = [u8; 32];
+
+// Marks the start of encrypted content, and the version of the format
+const MAGIC: &[u8] = b"TGFSENC1";
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 16;
+// Master keys kept for the salts of other mounts; the least recently used one goes first
+const MAX_KEYS: usize = 1024;
+const TAG_LEN: usize = 32;
+// Names are short, so half a tag is plenty for them
+const NAME_TAG_LEN: usize = 16;
+
+// Length of the header in front of encrypted content
+pub const HEADER_LEN: u64 = (MAGIC.len() + SALT_LEN + NONCE_LEN) as u64;
+
+// Plaintext bytes per chunk
+const CHUNK_LEN: u64 = 64 * 1024;
+const SEALED_CHUNK_LEN: u64 = CHUNK_LEN + TAG_LEN as u64;
+
+// Rounds of PBKDF2 for passphrases, to make guessing them expensive
+const PBKDF2_ROUNDS: u32 = 600_000;
+
+// Encrypted names start with this; base64 never does
+const NAME_PREFIX: char = '~';
+
+// Name of the documents carrying encrypted content, the real name is in the caption
+pub const DOCUMENT_NAME: &str = "encrypted.bin";
+
+// What the keys are derived from
+#[derive(Clone)]
+pub enum Secret {
+    Passphrase(String),
+    KeyFile(Vec<u8>),
+}
+
+impl Secret {
+    // The passphrase on the first line of the file at `path`
+    pub fn passphrase_file(path: &Path) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        match contents.lines().next() {
+            Some(passphrase) if !passphrase.is_empty() => Ok(Self::Passphrase(passphrase.to_string())),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "the passphrase is empty")),
+        }
+    }
+
+    // The whole content of the file at `path`, which must hold at least 32 bytes
+    pub fn key_file(path: &Path) -> io::Result<Self> {
+        let key = fs::read(path)?;
+        if key.len() < 32 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "the key file must hold at least 32 bytes"));
+        }
+        Ok(Self::KeyFile(key))
+    }
+}
+
+// Encrypted data that does not authenticate: the key is wrong or the data was changed
+#[derive(Debug)]
+pub struct Error;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "decryption failed: wrong key or corrupted data")
+    }
+}
+
+impl std::error::Error for Error {}
+
+/* Encrypts and decrypts file content and names:
+- what the keys are derived from (`secret`)
+- the salt of everything this mount encrypts (`salt`), chosen at random on startup
+- the master key of that salt (`key`)
+- the master keys derived for the salts of other mounts (`keys`), as deriving a key from a
+  passphrase is slow on purpose*/
+pub struct Crypt {
+    secret: Secret,
+    salt: [u8; SALT_LEN],
+    key: Key,
+    keys: Mutex<Keys>,
+}
+
+// Master keys by salt, with the tick of their last use
+#[derive(Default)]
+struct Keys {
+    by_salt: HashMap<[u8; SALT_LEN], (Key, u64)>,
+    tick: u64,
+}
+
+impl Crypt {
+    pub fn new(secret: Secret) -> io::Result<Self> {
+        let mut salt = [0; SALT_LEN];
+        random(&mut salt)?;
+        let key = derive(&secret, &salt);
+        Ok(Self { secret, salt, key, keys: Mutex::default() })
+    }
+
+    /* Encrypt the `len` bytes `input` holds into the chunked format, written to `output` chunk
+    by chunk, so content of any size is sealed in little memory. Returns the size of the result.*/
+    pub fn seal(&self, mut input: impl Read, len: u64, output: &mut impl Write) -> io::Result<u64> {
+        let mut nonce = [0; NONCE_LEN];
+        random(&mut nonce)?;
+        let (enc_key, mac_key) = self.file_keys(&self.salt, &nonce);
+
+        output.write_all(MAGIC)?;
+        output.write_all(&self.salt)?;
+        output.write_all(&nonce)?;
+        let count = len / CHUNK_LEN + 1;
+        let mut chunk = vec![0; CHUNK_LEN as usize];
+        for i in 0..count {
+            let chunk = &mut chunk[..min(CHUNK_LEN, len - i * CHUNK_LEN) as usize];
+            input.read_exact(chunk)?;
+            apply_keystream(&enc_key, i, chunk);
+            let tag = chunk_mac(&mac_key, i, i + 1 == count, chunk).finalize().into_bytes();
+            output.write_all(chunk)?;
+            output.write_all(&tag)?;
+        }
+        Ok(sealed_size(len))
+    }
+
+    /* Decrypt `sealed`, the stored chunks of a file starting with chunk `first`, at least one.
+    `header` is the start of the stored content and `stored_size` its full size.
+    Fails if the header or any chunk does not authenticate, or the content was cut short.*/
+    pub fn open(&self, header: &[u8], first: u64, sealed: &[u8], stored_size: u64) -> Result<Vec<u8>, Error> {
+        if header.len() < HEADER_LEN as usize || !header.starts_with(MAGIC) {
+            return Err(Error);
+        }
+        let salt = header[MAGIC.len()..][..SALT_LEN].try_into().unwrap();
+        let nonce = &header[MAGIC.len() + SALT_LEN..HEADER_LEN as usize];
+        let (enc_key, mac_key) = self.file_keys(&salt, nonce);
+
+        let count = chunk_count(stored_size).ok_or(Error)?;
+        if sealed.is_empty() {
+            return Err(Error);
+        }
+        let mut plain = Vec::with_capacity(sealed.len());
+        for (i, chunk) in sealed.chunks(SEALED_CHUNK_LEN as usize).enumerate() {
+            let index = first + i as u64;
+            // Only the last chunk is short, and even an empty one has its tag
+            let short = chunk.len() < SEALED_CHUNK_LEN as usize;
+            if chunk.len() < TAG_LEN || index >= count || short != (index + 1 == count) {
+                return Err(Error);
+            }
+            let (ciphertext, tag) = chunk.split_at(chunk.len() - TAG_LEN);
+            chunk_mac(&mac_key, index, index + 1 == count, ciphertext).verify_slice(tag).map_err(|_| Error)?;
+            let start = plain.len();
+            plain.extend_from_slice(ciphertext);
+            apply_keystream(&enc_key, index, &mut plain[start..]);
+        }
+        Ok(plain)
+    }
+
+    // Encrypt a name or path for storing it in a caption
+    pub fn seal_name(&self, name: &str) -> io::Result<String> {
+        let mut nonce = [0; NONCE_LEN];
+        random(&mut nonce)?;
+        let (enc_key, mac_key) = self.file_keys(&self.salt, &nonce);
+
+        let mut blob = [&self.salt[..], &nonce, name.as_bytes()].concat();
+        let ciphertext = &mut blob[SALT_LEN + NONCE_LEN..];
+        apply_keystream(&enc_key, 0, ciphertext);
+        let tag = chunk_mac(&mac_key, 0, true, ciphertext).finalize().into_bytes();
+        blob.extend_from_slice(&tag[..NAME_TAG_LEN]);
+        Ok(format!("{NAME_PREFIX}{}", URL_SAFE_NO_PAD.encode(blob)))
+    }
+
+    /* Decrypt a name stored by `seal_name`.
+    Values that are not encrypted names are returned as they are, and so are the ones
+    that do not authenticate, e.g. because they were encrypted with another key.*/
+    pub fn open_name(&self, value: &str) -> String {
+        let blob = value.strip_prefix(NAME_PREFIX).and_then(|encoded| URL_SAFE_NO_PAD.decode(encoded).ok());
+        let Some(blob) = blob.filter(|blob| blob.len() >= SALT_LEN + NONCE_LEN + NAME_TAG_LEN) else {
+            return value.to_string();
+        };
+        let (salt, rest) = blob.split_at(SALT_LEN);
+        let (nonce, rest) = rest.split_at(NONCE_LEN);
+        let (ciphertext, tag) = rest.split_at(rest.len() - NAME_TAG_LEN);
+        let (enc_key, mac_key) = self.file_keys(salt.try_into().unwrap(), nonce);
+        if chunk_mac(&mac_key, 0, true, ciphertext).verify_truncated_left(tag).is_err() {
+            return value.to_string();
+        }
+        let mut name = ciphertext.to_vec();
+        apply_keystream(&enc_key, 0, &mut name);
+        String::from_utf8(name).unwrap_or_else(|_| value.to_string())
+    }
+
+    // The keys for encrypting and authenticating the file or name with `nonce`
+    fn file_keys(&self, salt: &[u8; SALT_LEN], nonce: &[u8]) -> (Key, Key) {
+        let master = self.master_key(salt);
+        (hmac(&master, &[b"enc", nonce]), hmac(&master, &[b"mac", nonce]))
+    }
+
+    fn master_key(&self, salt: &[u8; SALT_LEN]) -> Key {
+        if *salt == self.salt {
+            return self.key;
+        }
+        {
+            let keys = &mut *self.keys.lock().unwrap();
+            keys.tick += 1;
+            if let Some((key, used)) = keys.by_salt.get_mut(salt) {
+                *used = keys.tick;
+                return *key;
+            }
+        }
+
+        let key = derive(&self.secret, salt);
+        let keys = &mut *self.keys.lock().unwrap();
+        if keys.by_salt.len() >= MAX_KEYS
+            && let Some(oldest) = keys.by_salt.iter().min_by_key(|(_, (_, used))| *used).map(|(salt, _)| *salt)
+        {
+            keys.by_salt.remove(&oldest);
+        }
+        keys.tick += 1;
+        keys.by_salt.insert(*salt, (key, keys.tick));
+        key
+    }
+}
+
+// The master key of `salt`
+fn derive(secret: &Secret, salt: &[u8; SALT_LEN]) -> Key {
+    match secret {
+        Secret::Passphrase(passphrase) => pbkdf2::pbkdf2_hmac_array::<Sha256, 32>(passphrase.as_bytes(), salt, PBKDF2_ROUNDS),
+        Secret::KeyFile(key) => hmac(key, &[salt]),
+    }
+}
+
+// Size of the encrypted content of `plain_size` bytes
+pub fn sealed_size(plain_size: u64) -> u64 {
+    HEADER_LEN + plain_size + (plain_size / CHUNK_LEN + 1) * TAG_LEN as u64
+}
+
+// Size of the plaintext in encrypted content of `stored_size` bytes, None if no content has that size
+pub fn plain_size(stored_size: u64) -> Option<u64> {
+    let count = chunk_count(stored_size)?;
+    Some(stored_size - HEADER_LEN - count * TAG_LEN as u64)
+}
+
+// Number of chunks in encrypted content of `stored_size` bytes, None if it cannot end in a last chunk
+fn chunk_count(stored_size: u64) -> Option<u64> {
+    let body = stored_size.checked_sub(HEADER_LEN)?;
+    (body % SEALED_CHUNK_LEN >= TAG_LEN as u64).then_some(body / SEALED_CHUNK_LEN + 1)
+}
+
+/* The stored chunks holding plaintext bytes `offset..offset + size` of encrypted content of
+`stored_size` bytes: the index of the first chunk, where the chunks start, and how many bytes
+they take. An empty range takes the chunk at `offset`, so reading at the end of the file checks
+the last chunk. Decrypting them yields the plaintext from `first * 64 KiB` on.*/
+pub fn sealed_range(offset: u64, size: u64, stored_size: u64) -> (u64, u64, u64) {
+    let first = offset / CHUNK_LEN;
+    let last = ((offset + size).saturating_sub(1) / CHUNK_LEN).max(first);
+    let start = HEADER_LEN + first * SEALED_CHUNK_LEN;
+    let end = min(HEADER_LEN + (last + 1) * SEALED_CHUNK_LEN, stored_size);
+    (first, start, end.saturating_sub(start))
+}
+
+// Where plaintext decrypted from chunk `first` on starts in the file
+pub fn chunk_offset(first: u64) -> u64 {
+    first * CHUNK_LEN
+}
+
+// XOR `data` with the AES-256-CTR keystream of chunk `chunk`
+fn apply_keystream(key: &Key, chunk: u64, data: &mut [u8]) {
+    let cipher = Aes256::new(GenericArray::from_slice(key));
+    let first_block = chunk as u128 * (CHUNK_LEN / 16) as u128;
+    for (i, piece) in data.chunks_mut(16).enumerate() {
+        let mut block = GenericArray::clone_from_slice(&(first_block + i as u128).to_be_bytes());
+        cipher.encrypt_block(&mut block);
+        for (byte, key) in piece.iter_mut().zip(block.iter()) {
+            *byte ^= key;
+        }
+    }
+}
+
+// The MAC of a chunk, covering its position so chunks cannot be moved around
+fn chunk_mac(key: &Key, index: u64, last: bool, ciphertext: &[u8]) -> HmacSha256 {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(&index.to_be_bytes());
+    mac.update(&[last as u8]);
+    mac.update(ciphertext);
+    mac
+}
+
+fn hmac(key: &[u8], parts: &[&[u8]]) -> Key {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC takes keys of any length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+fn random(buf: &mut [u8]) -> io::Result<()> {
+    getrandom::getrandom(buf).map_err(io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crypt() -> Crypt {
+        Crypt::new(Secret::KeyFile(vec![7; 32])).unwrap()
+    }
+
+    fn plaintext(len: u64) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    fn seal(crypt: &Crypt, plain: &[u8]) -> Vec<u8> {
+        let mut sealed = Vec::new();
+        let size = crypt.seal(plain, plain.len() as u64, &mut sealed).unwrap();
+        assert_eq!(size, sealed.len() as u64);
+        sealed
+    }
+
+    // Read `size` bytes at `offset` of `sealed` the way the mount does
+    fn read(crypt: &Crypt, sealed: &[u8], offset: u64, size: u64) -> Result<Vec<u8>, Error> {
+        let stored_size = sealed.len() as u64;
+        plain_size(stored_size).ok_or(Error)?;
+        let (first, start, len) = sealed_range(offset, size, stored_size);
+        let chunks = &sealed[start as usize..(start + len) as usize];
+        let plain = crypt.open(&sealed[..HEADER_LEN as usize], first, chunks, stored_size)?;
+        let skip = min((offset - chunk_offset(first)) as usize, plain.len());
+        Ok(plain[skip..min(skip + size as usize, plain.len())].to_vec())
+    }
+
+    #[test]
+    fn round_trip() {
+        let crypt = crypt();
+        for len in [0, 1, CHUNK_LEN - 1, CHUNK_LEN, CHUNK_LEN + 1, 3 * CHUNK_LEN] {
+            let plain = plaintext(len);
+            let sealed = seal(&crypt, &plain);
+            assert_eq!(plain_size(sealed.len() as u64), Some(len));
+            assert_eq!(read(&crypt, &sealed, 0, len).unwrap(), plain, "{len} bytes");
+            // The empty read at the end checks the last chunk
+            assert_eq!(read(&crypt, &sealed, len, 0).unwrap(), Vec::<u8>::new());
+            if len > 2 {
+                let (offset, size) = (len / 2, len / 3);
+                let range = offset as usize..(offset + size) as usize;
+                assert_eq!(read(&crypt, &sealed, offset, size).unwrap(), plain[range]);
+            }
+        }
+    }
+
+    #[test]
+    fn other_key_fails() {
+        let sealed = seal(&crypt(), &plaintext(100));
+        let other = Crypt::new(Secret::KeyFile(vec![8; 32])).unwrap();
+        assert!(read(&other, &sealed, 0, 100).is_err());
+    }
+
+    #[test]
+    fn tampering_fails() {
+        let crypt = crypt();
+        let len = 2 * CHUNK_LEN + 10;
+        let sealed = seal(&crypt, &plaintext(len));
+        for at in [0, MAGIC.len(), HEADER_LEN as usize, HEADER_LEN as usize + CHUNK_LEN as usize, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[at] ^= 1;
+            assert!(read(&crypt, &tampered, 0, len).is_err(), "byte {at} changed");
+        }
+    }
+
+    #[test]
+    fn reordering_fails() {
+        let crypt = crypt();
+        let len = 2 * CHUNK_LEN + 10;
+        let mut sealed = seal(&crypt, &plaintext(len));
+        let chunk = SEALED_CHUNK_LEN as usize;
+        let (first, rest) = sealed[HEADER_LEN as usize..].split_at_mut(chunk);
+        first.swap_with_slice(&mut rest[..chunk]);
+        assert!(read(&crypt, &sealed, 0, len).is_err());
+        assert!(read(&crypt, &sealed, 0, 1).is_err());
+    }
+
+    #[test]
+    fn truncation_fails() {
+        let crypt = crypt();
+        let header = HEADER_LEN as usize;
+        let chunk = SEALED_CHUNK_LEN as usize;
+
+        // Only the header left of an empty file
+        let sealed = seal(&crypt, &[]);
+        assert!(read(&crypt, &sealed[..header], 0, 0).is_err());
+
+        // Cut at a chunk boundary: the last full chunk is not marked as the last one
+        let sealed = seal(&crypt, &plaintext(2 * CHUNK_LEN));
+        assert!(read(&crypt, &sealed[..header + chunk], 0, CHUNK_LEN).is_err());
+        assert!(read(&crypt, &sealed[..header + chunk], CHUNK_LEN, 0).is_err());
+        // The empty last chunk dropped
+        assert!(read(&crypt, &sealed[..header + 2 * chunk], 0, 2 * CHUNK_LEN).is_err());
+        assert!(read(&crypt, &sealed[..header], 0, 0).is_err());
+
+        // Cut within the last chunk
+        let sealed = seal(&crypt, &plaintext(CHUNK_LEN + 100));
+        let cut = &sealed[..sealed.len() - 50];
+        assert!(read(&crypt, cut, 0, plain_size(cut.len() as u64).unwrap()).is_err());
+        // Cut within a chunk before the last one
+        let cut = &sealed[..header + chunk - 1];
+        assert!(read(&crypt, cut, 0, plain_size(cut.len() as u64).unwrap()).is_err());
+    }
+
+    #[test]
+    fn sizes() {
+        let tag = TAG_LEN as u64;
+        assert_eq!(sealed_size(0), HEADER_LEN + tag);
+        assert_eq!(sealed_size(1), HEADER_LEN + 1 + tag);
+        assert_eq!(sealed_size(CHUNK_LEN - 1), HEADER_LEN + CHUNK_LEN - 1 + tag);
+        assert_eq!(sealed_size(CHUNK_LEN), HEADER_LEN + CHUNK_LEN + 2 * tag);
+        assert_eq!(sealed_size(CHUNK_LEN + 1), HEADER_LEN + CHUNK_LEN + 1 + 2 * tag);
+        for len in [0, 1, CHUNK_LEN - 1, CHUNK_LEN, CHUNK_LEN + 1, 5 * CHUNK_LEN, 5 * CHUNK_LEN + 7, 1 << 40] {
+            assert_eq!(plain_size(sealed_size(len)), Some(len));
+        }
+        // Sizes that cannot end in a tagged last chunk
+        assert_eq!(plain_size(0), None);
+        assert_eq!(plain_size(HEADER_LEN), None);
+        assert_eq!(plain_size(HEADER_LEN + tag - 1), None);
+        assert_eq!(plain_size(HEADER_LEN + SEALED_CHUNK_LEN), None);
+        assert_eq!(plain_size(HEADER_LEN + SEALED_CHUNK_LEN + tag - 1), None);
+    }
+
+    #[test]
+    fn names() {
+        let crypt = crypt();
+        let sealed = crypt.seal_name("notes/2024.txt").unwrap();
+        assert!(sealed.starts_with(NAME_PREFIX));
+        assert_eq!(crypt.open_name(&sealed), "notes/2024.txt");
+        assert_eq!(crypt.open_name("plain.txt"), "plain.txt");
+        let other = Crypt::new(Secret::KeyFile(vec![8; 32])).unwrap();
+        assert_eq!(other.open_name(&sealed), sealed);
+    }
+
+    #[test]
+    fn keys_of_other_mounts() {
+        let (crypt, earlier) = (crypt(), crypt());
+        let sealed = seal(&earlier, &plaintext(100));
+        assert_eq!(read(&crypt, &sealed, 0, 100).unwrap(), plaintext(100));
+        assert_eq!(crypt.open_name(&earlier.seal_name("a.txt").unwrap()), "a.txt");
+
+        // Only so many are kept, the most recently used ones
+        for i in 0..MAX_KEYS as u64 + 10 {
+            let salt: [u8; SALT_LEN] = (i as u128).to_be_bytes();
+            crypt.master_key(&salt);
+            crypt.master_key(&earlier.salt);
+        }
+        let keys = &crypt.keys.lock().unwrap().by_salt;
+        assert_eq!(keys.len(), MAX_KEYS);
+        assert!(keys.contains_key(&earlier.salt));
+        assert!(!keys.contains_key(&crypt.salt));
+    }
+}
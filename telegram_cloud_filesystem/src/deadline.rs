@@ -0,0 +1,78 @@
+//! Bounds on how long a filesystem operation waits for Telegram.
+//!
+//! The kernel's requests are handled one at a time, for every account of the mount, and each
+//! waits for the calls to Telegram it makes. A connection that stalls would hold up the whole
+//! mount, so a call that gets no answer within a minute is given up and the operation fails
+//! with EIO. Uploads take as long as the file needs, and are only given up once they stop
+//! making progress for that long.
+//!
+//! This bounds the wait, it does not remove it: until the call is given up, every other
+//! operation on the mount waits for it, on whichever account it is.
+
+use std::cell::Cell;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::{pin, Pin};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::time::{self, Instant};
+
+// How long a call may go without an answer, or an upload without progress
+pub const TIMEOUT: Duration = Duration::from_secs(60);
+
+// A call to Telegram that was given up
+#[derive(Debug)]
+pub struct TimedOut;
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Telegram did not answer within {} seconds", TIMEOUT.as_secs())
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+// The result of `future`, or TimedOut if it does not finish within `TIMEOUT`
+pub async fn within<T, E: From<TimedOut>>(future: impl Future<Output = Result<T, E>>) -> Result<T, E> {
+    time::timeout(TIMEOUT, future).await.map_err(|_| TimedOut)?
+}
+
+/* The result of `future`, which uploads from a stream wrapped in `Watched` with `last_read`,
+or TimedOut once the stream has not been read from for `TIMEOUT`.*/
+pub async fn while_reading<T, E: From<TimedOut>>(last_read: &Cell<Instant>, future: impl Future<Output = Result<T, E>>) -> Result<T, E> {
+    let mut future = pin!(future);
+    loop {
+        match time::timeout_at(last_read.get() + TIMEOUT, &mut future).await {
+            Ok(result) => return result,
+            Err(_) if last_read.get() + TIMEOUT <= Instant::now() => return Err(TimedOut.into()),
+            // Read from in the meantime, so it still makes progress
+            Err(_) => {}
+        }
+    }
+}
+
+// A stream that notes in `last_read` when it was last read from
+pub struct Watched<'a, R> {
+    inner: R,
+    last_read: &'a Cell<Instant>,
+}
+
+impl<'a, R> Watched<'a, R> {
+    pub fn new(inner: R, last_read: &'a Cell<Instant>) -> Self {
+        last_read.set(Instant::now());
+        Self { inner, last_read }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Watched<'_, R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if result.is_ready() {
+            self.last_read.set(Instant::now());
+        }
+        result
+    }
+}
@@ -0,0 +1,127 @@
+//! Files with the same content, stored once.
+//!
+//! Every file written through the mount is hashed, and the SHA-256 digest of its content
+//! (before encryption, see the `crypt` module) goes into its caption:
+//!
+//! ```text
+//! tgfs: sha256=d7a8fbb307d7809469ca9abcb0082e4f8d5651e46d3cdb762d02d0bf37c9e592
+//! ```
+//!
+//! Before uploading, the digest is looked up among the files of all chat folders. If a
+//! file with the same content exists already, no second copy is uploaded: the existing
+//! message is forwarded into the target chat instead, and a split file in the same chat
+//! gets a second manifest pointing at the same parts. Parts are only deleted with the last
+//! manifest listing them.
+//!
+//! Files with the same digest share one inode, keyed by the digest instead of their
+//! message, so they show up as hard links of each other with a link count to match.
+//! `ln` makes such a link by forwarding the file.
+//!
+//! Anyone in a chat can write a caption, so only digests in messages this account sent are
+//! trusted: the files of others never stand in for new content and never share an inode.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read};
+use std::sync::Mutex;
+
+use fuser::FileAttr;
+use sha2::{Digest, Sha256};
+
+use crate::{CachedFile, CachedFolder, FileContent};
+
+// The digest stored for the content `data` reads, as lowercase hex
+pub fn digest(mut data: impl Read) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut data, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// Whether `value` looks like a digest from `digest`
+pub fn is_digest(value: &str) -> bool {
+    value.len() == 64 && value.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/* A stored file with content `digest` of `size` bytes that a new file can refer to, with the
+name of its folder. Files in the folder `prefer` come first, as their parts can be shared.
+Only files this account sent and encrypted as the new one would be (`sealed`) qualify, and
+never the message `except` of the folder `prefer`, which the new file replaces.*/
+pub fn find_original<'a>(cache: &'a HashMap<String, CachedFolder>, digest: &str, size: u64, sealed: bool, prefer: &str, except: Option<i32>) -> Option<(&'a str, &'a CachedFile)> {
+    let mut found = None;
+    for (name, folder) in cache {
+        for file in &folder.files {
+            let stored = matches!(file.content, FileContent::Remote(_) | FileContent::Parts(_));
+            if !stored
+                || !file.outgoing
+                || file.digest.as_deref() != Some(digest)
+                || file.attr.size != size
+                || file.sealed_size.is_some() != sealed
+                || (name == prefer && except == Some(file.message_id))
+            {
+                continue;
+            }
+            if name == prefer {
+                return Some((name, file));
+            }
+            found.get_or_insert((name.as_str(), file));
+        }
+    }
+    found
+}
+
+// The parts of split files in `folder` that a manifest other than the message `message_id` lists
+pub fn shared_parts(folder: &CachedFolder, message_id: i32) -> HashSet<i32> {
+    let mut shared = HashSet::new();
+    for file in &folder.files {
+        if let FileContent::Parts(manifest) = &file.content
+            && file.message_id != message_id
+        {
+            shared.extend(&manifest.parts);
+        }
+    }
+    shared
+}
+
+/* The files of all chat folders by inode, so the link count of a file is known without going
+through every folder. Whatever adds files to the cache or drops them from it tells the index;
+files are recorded by their message, so telling it twice changes nothing.*/
+#[derive(Default)]
+pub struct Links(Mutex<HashMap<u64, HashSet<(i64, i32)>>>);
+
+impl Links {
+    pub fn add(&self, file: &CachedFile) {
+        self.0.lock().unwrap().entry(file.ino).or_default().insert((file.chat_id, file.message_id));
+    }
+
+    pub fn remove(&self, file: &CachedFile) {
+        let mut links = self.0.lock().unwrap();
+        if let Some(messages) = links.get_mut(&file.ino) {
+            messages.remove(&(file.chat_id, file.message_id));
+            if messages.is_empty() {
+                links.remove(&file.ino);
+            }
+        }
+    }
+
+    // Keep the files of `files` that `keep` holds for, dropping the others from the index
+    pub fn retain(&self, files: &mut Vec<CachedFile>, mut keep: impl FnMut(&CachedFile) -> bool) {
+        files.retain(|file| {
+            let kept = keep(file);
+            if !kept {
+                self.remove(file);
+            }
+            kept
+        });
+    }
+
+    // Record that the files `old` of a folder were replaced by `new`
+    pub fn replace(&self, old: &[CachedFile], new: &[CachedFile]) {
+        old.iter().for_each(|file| self.remove(file));
+        new.iter().for_each(|file| self.add(file));
+    }
+
+    // The attributes of `file`, counting every file sharing its inode as a link
+    pub fn attr(&self, file: &CachedFile) -> FileAttr {
+        let links = self.0.lock().unwrap().get(&file.ino).map_or(0, HashSet::len);
+        FileAttr { nlink: links.max(1) as u32, ..file.attr }
+    }
+}
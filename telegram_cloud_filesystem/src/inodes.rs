@@ -0,0 +1,252 @@
+//! Inode numbers for chats and files, stable across refreshes and remounts.
+//!
+//! Every object gets its inode from the Telegram ids identifying it, not from its
+//! name or position in a listing, so renaming a chat or receiving new messages does
+//! not change the inode of anything else. The table is saved to disk and loaded on
+//! the next mount. Numbers are handed out in increasing order and never reused, even
+//! after the object they belonged to is gone, so a stale inode held by the kernel or
+//! a program can never point at a different file.
+//!
+//! The table is stored as plain text, one entry per line:
+//!
+//! ```text
+//! next 42
+//! chat <chat id> <inode>
+//! msg <chat id> <message id> <inode>
+//! dir <chat id> <percent-encoded path> <inode>
+//! sha256 <digest> <inode>
+//! byid <inode>
+//! link <chat id> <inode>
+//! view <chat id> <media type> <inode>
+//! ```
+//!
+//! Files whose content digest is known share the inode of that digest, see the `dedup` module.
+//!
+//! When several accounts are mounted, each has a table of its own and a range of numbers
+//! starting at the inode of its directory, see the `accounts` module. The file stores numbers
+//! relative to that, so it reads the same as the one of a single account. Numbers that do not
+//! fit into the range of an account are malformed, and once a table has handed out all of
+//! its range, objects without an inode yet fail to show up rather than take the numbers of the
+//! next account.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::accounts::ACCOUNT_SHIFT;
+use crate::media_views::MediaKind;
+use crate::meta::{decode, encode};
+use crate::persist;
+
+// Inode of the mount root, which is fixed by FUSE
+pub const ROOT_INO: u64 = 1;
+
+// How many numbers a table has, from the one below the inode of its directory on
+const RANGE_LEN: u64 = 1 << ACCOUNT_SHIFT;
+
+// The Telegram object an inode stands for
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub enum InodeKey {
+    // A chat folder, by chat id
+    Chat(i64),
+    // A file, by chat id and message id
+    Message(i64, i32),
+    // A directory inside a chat folder, by chat id and path
+    Dir(i64, String),
+    // Every file with the same content, by the digest of the content
+    Content(String),
+    // The directory of links to chat folders by id, see the `chat_names` module
+    ById,
+    // The link to a chat folder in that directory, by chat id
+    ChatLink(i64),
+    // The directory of a chat folder listing its files of one media type, see the `media_views` module
+    MediaView(i64, MediaKind),
+}
+
+pub struct InodeTable {
+    path: PathBuf,
+    // Inode of the directory the table's objects live in; the file stores numbers relative to it
+    root: u64,
+    inodes: HashMap<InodeKey, u64>,
+    // Next number to hand out; everything below it has been used before
+    next: u64,
+    dirty: bool,
+}
+
+impl InodeTable {
+    /* Load the table saved at `path` for the objects below the directory with inode `root`,
+    or start an empty one if there is no file yet.
+    Malformed lines are skipped with a warning rather than failing the mount.*/
+    pub fn load(path: &Path, root: u64) -> io::Result<Self> {
+        let base = root - ROOT_INO;
+        let mut table = Self {
+            path: path.to_path_buf(),
+            root,
+            inodes: HashMap::new(),
+            next: root + 1,
+            dirty: false,
+        };
+
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(table),
+            Err(e) => return Err(e),
+        };
+
+        for line in contents.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let parsed = match fields.as_slice() {
+                ["next", next] => {
+                    match next.parse::<u64>().ok().filter(|&next| next <= RANGE_LEN).and_then(|next| base.checked_add(next)) {
+                        Some(next) => table.next = table.next.max(next),
+                        None => log::warn!("skipping malformed line in {}: {line:?}", path.display()),
+                    }
+                    continue;
+                }
+                ["chat", chat, ino] => chat.parse().ok().map(InodeKey::Chat).zip(ino.parse().ok()),
+                ["msg", chat, msg, ino] => chat
+                    .parse()
+                    .ok()
+                    .zip(msg.parse().ok())
+                    .map(|(chat, msg)| InodeKey::Message(chat, msg))
+                    .zip(ino.parse().ok()),
+                ["dir", chat, path, ino] => chat
+                    .parse()
+                    .ok()
+                    .map(|chat| InodeKey::Dir(chat, decode(path)))
+                    .zip(ino.parse().ok()),
+                ["sha256", digest, ino] => Some(InodeKey::Content(digest.to_string())).zip(ino.parse().ok()),
+                ["byid", ino] => Some(InodeKey::ById).zip(ino.parse().ok()),
+                ["link", chat, ino] => chat.parse().ok().map(InodeKey::ChatLink).zip(ino.parse().ok()),
+                ["view", chat, kind, ino] => chat
+                    .parse()
+                    .ok()
+                    .zip(MediaKind::from_dir_name(kind))
+                    .map(|(chat, kind)| InodeKey::MediaView(chat, kind))
+                    .zip(ino.parse().ok()),
+                _ => None,
+            };
+            let parsed = parsed.and_then(|(key, ino): (InodeKey, u64)| Some(key).zip(base.checked_add(ino).filter(|_| ino < RANGE_LEN)));
+            match parsed {
+                Some((key, ino)) if ino > root => {
+                    table.inodes.insert(key, ino);
+                    // Never hand out a number that is already taken, even if `next` was lost
+                    table.next = table.next.max(ino + 1);
+                }
+                _ => log::warn!("skipping malformed line in {}: {line:?}", path.display()),
+            }
+        }
+        Ok(table)
+    }
+
+    /* Inode of the object behind `key`, assigning a new one the first time it is seen.
+    None if it has none yet and the numbers are used up, see `allocate`.*/
+    pub fn get_or_assign(&mut self, key: InodeKey) -> Option<u64> {
+        if let Some(&ino) = self.inodes.get(&key) {
+            return Some(ino);
+        }
+        let ino = self.allocate()?;
+        self.inodes.insert(key, ino);
+        self.dirty = true;
+        Some(ino)
+    }
+
+    /* A fresh inode that is not tied to any object yet (e.g. for a file still being written),
+    or None once the table has handed out all numbers of its range.*/
+    pub fn allocate(&mut self) -> Option<u64> {
+        if self.next - (self.root - ROOT_INO) >= RANGE_LEN {
+            log::error!("no inode numbers left for {}", self.path.display());
+            return None;
+        }
+        let ino = self.next;
+        self.next += 1;
+        self.dirty = true;
+        Some(ino)
+    }
+
+    // Tie an inode from `allocate` to the object it became, such as an uploaded message
+    pub fn bind(&mut self, key: InodeKey, ino: u64) {
+        self.inodes.insert(key, ino);
+        self.dirty = true;
+    }
+
+    // Inode of the object behind `key`, if it has one
+    pub fn get(&self, key: &InodeKey) -> Option<u64> {
+        self.inodes.get(key).copied()
+    }
+
+    // Whether an inode was ever assigned to the object behind `key`
+    pub fn contains(&self, key: &InodeKey) -> bool {
+        self.inodes.contains_key(key)
+    }
+
+    /* Forget the inode of the object behind `key`; it gets a new one if it is seen again.
+    The number itself is not handed out again.*/
+    pub fn forget(&mut self, key: &InodeKey) {
+        if self.inodes.remove(key).is_some() {
+            self.dirty = true;
+        }
+    }
+
+    // Write the table to disk if anything changed since it was last saved, see the `persist` module
+    pub fn save(&mut self) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let base = self.root - ROOT_INO;
+        let mut contents = format!("next {}\n", self.next - base);
+        let mut entries: Vec<_> = self.inodes.iter().map(|(key, ino)| (key, ino - base)).collect();
+        entries.sort_by_key(|(_, ino)| *ino);
+        for (key, ino) in entries {
+            match key {
+                InodeKey::Chat(chat) => contents.push_str(&format!("chat {chat} {ino}\n")),
+                InodeKey::Message(chat, msg) => contents.push_str(&format!("msg {chat} {msg} {ino}\n")),
+                InodeKey::Dir(chat, path) => contents.push_str(&format!("dir {chat} {} {ino}\n", encode(path))),
+                InodeKey::Content(digest) => contents.push_str(&format!("sha256 {digest} {ino}\n")),
+                InodeKey::ById => contents.push_str(&format!("byid {ino}\n")),
+                InodeKey::ChatLink(chat) => contents.push_str(&format!("link {chat} {ino}\n")),
+                InodeKey::MediaView(chat, kind) => contents.push_str(&format!("view {chat} {} {ino}\n", kind.dir_name())),
+            }
+        }
+
+        persist::write(&self.path, contents)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The table of the account in the range after the first one
+    const ROOT: u64 = RANGE_LEN + ROOT_INO;
+
+    fn load(contents: &str) -> InodeTable {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("telegramfs.work.inodes");
+        fs::write(&path, contents).unwrap();
+        InodeTable::load(&path, ROOT).unwrap()
+    }
+
+    #[test]
+    fn numbers_outside_the_range_are_malformed() {
+        let huge = u64::MAX;
+        let mut table = load(&format!("next {huge}\nchat 1 {RANGE_LEN}\nchat 2 {huge}\nchat 3 5\n"));
+        assert_eq!(table.get(&InodeKey::Chat(1)), None);
+        assert_eq!(table.get(&InodeKey::Chat(2)), None);
+        assert_eq!(table.get(&InodeKey::Chat(3)), Some(RANGE_LEN + 5));
+        assert_eq!(table.allocate(), Some(RANGE_LEN + 6));
+    }
+
+    #[test]
+    fn allocation_stops_at_the_end_of_the_range() {
+        let mut table = load(&format!("next {}\n", RANGE_LEN - 1));
+        assert_eq!(table.get_or_assign(InodeKey::Chat(1)), Some(2 * RANGE_LEN - 1));
+        assert_eq!(table.get_or_assign(InodeKey::Chat(2)), None);
+        assert_eq!(table.allocate(), None);
+        assert_eq!(table.get_or_assign(InodeKey::Chat(1)), Some(2 * RANGE_LEN - 1));
+    }
+}
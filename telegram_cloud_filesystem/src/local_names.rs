@@ -0,0 +1,124 @@
+//! File paths set by `mv` that could not be stored in Telegram.
+//!
+//! Renaming or moving a file within its chat normally edits the caption of its message
+//! (see the `meta` module), but only messages this account sent can be edited. Other files
+//! are renamed locally: their new path below the chat folder is kept in this table, saved
+//! to disk and loaded on the next mount.
+//!
+//! The table is stored as plain text, one entry per line, with percent-encoded paths:
+//!
+//! ```text
+//! <chat id> <message id> <path>
+//! ```
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::meta::{decode, encode};
+use crate::persist;
+
+pub struct LocalNames {
+    path: PathBuf,
+    names: HashMap<(i64, i32), String>,
+}
+
+impl LocalNames {
+    /* Load the table saved at `path`, or start an empty one if there is no file yet.
+    Malformed lines are skipped with a warning rather than failing the mount.*/
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut table = Self {
+            path: path.to_path_buf(),
+            names: HashMap::new(),
+        };
+
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(table),
+            Err(e) => return Err(e),
+        };
+
+        for line in contents.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let parsed = match fields.as_slice() {
+                [chat, msg, _] => chat.parse().ok().zip(msg.parse().ok()),
+                _ => None,
+            };
+            match parsed {
+                Some(key) => {
+                    table.names.insert(key, decode(fields[2]));
+                }
+                None => log::warn!("skipping malformed line in {}: {line:?}", path.display()),
+            }
+        }
+        Ok(table)
+    }
+
+    // Path given to the file of message `message_id` in chat `chat_id`, if it was renamed locally
+    pub fn get(&self, chat_id: i64, message_id: i32) -> Option<&str> {
+        self.names.get(&(chat_id, message_id)).map(String::as_str)
+    }
+
+    // Remember `path` for the file of a message, or forget its local path if `path` is None
+    pub fn set(&mut self, chat_id: i64, message_id: i32, path: Option<&str>) {
+        match path {
+            Some(path) => self.names.insert((chat_id, message_id), path.to_string()),
+            None => self.names.remove(&(chat_id, message_id)),
+        };
+    }
+
+    // Write the table to disk, see the `persist` module
+    pub fn save(&self) -> io::Result<()> {
+        let mut entries: Vec<_> = self.names.iter().collect();
+        entries.sort();
+        let mut contents = String::new();
+        for ((chat, msg), name) in entries {
+            contents.push_str(&format!("{chat} {msg} {}\n", encode(name)));
+        }
+
+        persist::write(&self.path, contents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::inodes::{InodeKey, InodeTable, ROOT_INO};
+
+    // The names and the inodes of an account are saved next to each other, from different threads
+    #[test]
+    fn saves_next_to_the_inode_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let names_path = dir.path().join("telegramfs.work.names");
+        let inodes_path = dir.path().join("telegramfs.work.inodes");
+
+        let mut names = LocalNames::load(&names_path).unwrap();
+        names.set(-100, 7, Some("docs/report.pdf"));
+        let mut inodes = InodeTable::load(&inodes_path, ROOT_INO).unwrap();
+        let ino = inodes.get_or_assign(InodeKey::Message(-100, 7)).unwrap();
+
+        thread::scope(|s| {
+            s.spawn(|| (0..200).for_each(|_| names.save().unwrap()));
+            s.spawn(|| {
+                for i in 0..200 {
+                    inodes.get_or_assign(InodeKey::Chat(i)).unwrap();
+                    inodes.save().unwrap();
+                }
+            });
+        });
+
+        let names = LocalNames::load(&names_path).unwrap();
+        assert_eq!(names.get(-100, 7), Some("docs/report.pdf"));
+        let inodes = InodeTable::load(&inodes_path, ROOT_INO).unwrap();
+        assert_eq!(inodes.get(&InodeKey::Message(-100, 7)), Some(ino));
+        assert!(inodes.contains(&InodeKey::Chat(199)));
+
+        // Nothing but the two tables is left behind
+        let mut files: Vec<_> = fs::read_dir(dir.path()).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        files.sort();
+        assert_eq!(files, ["telegramfs.work.inodes", "telegramfs.work.names"]);
+    }
+}
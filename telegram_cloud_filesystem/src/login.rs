@@ -0,0 +1,311 @@
+//! Signing in to Telegram, once, before the filesystem is mounted.
+//!
+//! The daemon never reads from stdin, so it can run under systemd: it needs a session file
+//! that is signed in already and stops with an error otherwise. The `login` subcommand
+//! creates that session file:
+//!
+//! ```text
+//! telegram_cloud_filesystem login [--config <file>] [--credentials <file>]
+//!     [--session <file> | --account <name>] [--qr | --phone <number>] [--password-file <file>]
+//! ```
+//!
+//! With `--account <name>` it signs in the account of that name from the `[accounts]` section
+//! of the configuration, to its session file, see the `accounts` module.
+//!
+//! By default it asks for the phone number (unless `--phone` or `TG_PHONE` gives it) and the
+//! code Telegram sends to the account. With `--qr` it prints a QR code instead, to be scanned
+//! in a Telegram app signed in to the account under Settings > Devices > Link Desktop Device,
+//! which works without a phone number or a code on a headless server.
+//!
+//! The two-step verification password, if the account has one, is the first line of the file
+//! given with `--password-file <file>`, or else the `TG_PASSWORD` environment variable, or else
+//! the first line of `account.password_file` from the configuration; it is only asked for when
+//! none of them has it. The session file is only readable by its owner, as it gives full access
+//! to the account.
+
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use grammers_client::grammers_tl_types as tl;
+use grammers_client::session::Session;
+use grammers_client::types::{PasswordToken, User};
+use grammers_client::{Client, Config, InvocationError, SignInError, Update};
+use tokio::runtime::Runtime;
+
+use crate::accounts::Account;
+use crate::config::{self, expand_home};
+use crate::credentials::Credentials;
+use crate::qr::QrCode;
+use crate::DEFAULT_SESSION_FILE;
+
+// The data center a client without an account connects to
+const LOGIN_DC: i32 = 2;
+
+// Longest wait before a QR code is replaced by a fresh one
+const MAX_QR_WAIT: Duration = Duration::from_secs(60);
+
+/* Options of the login subcommand:
+telegram_cloud_filesystem login [--config <file>] [--credentials <file>]
+    [--session <file> | --account <name>] [--qr | --phone <number>] [--password-file <file>]*/
+struct LoginOptions {
+    credentials: Credentials,
+    session_file: PathBuf,
+    qr: bool,
+    phone: Option<String>,
+    password: Option<String>,
+}
+
+impl LoginOptions {
+    fn from_args(args: Vec<String>) -> Result<Self, String> {
+        let usage = "Usage: ./program login [--config <file>] [--credentials <file>] \
+            [--session <file> | --account <name>] [--qr | --phone <number>] [--password-file <file>]";
+        let mut config_path = None;
+        let mut credentials_file = None;
+        let mut session_file = None;
+        let mut account_name = None;
+        let mut qr = false;
+        let mut phone = None;
+        let mut password_file = None;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => config_path = Some(PathBuf::from(args.next().ok_or(usage)?)),
+                "--credentials" => credentials_file = Some(PathBuf::from(args.next().ok_or(usage)?)),
+                "--session" => session_file = Some(PathBuf::from(args.next().ok_or(usage)?)),
+                "--account" => account_name = Some(args.next().ok_or(usage)?),
+                "--qr" => qr = true,
+                "--phone" => phone = Some(args.next().ok_or(usage)?),
+                "--password-file" => password_file = Some(PathBuf::from(args.next().ok_or(usage)?)),
+                _ => return Err(usage.to_string()),
+            }
+        }
+        if qr && phone.is_some() {
+            return Err("only one of --qr and --phone can be given".to_string());
+        }
+        if session_file.is_some() && account_name.is_some() {
+            return Err("only one of --session and --account can be given".to_string());
+        }
+
+        let (config, _) = config::Config::load(config_path.as_deref())?;
+        let account = &config.account;
+        let (credentials, session_file, configured_password) = match &account_name {
+            Some(name) => {
+                let named = Account::configured(&config, name, credentials_file.as_deref())?;
+                let password_file = config.accounts[name].password_file.as_ref().or(account.password_file.as_ref());
+                (named.credentials, named.session_file, password_file)
+            }
+            None => {
+                let configured = account.credentials.as_deref().map(expand_home);
+                let credentials = Credentials::find(credentials_file.as_deref(), account.api_id, account.api_hash.as_deref(), configured.as_deref())?;
+                let session_file = session_file
+                    .or_else(|| account.session.as_deref().map(expand_home))
+                    .unwrap_or_else(|| PathBuf::from(DEFAULT_SESSION_FILE));
+                (credentials, session_file, account.password_file.as_ref())
+            }
+        };
+
+        // The password from the command line, the environment, then the configuration file
+        let password = match (password_file, env::var("TG_PASSWORD")) {
+            (Some(path), _) => Some(read_password(&path).map_err(|e| format!("failed to read --password-file {}: {e}", path.display()))?),
+            (None, Ok(password)) => Some(password),
+            (None, Err(_)) => match configured_password {
+                Some(path) => Some(read_password(&expand_home(path)).map_err(|e| format!("failed to read password_file {}: {e}", path.display()))?),
+                None => None,
+            },
+        };
+
+        Ok(Self {
+            credentials,
+            session_file,
+            qr,
+            phone: phone.or_else(|| env::var("TG_PHONE").ok()),
+            password,
+        })
+    }
+}
+
+// Sign in with the arguments following `login` on the command line and save the session
+pub fn run(args: Vec<String>) -> Result<(), String> {
+    let options = LoginOptions::from_args(args)?;
+    let rt = Runtime::new().map_err(|e| format!("failed to start the runtime: {e}"))?;
+    rt.block_on(login(&options))
+}
+
+async fn login(options: &LoginOptions) -> Result<(), String> {
+    let session = Session::load_file_or_create(&options.session_file)
+        .map_err(|e| format!("failed to load the session {}: {e}", options.session_file.display()))?;
+    println!("Connecting to Telegram...");
+    let client = connect(&options.credentials, session).await?;
+    if client.is_authorized().await.map_err(|e| format!("failed to reach Telegram: {e}"))? {
+        println!("{} is signed in already", options.session_file.display());
+        return Ok(());
+    }
+
+    let client = if options.qr { qr_login(client, options).await? } else { code_login(client, options).await? };
+
+    save_session(&client, &options.session_file)?;
+    println!("Signed in! The session is saved to {}", options.session_file.display());
+    Ok(())
+}
+
+// Save the authorized session of `client` to `path` for the daemon, readable by nobody else
+pub fn save_session(client: &Client, path: &Path) -> Result<(), String> {
+    client.session().save_to_file(path).map_err(|e| format!("failed to save the session {}: {e}", path.display()))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600)).map_err(|e| format!("failed to restrict {}: {e}", path.display()))
+}
+
+async fn connect(credentials: &Credentials, session: Session) -> Result<Client, String> {
+    let Credentials { api_id, api_hash } = credentials.clone();
+    Client::connect(Config { session, api_id, api_hash, params: Default::default() })
+        .await
+        .map_err(|e| format!("failed to connect to Telegram: {e}"))
+}
+
+// Sign in with the phone number and the code sent to the account
+async fn code_login(client: Client, options: &LoginOptions) -> Result<Client, String> {
+    let phone = match &options.phone {
+        Some(phone) => phone.clone(),
+        None => prompt("Enter your phone number (international format): ")?,
+    };
+    let token = client.request_login_code(phone.trim()).await.map_err(|e| format!("failed to request a login code: {e}"))?;
+    let code = prompt("Enter the code you received: ")?;
+    match client.sign_in(&token, code.trim()).await {
+        Ok(_) => {}
+        Err(SignInError::PasswordRequired(password_token)) => check_password(&client, password_token, options).await?,
+        Err(SignInError::SignUpRequired { .. }) => return Err(no_account()),
+        Err(e) => return Err(format!("failed to sign in: {e}")),
+    }
+    Ok(client)
+}
+
+/* Sign in by scanning QR codes, each holding a login token that expires after a while.
+Once a code is scanned, the next token exported is the authorization, or names the data
+center of the account, where the token is imported to complete it.*/
+async fn qr_login(mut client: Client, options: &LoginOptions) -> Result<Client, String> {
+    let Credentials { api_id, api_hash } = options.credentials.clone();
+    let export = tl::functions::auth::ExportLoginToken { api_id, api_hash, except_ids: vec![] };
+    let mut shown = vec![];
+    loop {
+        let token = match client.invoke(&export).await {
+            Ok(tl::enums::auth::LoginToken::Token(token)) => token,
+            Ok(tl::enums::auth::LoginToken::MigrateTo(migrate)) => {
+                // A session naming the account's data center connects there
+                let session = Session::load(&client.session().save()).map_err(|e| format!("failed to copy the session: {e}"))?;
+                session.set_user(0, migrate.dc_id, false);
+                client = connect(&options.credentials, session).await?;
+                let imported = client.invoke(&tl::functions::auth::ImportLoginToken { token: migrate.token }).await;
+                return complete_qr_login(client, imported, migrate.dc_id, options).await;
+            }
+            exported => return complete_qr_login(client, exported, LOGIN_DC, options).await,
+        };
+        if token.token != shown {
+            show_qr_code(&token.token)?;
+            shown = token.token;
+        }
+        wait_for_scan(&client, token.expires).await;
+    }
+}
+
+// Record the account a scanned QR code signed in to, asking for its password if it has one
+async fn complete_qr_login(
+    client: Client,
+    result: Result<tl::enums::auth::LoginToken, InvocationError>,
+    dc_id: i32,
+    options: &LoginOptions,
+) -> Result<Client, String> {
+    match result {
+        Ok(tl::enums::auth::LoginToken::Success(success)) => match success.authorization {
+            tl::enums::auth::Authorization::Authorization(authorization) => {
+                let user = User::from_raw(authorization.user);
+                client.session().set_user(user.id(), dc_id, false);
+                Ok(client)
+            }
+            tl::enums::auth::Authorization::SignUpRequired(_) => Err(no_account()),
+        },
+        Ok(_) => Err("the QR code login did not complete, try again".to_string()),
+        Err(e) if e.is("SESSION_PASSWORD_NEEDED") => {
+            let tl::enums::account::Password::Password(password) = client
+                .invoke(&tl::functions::account::GetPassword {})
+                .await
+                .map_err(|e| format!("failed to get the password parameters: {e}"))?;
+            check_password(&client, PasswordToken::new(password), options).await?;
+            Ok(client)
+        }
+        Err(e) => Err(format!("failed to sign in with the QR code: {e}")),
+    }
+}
+
+fn show_qr_code(token: &[u8]) -> Result<(), String> {
+    let link = format!("tg://login?token={}", URL_SAFE_NO_PAD.encode(token));
+    let code = QrCode::encode(link.as_bytes()).ok_or("the login token is too long for a QR code")?;
+    println!("\nScan this QR code in a Telegram app signed in to your account,");
+    println!("under Settings > Devices > Link Desktop Device:\n");
+    print!("{}", code.render());
+    println!("\nor open {link} on a device signed in to it.");
+    Ok(())
+}
+
+// Wait until the QR code of a token that expires at `expires` (unix time) is scanned or runs out
+async fn wait_for_scan(client: &Client, expires: i32) {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs() as i64);
+    let left = Duration::from_secs((expires as i64 - now).max(1) as u64).min(MAX_QR_WAIT);
+    let scanned = async {
+        loop {
+            match client.next_update().await {
+                Ok(Update::Raw(tl::enums::Update::LoginToken)) => return,
+                Ok(_) => {}
+                // Without updates the token is exported again once it expires
+                Err(_) => std::future::pending::<()>().await,
+            }
+        }
+    };
+    let _ = tokio::time::timeout(left, scanned).await;
+}
+
+// Enter the two-step verification password, asking for it if none was given
+async fn check_password(client: &Client, token: PasswordToken, options: &LoginOptions) -> Result<(), String> {
+    let password = match &options.password {
+        Some(password) => password.clone(),
+        None => {
+            let hint = token.hint().map_or_else(String::new, |hint| format!(" (hint {hint})"));
+            prompt(&format!("Enter the password{hint}: "))?
+        }
+    };
+    match client.check_password(token, password.trim_end_matches(['\r', '\n'])).await {
+        Ok(_) => Ok(()),
+        Err(SignInError::InvalidPassword) => Err("the two-step verification password is wrong".to_string()),
+        Err(e) => Err(format!("failed to check the password: {e}")),
+    }
+}
+
+// The first line of the file at `path`
+fn read_password(path: &Path) -> io::Result<String> {
+    let contents = fs::read_to_string(path)?;
+    match contents.lines().next() {
+        Some(password) if !password.is_empty() => Ok(password.to_string()),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "the password is empty")),
+    }
+}
+
+fn no_account() -> String {
+    "this phone number has no Telegram account, sign up in a Telegram app first".to_string()
+}
+
+fn prompt(message: &str) -> Result<String, String> {
+    let ask = || -> io::Result<String> {
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(message.as_bytes())?;
+        stdout.flush()?;
+        let mut line = String::new();
+        io::stdin().lock().read_line(&mut line)?;
+        Ok(line)
+    };
+    ask().map_err(|e| format!("failed to read the answer: {e}"))
+}
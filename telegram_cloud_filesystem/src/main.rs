@@ -1,272 +1,2039 @@
 //! Example to mount media from a telegram chat to virtual filesystem.
 //!
-//! The `TG_ID` and `TG_HASH` environment variables must be set 
+//! Telegram API credentials are read at startup from the `TG_ID` and `TG_HASH` environment
+//! variables, the configuration file or a credentials file (`--credentials <file>`), see the
+//! `credentials` module.
 //! Instruction to get TG_ID and TG_HASH: https://core.telegram.org/api/obtaining_api_id#obtaining-api-id
 //! 
+//! Only file metadata is fetched up front; the content of a file is downloaded
+//! chunk by chunk when it is read.
+//!
+//! Files copied into a chat folder (e.g. `cp report.pdf ~/mnt/MyChat/`) are uploaded
+//! to that chat as documents when they are closed. Until then their content is kept in a
+//! temporary file under the cache directory, see the `staging` module.
+//!
 //! To run:
 //! open terminal in Telegram_Cloud_Storage directory and type:
 //! cargo run --bin telegram_cloud_filesystem ~/path/where/to/mount
+//!
+//! The daemon does not sign in by itself. Run `telegram_cloud_filesystem login` once to create
+//! the session file, interactively or by scanning a QR code, see the `login` module. Given a
+//! bot token with `--bot-token-file <file>`, it signs in as that bot instead and mounts the
+//! chats the bot is a member of, see the `bot` module.
+//!
+//! Several accounts, listed in the `[accounts]` section of the configuration, can be mounted
+//! side by side, each as a top-level directory holding its chats, see the `accounts` module.
+//!
+//! Chat folders are named after their chats, made safe for file names and unique: a second
+//! chat with a taken title gets its @username or id appended. A renamed chat's folder follows
+//! the new title without disturbing open files. With `--by-id`, a `by-id` directory holds a
+//! symbolic link per chat folder named after the chat id. See the `chat_names` module.
+//!
+//! With `--media-dirs`, every chat folder also has `photos`, `videos`, `documents`, `audio`,
+//! `voice` and `stickers` directories listing the files of that type, see the `media_views` module.
+//!
+//! Every option below can also be set in `telegramfs.toml` (or the file given with
+//! `--config <file>`), along with the session file (`--session <file>`), which chats to show,
+//! the attribute timeout and naming rules; flags on the command line win. See the `config` module.
+//!
+//! Downloaded content is kept in a block cache on disk, which can be configured with
+//! `--cache-dir <dir>` (default `~/.cache/telegramfs`) and `--cache-size <size>`
+//! (default `1G`, suffixes `K`, `M` and `G` are accepted). Recently read blocks are also
+//! kept in memory, bounded by `--mem-cache <size>` (default `64M`, `0` disables it) and
+//! evicted by `--mem-cache-policy lru|lfu`; hit/miss/eviction counters are logged every minute.
+//!
+//! Files are owned by the user running the daemon unless `--uid <uid>` / `--gid <gid>` are given,
+//! and carry the date of their message as modification time (and its edit date as change time).
+//!
+//! New, edited and deleted messages show up as soon as Telegram reports them. As a fallback for
+//! missed updates, all chats are reconciled every `--refresh-interval <seconds>` (default 300),
+//! and scanned again in full every twelfth time; chats that were left or deleted lose their folder.
+//!
+//! Removing a file (`rm ~/mnt/MyChat/report.pdf`) deletes its message. By default it is only
+//! deleted for this account; with `--delete-for everyone` it is deleted for all members of the
+//! chat. Telegram cannot delete messages in channels and supergroups for a single member, so
+//! there they are always deleted for everyone, which needs admin rights for other people's posts.
+//!
+//! Renaming a file within a chat stores the new name in the caption of its message, or on this
+//! machine if the message cannot be edited. Moving a file to another chat re-sends it there
+//! (downloading and uploading it again if the source chat forbids forwarding) and then deletes
+//! the original, unless `--move-originals keep` is given.
+//!
+//! `mkdir ~/mnt/Backups` creates a new private channel named `Backups`, or a basic group with
+//! only this account in it when `--mkdir-creates group` is given, and shows it as an empty folder.
+//! Inside a chat folder, `mkdir` creates a directory; directories can be nested to any depth and
+//! are stored in the chat itself, see the `tree` module. Renaming a directory is not supported
+//! (EXDEV), so `mv` copies its contents instead.
+//!
+//! Files larger than `--part-size <size>` (default `2000M`, Telegram's limit for a document;
+//! premium accounts may use up to `4000M`) are uploaded in parts and shown as one file, see
+//! the `chunks` module.
+//!
+//! In supergroups with topics enabled, every forum topic is a directory of the chat folder, and
+//! files written into it are posted in that topic. `mkdir` in the chat folder of a forum creates
+//! a new topic; topics cannot be removed through the mount. See the `topics` module.
+//!
+//! With `--passphrase-file <file>` (the passphrase on its first line) or `--key-file <file>`,
+//! files written through the mount are encrypted before they are uploaded, names included,
+//! and decrypted again when they are read, see the `crypt` module.
+//!
+//! Content that is stored already is not uploaded twice: a file with the same content refers to
+//! the existing one, and files with the same content show up as hard links of each other
+//! (`ln` makes another), see the `dedup` module.
+
+mod accounts;
+mod block_cache;
+mod bot;
+mod chat_folders;
+mod chat_names;
+mod chunks;
+mod config;
+mod credentials;
+mod crypt;
+mod dedup;
+mod deadline;
+mod inodes;
+mod local_names;
+mod login;
+mod media_views;
+mod mem_cache;
+mod meta;
+mod naming;
+mod pattern;
+mod persist;
+mod qr;
+mod staging;
+mod sync;
+mod topics;
+mod tree;
 
+use std::cell::Cell;
 use std::ffi::OsStr;
-use std::io::{BufRead, Write};
-use std::sync::Arc;
-use std::time::{Duration, UNIX_EPOCH};
-use std::{env, io};
-
-use fuser::{FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyEntry, Request};
-use grammers_client::types::Downloadable;
-use grammers_client::{Client, Config, SignInError};
-use libc::ENOENT;
-use mime::Mime;
-use mime_guess::mime;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+use std::cmp::min;
+use std::str::FromStr;
+use std::{env, fs, io};
+
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, Notifier, ReplyAttr, ReplyCreate, ReplyData, ReplyEmpty, ReplyEntry, ReplyOpen,
+    ReplyWrite, Request, TimeOrNow,
+};
+use grammers_client::types::{Chat, Downloadable, Message, PackedChat};
+use grammers_client::grammers_tl_types as tl;
+use grammers_client::{Client, Config, InputMessage, InvocationError};
+use libc::{EACCES, EBADF, EEXIST, EFBIG, EINVAL, EIO, EISDIR, ENAMETOOLONG, ENOENT, ENOSPC, ENOTEMPTY, EPERM, EXDEV};
 use simple_logger::SimpleLogger;
+use tokio::io::AsyncReadExt;
 use tokio::runtime::Runtime;
 
+use accounts::{Account, Accounts};
+use block_cache::{BlockCache, BlockKey};
+use chat_names::BY_ID_DIR;
+use chunks::{part_caption, part_name, Manifest};
+use config::{expand_home, ChatFilter};
+use credentials::Credentials;
+use crypt::{Crypt, Secret};
+use dedup::Links;
+use deadline::Watched;
+use inodes::{InodeKey, InodeTable, ROOT_INO};
+use local_names::LocalNames;
+use media_views::{MediaKind, ViewCache};
+use mem_cache::{EvictionPolicy, MemoryCache};
+use meta::Meta;
+use naming::{document_name, NamingRules};
+use staging::Staged;
+use sync::Updater;
+use tree::{join, rebuild_dirs, split};
+
 use grammers_client::session::Session;
 use grammers_client::types::Media::{self, Contact, Document, Photo, Sticker};
 use std::sync::RwLock;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
-// This allows resuming sessions without re-authenticating every time.
-const SESSION_FILE: &str = "downloader.session";
-
-// Time-To-Live for cached file attributes in the virtual filesystem.
-const TTL: Duration = Duration::from_secs(1); // 1 second
-
-// This describes a directory inode with standard permissions and timestamps
-const DIR_ATTR: FileAttr = FileAttr {
-    ino: 1,
-    size: 0,
-    blocks: 0,
-    atime: UNIX_EPOCH, // 1970-01-01 00:00:00
-    mtime: UNIX_EPOCH,
-    ctime: UNIX_EPOCH,
-    crtime: UNIX_EPOCH,
-    kind: FileType::Directory,
-    perm: 0o755,
-    nlink: 2,
-    uid: 501,
-    gid: 20,
-    rdev: 0,
-    flags: 0,
-    blksize: 512,
-};
+// Default session file. This allows resuming sessions without re-authenticating every time.
+pub(crate) const DEFAULT_SESSION_FILE: &str = "downloader.session";
+
+// Inode numbers handed out so far, kept so they stay the same across mounts.
+const INODE_FILE: &str = "telegramfs.inodes";
+
+// Names of files renamed on this machine because their message could not be edited.
+const NAMES_FILE: &str = "telegramfs.names";
+
+// Size of the chunks file content is downloaded in. Telegram requires it to divide 1 MiB,
+// which keeps every chunk-aligned request inside a single 1 MiB window.
+const CHUNK_SIZE: i32 = 128 * 1024;
+
+// Error code of Telegram for files stored in another data center
+const FILE_MIGRATE_ERROR: i32 = 303;
+
+// Default size of the parts larger files are split into: the most Telegram accepts for one document
+const DEFAULT_PART_SIZE: u64 = 2000 * 1024 * 1024;
+
+// Largest file that can be written through the mount; writes and truncation beyond it fail with EFBIG
+const MAX_FILE_SIZE: u64 = 64 * 1024 * 1024 * 1024;
+
+// Longest chat title Telegram accepts, in characters
+const MAX_TITLE_LEN: usize = 128;
+
+// Default upper bound for the size of the on-disk block cache
+const DEFAULT_CACHE_SIZE: u64 = 1024 * 1024 * 1024;
+
+// Default memory budget for file content blocks
+const DEFAULT_MEM_CACHE_SIZE: u64 = 64 * 1024 * 1024;
+
+// Default time between reconciliation passes over all chats; updates are applied as they arrive
+const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+
+// How often the memory cache counters are logged by default
+const DEFAULT_STATS_INTERVAL: Duration = Duration::from_secs(60);
+
+// Default Time-To-Live for cached file attributes in the virtual filesystem.
+// The kernel is notified whenever a chat changes, so entries can be cached for long.
+const DEFAULT_TTL: Duration = Duration::from_secs(300); // 5 minutes
+
+
+/* Command line options of the filesystem daemon:
+telegram_cloud_filesystem <mountpoint> [--config <file>] [--credentials <file>] [--session <file>]
+    [--bot-token-file <file>] [--cache-dir <dir>] [--cache-size <size>]
+    [--mem-cache <size>] [--mem-cache-policy lru|lfu] [--uid <uid>] [--gid <gid>]
+    [--refresh-interval <seconds>] [--delete-for me|everyone] [--move-originals delete|keep]
+    [--mkdir-creates channel|group] [--part-size <size>] [--by-id] [--media-dirs]
+    [--passphrase-file <file> | --key-file <file>]
+Every option but `--config` can also be set in the configuration file, see the `config` module;
+flags given on the command line win over it.*/
+struct Options {
+    mountpoint: PathBuf,
+    accounts: Vec<Account>,
+    cache_dir: PathBuf,
+    cache_size: u64,
+    mem_cache_size: u64,
+    mem_cache_policy: EvictionPolicy,
+    owner: Owner,
+    allow_other: bool,
+    by_id: bool,
+    media_dirs: bool,
+    ttl: Duration,
+    refresh_interval: Duration,
+    stats_interval: Duration,
+    delete_for: DeleteFor,
+    keep_moved_originals: bool,
+    new_chat_kind: ChatKind,
+    part_size: u64,
+    secret: Option<Secret>,
+    chat_filter: ChatFilter,
+    naming: NamingRules,
+}
+
+impl Options {
+    fn from_args() -> std::result::Result<Self, String> {
+        let usage = "Usage: ./program <mountpoint> [--config <file>] [--credentials <file>] [--session <file>] \
+            [--bot-token-file <file>] [--cache-dir <dir>] [--cache-size <size>] \
+            [--mem-cache <size>] [--mem-cache-policy lru|lfu] [--uid <uid>] [--gid <gid>] \
+            [--refresh-interval <seconds>] [--delete-for me|everyone] [--move-originals delete|keep] \
+            [--mkdir-creates channel|group] [--part-size <size>] [--by-id] [--media-dirs] \
+            [--passphrase-file <file> | --key-file <file>]";
+        let mut args: Vec<String> = env::args().skip(1).collect();
+
+        // The configuration file provides the defaults the other flags override
+        let config_path = match args.iter().position(|arg| arg == "--config") {
+            Some(i) => {
+                let path = PathBuf::from(args.get(i + 1).ok_or(usage)?);
+                args.drain(i..=i + 1);
+                Some(path)
+            }
+            None => None,
+        };
+        let (config, config_path) = config::Config::load(config_path.as_deref())?;
+        let file = config_path.map_or_else(String::new, |path| path.display().to_string());
+        let invalid = |key: &str, reason: &str| format!("invalid {key} in {file}: {reason}");
+        let size = |key: &str, value: &Option<config::Size>, default: u64| match value {
+            Some(size) => size.bytes().ok_or_else(|| invalid(key, "expected a size like 512M")),
+            None => Ok(default),
+        };
+        let seconds = |key: &str, value: Option<u64>, default: Duration| match value {
+            Some(0) => Err(invalid(key, "expected at least 1 second")),
+            Some(seconds) => Ok(Duration::from_secs(seconds)),
+            None => Ok(default),
+        };
+
+        let account = &config.account;
+        let mut credentials_file = None;
+        let mount = &config.mount;
+        let mut mountpoint = mount.mountpoint.as_deref().map(expand_home);
+        let mut session_file = None;
+        let mut cache_dir = config.cache.dir.as_deref().map(expand_home);
+        let mut cache_size = size("cache.size", &config.cache.size, DEFAULT_CACHE_SIZE)?;
+        let mut mem_cache_size = size("cache.memory", &config.cache.memory, DEFAULT_MEM_CACHE_SIZE)?;
+        let mut mem_cache_policy = match &config.cache.memory_policy {
+            Some(policy) => policy.parse().map_err(|e: String| invalid("cache.memory_policy", &e))?,
+            None => EvictionPolicy::Lru,
+        };
+        let mut owner = Owner::current();
+        owner.uid = mount.uid.unwrap_or(owner.uid);
+        owner.gid = mount.gid.unwrap_or(owner.gid);
+        let ttl = seconds("mount.attr_timeout", mount.attr_timeout, DEFAULT_TTL)?;
+        let mut refresh_interval = seconds("refresh.interval", config.refresh.interval, DEFAULT_REFRESH_INTERVAL)?;
+        let stats_interval = seconds("refresh.stats_interval", config.refresh.stats_interval, DEFAULT_STATS_INTERVAL)?;
+        let mut delete_for = match &mount.delete_for {
+            Some(value) => value.parse().map_err(|e: String| invalid("mount.delete_for", &e))?,
+            None => DeleteFor::Me,
+        };
+        let mut keep_moved_originals = match &mount.move_originals {
+            Some(value) => keep_originals(value).map_err(|e| invalid("mount.move_originals", &e))?,
+            None => false,
+        };
+        let mut new_chat_kind = match &mount.mkdir_creates {
+            Some(value) => value.parse().map_err(|e: String| invalid("mount.mkdir_creates", &e))?,
+            None => ChatKind::Channel,
+        };
+        let mut part_size = size("mount.part_size", &mount.part_size, DEFAULT_PART_SIZE)?;
+        let mut by_id = mount.by_id.unwrap_or(false);
+        let mut media_dirs = mount.media_dirs.unwrap_or(false);
+        if !valid_part_size(part_size) {
+            return Err(invalid("mount.part_size", "expected a multiple of 128K"));
+        }
+        let encryption = &config.encryption;
+        if encryption.passphrase_file.is_some() && encryption.key_file.is_some() {
+            return Err(invalid("encryption", "only one of passphrase_file and key_file can be given"));
+        }
+        let naming = NamingRules::new(config.naming.unnamed.as_deref(), config.naming.numbered.as_deref()).map_err(|e| invalid("naming", &e))?;
+        let mut secret = None;
+        let mut bot_token = None;
+        let mut positional = false;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--credentials" => {
+                    credentials_file = Some(PathBuf::from(args.next().ok_or(usage)?));
+                }
+                "--session" => {
+                    session_file = Some(PathBuf::from(args.next().ok_or(usage)?));
+                }
+                "--bot-token-file" => {
+                    let path = PathBuf::from(args.next().ok_or(usage)?);
+                    bot_token = Some(bot::read_token(&path).map_err(|e| format!("failed to read --bot-token-file {}: {e}", path.display()))?);
+                }
+                "--by-id" => by_id = true,
+                "--media-dirs" => media_dirs = true,
+                "--cache-dir" => {
+                    cache_dir = Some(PathBuf::from(args.next().ok_or(usage)?));
+                }
+                "--cache-size" => {
+                    let value = args.next().ok_or(usage)?;
+                    cache_size = parse_size(&value).ok_or(format!("invalid --cache-size: {value}"))?;
+                }
+                "--mem-cache" => {
+                    let value = args.next().ok_or(usage)?;
+                    mem_cache_size = parse_size(&value).ok_or(format!("invalid --mem-cache: {value}"))?;
+                }
+                "--mem-cache-policy" => {
+                    mem_cache_policy = args.next().ok_or(usage)?.parse()?;
+                }
+                "--uid" => {
+                    let value = args.next().ok_or(usage)?;
+                    owner.uid = value.parse().map_err(|_| format!("invalid --uid: {value}"))?;
+                }
+                "--gid" => {
+                    let value = args.next().ok_or(usage)?;
+                    owner.gid = value.parse().map_err(|_| format!("invalid --gid: {value}"))?;
+                }
+                "--refresh-interval" => {
+                    let value = args.next().ok_or(usage)?;
+                    let seconds = value.parse().ok().filter(|&s| s > 0);
+                    refresh_interval = Duration::from_secs(seconds.ok_or(format!("invalid --refresh-interval: {value}"))?);
+                }
+                "--delete-for" => {
+                    delete_for = args.next().ok_or(usage)?.parse().map_err(|e| format!("invalid --delete-for: {e}"))?;
+                }
+                "--move-originals" => {
+                    keep_moved_originals = keep_originals(&args.next().ok_or(usage)?).map_err(|e| format!("invalid --move-originals: {e}"))?;
+                }
+                "--mkdir-creates" => {
+                    new_chat_kind = args.next().ok_or(usage)?.parse().map_err(|e| format!("invalid --mkdir-creates: {e}"))?;
+                }
+                "--part-size" => {
+                    let value = args.next().ok_or(usage)?;
+                    let size = parse_size(&value).filter(|&size| valid_part_size(size));
+                    part_size = size.ok_or(format!("invalid --part-size: {value}, expected a multiple of 128K"))?;
+                }
+                "--passphrase-file" | "--key-file" if secret.is_some() => {
+                    return Err("only one of --passphrase-file and --key-file can be given".to_string());
+                }
+                "--passphrase-file" => {
+                    let path = PathBuf::from(args.next().ok_or(usage)?);
+                    let read = Secret::passphrase_file(&path);
+                    secret = Some(read.map_err(|e| format!("failed to read --passphrase-file {}: {e}", path.display()))?);
+                }
+                "--key-file" => {
+                    let path = PathBuf::from(args.next().ok_or(usage)?);
+                    let read = Secret::key_file(&path);
+                    secret = Some(read.map_err(|e| format!("failed to read --key-file {}: {e}", path.display()))?);
+                }
+                _ if !arg.starts_with("--") && !positional => {
+                    mountpoint = Some(PathBuf::from(arg));
+                    positional = true;
+                }
+                _ => return Err(usage.to_string()),
+            }
+        }
+
+        // The accounts of the `[accounts]` section, or the one the flags and `[account]` describe
+        let accounts = if config.accounts.is_empty() {
+            let configured = account.credentials.as_deref().map(expand_home);
+            let credentials = Credentials::find(credentials_file.as_deref(), account.api_id, account.api_hash.as_deref(), configured.as_deref())?;
+            let session_file = session_file
+                .or_else(|| account.session.as_deref().map(expand_home))
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_SESSION_FILE));
+            // A bot token from the command line, the environment, then the configuration file
+            if bot_token.is_none() {
+                if let Ok(token) = env::var("TG_BOT_TOKEN") {
+                    bot::check_token(token.trim()).map_err(|e| format!("invalid TG_BOT_TOKEN: {e}"))?;
+                    bot_token = Some(token.trim().to_string());
+                } else if let Some(path) = &account.bot_token_file {
+                    let read = bot::read_token(&expand_home(path));
+                    bot_token = Some(read.map_err(|e| format!("failed to read account.bot_token_file {}: {e}", path.display()))?);
+                }
+            }
+            vec![Account { name: None, credentials, session_file, bot_token }]
+        } else {
+            if session_file.is_some() || bot_token.is_some() {
+                return Err(format!("--session and --bot-token-file apply to a single account, set them in the [accounts] section of {file}"));
+            }
+            let accounts = config
+                .accounts
+                .keys()
+                .map(|name| Account::configured(&config, name, credentials_file.as_deref()))
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            accounts::check_slots(&accounts)?;
+            accounts
+        };
+
+        // A key from the command line replaces the one from the configuration file
+        if secret.is_none() {
+            if let Some(path) = &encryption.passphrase_file {
+                let read = Secret::passphrase_file(&expand_home(path));
+                secret = Some(read.map_err(|e| format!("failed to read encryption.passphrase_file {}: {e}", path.display()))?);
+            } else if let Some(path) = &encryption.key_file {
+                let read = Secret::key_file(&expand_home(path));
+                secret = Some(read.map_err(|e| format!("failed to read encryption.key_file {}: {e}", path.display()))?);
+            }
+        }
+
+        Ok(Self {
+            mountpoint: mountpoint.ok_or(usage)?,
+            accounts,
+            cache_dir: cache_dir.unwrap_or_else(default_cache_dir),
+            cache_size,
+            mem_cache_size,
+            mem_cache_policy,
+            owner,
+            allow_other: mount.allow_other.unwrap_or(true),
+            by_id,
+            media_dirs,
+            ttl,
+            refresh_interval,
+            stats_interval,
+            delete_for,
+            keep_moved_originals,
+            new_chat_kind,
+            part_size,
+            secret,
+            chat_filter: config.chats.clone(),
+            naming,
+        })
+    }
+}
+
+// Whether moved files keep their original, from `delete` or `keep`
+fn keep_originals(value: &str) -> std::result::Result<bool, String> {
+    match value {
+        "delete" => Ok(false),
+        "keep" => Ok(true),
+        _ => Err(format!("{value}, expected delete or keep")),
+    }
+}
+
+// Parts are downloaded in whole chunks, so a chunk must never span two parts
+fn valid_part_size(size: u64) -> bool {
+    size > 0 && size.is_multiple_of(CHUNK_SIZE as u64)
+}
+
+// Whom a message is deleted for when its file is removed
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum DeleteFor {
+    Me,
+    Everyone,
+}
+
+impl FromStr for DeleteFor {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "me" => Ok(Self::Me),
+            "everyone" => Ok(Self::Everyone),
+            _ => Err(format!("{s}, expected me or everyone")),
+        }
+    }
+}
+
+// The kind of chat created for a new folder at the mount root
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ChatKind {
+    Channel,
+    Group,
+}
+
+impl FromStr for ChatKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "channel" => Ok(Self::Channel),
+            "group" => Ok(Self::Group),
+            _ => Err(format!("{s}, expected channel or group")),
+        }
+    }
+}
+
+// User and group that own every file and folder of the mount
+#[derive(Clone, Copy)]
+struct Owner {
+    uid: u32,
+    gid: u32,
+}
+
+impl Owner {
+    // The user running the daemon
+    fn current() -> Self {
+        // SAFETY: getuid and getgid cannot fail and have no side effects
+        unsafe {
+            Self {
+                uid: libc::getuid(),
+                gid: libc::getgid(),
+            }
+        }
+    }
+}
 
 /* Structure representing a cached file in the virtual filesystem.
 Each CachedFile stores:
 - a unique inode number (`ino`)
 - the filename as a String (`name`)
-- the actual file content stored in an Arc<Vec<u8>> for thread-safe sharing
+- the ids of the Telegram chat and message carrying the media (`chat_id`, `message_id`)
+- the path of the directory holding the file, empty for the chat folder itself (`dir`)
+- whether this account sent the message (`outgoing`), which decides if it may delete it
+- where to get the file content from (`content`), see FileContent
+- the size of the content as stored in Telegram, for encrypted files (`sealed_size`); their
+  attributes carry the size of the plaintext
+- the digest of the content, if this account stored it through the mount (`digest`), see the `dedup` module
 - file attributes (`attr`) like size, permissions, timestamps, etc.*/
+#[derive(Clone)]
 pub struct CachedFile {
     pub ino: u64,
     pub name: String,
-    pub content: Arc<Vec<u8>>,
+    pub chat_id: i64,
+    pub message_id: i32,
+    pub dir: String,
+    pub outgoing: bool,
+    pub content: FileContent,
+    pub sealed_size: Option<u64>,
+    pub digest: Option<String>,
     pub attr: FileAttr,
 }
 
+/* Where the bytes of a CachedFile come from.
+Media is only downloaded when the file is read, and then only the requested range.
+Content that Telegram sends along with the message itself (like a contact's vCard)
+is kept in memory instead. Files split into parts are read from the media of the
+part messages their manifest lists, see the `chunks` module.*/
+#[derive(Clone)]
+pub enum FileContent {
+    Remote(Arc<Media>),
+    Inline(Arc<Vec<u8>>),
+    Parts(Arc<Manifest>),
+}
+
+/* A chat folder in the cache:
+- the inode number of the folder and the id of its chat (`ino`, `chat_id`)
+- the media files of the chat (`files`)
+- the directories inside the folder, at any depth (`dirs`)
+- the media of the messages carrying parts of split files, by message id (`parts`)
+- the date of the latest message in the chat (`mtime`), used as the folder's timestamps
+- a number that no other folder has and that changes whenever its files do (`version`),
+  for what is derived from the files, see `touch`*/
+#[derive(Clone)]
+pub struct CachedFolder {
+    pub ino: u64,
+    pub chat_id: i64,
+    pub files: Vec<CachedFile>,
+    pub dirs: Vec<CachedDir>,
+    pub parts: HashMap<i32, Arc<Media>>,
+    pub mtime: SystemTime,
+    pub version: u64,
+}
+
+// The last version handed out to a chat folder
+static FOLDER_VERSION: AtomicU64 = AtomicU64::new(0);
+
+// A version for a new chat folder or a changed one, see `CachedFolder`
+pub fn next_folder_version() -> u64 {
+    FOLDER_VERSION.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+impl CachedFolder {
+    // Record that the files changed, so what was derived from them is derived again
+    pub fn touch(&mut self) {
+        self.version = next_folder_version();
+    }
+
+    // Inode of the directory at `path`, the empty path being the folder itself
+    pub fn dir_ino(&self, path: &str) -> Option<u64> {
+        if path.is_empty() {
+            return Some(self.ino);
+        }
+        self.dirs.iter().find(|d| d.path == path).map(|d| d.ino)
+    }
+
+    // Path of the directory with inode `ino`, the empty path being the folder itself
+    pub fn dir_path(&self, ino: u64) -> Option<&str> {
+        if ino == self.ino {
+            return Some("");
+        }
+        self.dirs.iter().find(|d| d.ino == ino).map(|d| d.path.as_str())
+    }
+
+    // Whether the chat is a forum, whose files all live in topic directories
+    pub fn is_forum(&self) -> bool {
+        self.dirs.iter().any(|d| d.topic.is_some())
+    }
+
+    /* The forum topic holding the directory at `path`, along with the path of that directory
+    relative to the topic's, as stored in captions. None outside forums and for the folder itself.*/
+    pub fn topic<'a>(&self, path: &'a str) -> Option<(i32, &'a str)> {
+        let (first, rest) = path.split_once('/').unwrap_or((path, ""));
+        let topic = self.dirs.iter().find(|d| d.path == first)?.topic?;
+        Some((topic, rest))
+    }
+
+    // Whether the directory at `path` holds no files and no directories
+    pub fn is_empty_dir(&self, path: &str) -> bool {
+        !self.files.iter().any(|f| f.dir == path) && !self.dirs.iter().any(|d| split(&d.path).0 == path)
+    }
+}
+
+/* A directory inside a chat folder:
+- its inode number and its path below the folder, like `photos/2024` (`ino`, `path`)
+- the message marking it as made by `mkdir` (`marker`); directories without
+  one only exist because something is stored in them
+- the forum topic it shows (`topic`), for the top-level directories of forums
+- the date of the latest change below it (`mtime`)*/
+#[derive(Clone)]
+pub struct CachedDir {
+    pub ino: u64,
+    pub path: String,
+    pub marker: Option<i32>,
+    pub topic: Option<i32>,
+    pub mtime: SystemTime,
+}
+
+/* A file created through the mount that has not been closed yet.
+Writes are staged in a temporary file (`data`), see the `staging` module, and uploaded to the chat behind
+`folder`, into the directory at `dir`, when the file is flushed. `message_id` is set once the upload
+went through, so a later flush knows which message it replaces.*/
+struct PendingUpload {
+    ino: u64,
+    folder: String,
+    // The chat behind `folder`, whose name changes if the chat is renamed while the file is open
+    chat_id: i64,
+    dir: String,
+    name: String,
+    data: Staged,
+    dirty: bool,
+    message_id: Option<i32>,
+    // Time of the last write, reported until the file is uploaded
+    mtime: SystemTime,
+}
+
 /* Main Telegram client structure that manages:
 - The grammers Client instance to communicate with Telegram API.
 - A Tokio Runtime for async execution.
-- A cache that maps folder names (chat names) to a vector of CachedFiles representing messages/media in that folder.
-This cache is protected by a read-write lock and wrapped in Arc for safe concurrent access.*/
+- A cache that maps folder names (chat names) to a CachedFolder holding the messages/media in that folder.
+This cache is protected by a read-write lock and wrapped in Arc for safe concurrent access.
+- The packed chat of every folder, needed to upload files into it.
+- The table of inode numbers, shared by the cache updater and the filesystem.
+- The names of files renamed on this machine only.
+- The chats in which this account may delete messages sent by others.
+- The files sharing each inode, for link counts, see the `dedup` module.
+- Messages whose cached content went stale, queued by the cache updater for the filesystem.
+- The channel for telling the kernel about changes, available once the filesystem is mounted.
+- The inode of the directory holding the chat folders: the mount root, or the directory of the account.*/
 struct TelegramClient{
     my_client: Client,
     rt: Runtime,
+    root: u64,
     //cache: Arc<RwLock<Vec<CachedFile>>>,
-    cache: Arc<RwLock<HashMap<String, Vec<CachedFile>>>>,
+    cache: Arc<RwLock<HashMap<String, CachedFolder>>>,
+    chats: Arc<RwLock<HashMap<String, PackedChat>>>,
+    moderated: Arc<RwLock<HashSet<i64>>>,
+    links: Arc<Links>,
+    inodes: Arc<Mutex<InodeTable>>,
+    names: Arc<Mutex<LocalNames>>,
+    invalidated: Arc<Mutex<Vec<(i64, i32)>>>,
+    notifier: Arc<OnceLock<Notifier>>,
 }
 
 impl TelegramClient {
-    fn init() -> Self {
+    /* Connect `account` and load what previous mounts recorded for it.
+    Fails if it cannot connect or is not signed in, as the daemon never asks for anything.*/
+    fn init(account: &Account) -> std::result::Result<Self, String> {
     // 1. Create a new Tokio runtime for asynchronous tasks
-        let rt = Runtime::new().unwrap();
+        let rt = Runtime::new().map_err(|e| format!("failed to start the runtime: {e}"))?;
 
         // 2. Run all async code inside the runtime context
         let client = rt.block_on(async {
-            SimpleLogger::new()
-                .with_level(log::LevelFilter::Info)
-                .init()
-                .unwrap();
-            
-            // Read Telegram API credentials from environment variables
-            let api_id = env!("TG_ID").parse().expect("TG_ID invalid");
-            let api_hash = env!("TG_HASH").to_string();
+            let Credentials { api_id, api_hash } = account.credentials.clone();
+            let session_file = &account.session_file;
+            let session = Session::load_file_or_create(session_file).map_err(|e| format!("failed to load the session {}: {e}", session_file.display()))?;
 
             println!("Connecting to Telegram...");
             // Connect to Telegram client with session or create new session file
             let client = Client::connect(Config {
-                session: Session::load_file_or_create(SESSION_FILE).unwrap(),
+                session,
                 api_id,
                 api_hash: api_hash.clone(),
                 params: Default::default(),
             })
             .await
-            .unwrap();
+            .map_err(|e| format!("failed to connect to Telegram: {e}"))?;
             println!("Connected!");
-            
-            // Check if client is authorized (logged in)
-            if !client.is_authorized().await.unwrap() {
-                println!("Signing in...");
-                let phone = prompt("Enter your phone number (international format): ").unwrap();
-                let token = client.request_login_code(&phone).await.unwrap();
-                let code = prompt("Enter the code you received: ").unwrap();
-                let signed_in = client.sign_in(&token, &code).await;
-                
-                // Handle two-factor password if required
-                match signed_in {
-                    Err(SignInError::PasswordRequired(password_token)) => {
-                        let hint = password_token.hint().unwrap();
-                        let prompt_message = format!("Enter the password (hint {}): ", &hint);
-                        let password = prompt(prompt_message.as_str()).unwrap();
-
-                        client
-                            .check_password(password_token, password.trim())
-                            .await
-                            .unwrap();
+
+            // The daemon never asks for anything: users sign in with the `login` subcommand,
+            // bots with their token
+            let authorized = client.is_authorized().await.map_err(|e| format!("failed to check the session: {e}"))?;
+            match (authorized, &account.bot_token) {
+                (true, _) => {}
+                (false, Some(token)) => {
+                    println!("Signing in as a bot...");
+                    client.bot_sign_in(token).await.map_err(|e| format!("failed to sign in with the bot token: {e}"))?;
+                    if let Err(e) = login::save_session(&client, session_file) {
+                        log::warn!("{e}, will have to sign in again next time");
                     }
-                    Ok(_) => (),
-                    Err(e) => panic!("{}", e),
+                    println!("Signed in!");
                 }
-                
-                // Save the authorized session to the file for future reuse
-                match client.session().save_to_file(SESSION_FILE) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        println!(
-                            "NOTE: failed to save the session, will sign out when done: {e}"
-                        );
+                (false, None) => {
+                    let account = account.name.as_ref().map_or_else(String::new, |name| format!(" --account {name}"));
+                    return Err(format!("{} is not signed in, run `telegram_cloud_filesystem login{account}` first", session_file.display()));
+                }
+            }
+            if account.bot_token.is_some() && !bot::is_bot(&client) {
+                return Err(format!("{} is signed in to a user account, give the bot a session file of its own", session_file.display()));
+            }
+
+            // Return the connected and authorized client
+            Ok(client)
+        })?;
+
+        // 3. Load the inode numbers assigned by previous mounts
+        let root = account.root();
+        let inodes_file = account.state_file(INODE_FILE);
+        let inodes = InodeTable::load(&inodes_file, root).map_err(|e| format!("failed to load the inode table {}: {e}", inodes_file.display()))?;
+        let names_file = account.state_file(NAMES_FILE);
+        let names = LocalNames::load(&names_file).map_err(|e| format!("failed to load local file names {}: {e}", names_file.display()))?;
+
+        // 4. Return the TelegramClient structure with client, runtime, and empty cache
+        Ok(Self {
+            my_client: client,
+            rt,
+            root,
+            cache: Arc::new(RwLock::new(HashMap::new())),
+            chats: Arc::new(RwLock::new(HashMap::new())),
+            moderated: Arc::new(RwLock::new(HashSet::new())),
+            links: Arc::new(Links::default()),
+            inodes: Arc::new(Mutex::new(inodes)),
+            names: Arc::new(Mutex::new(names)),
+            invalidated: Arc::new(Mutex::new(vec![])),
+            notifier: Arc::new(OnceLock::new()),
+        })
+    }
+
+    /* Upload the bytes `range` of `source` as a document named `name` to the chat behind `folder`,
+    with `caption`, posting it in the forum topic `topic` if given. Returns the message that
+    carries the new document.*/
+    fn upload_document(&self, folder: &str, topic: Option<i32>, name: &str, source: &Staged, range: Range<u64>, caption: &str) -> Result<Message> {
+        let chat = match self.chats.read().unwrap().get(folder) {
+            Some(chat) => *chat,
+            None => return Err(format!("unknown chat folder {folder}").into()),
+        };
+        let file = source.at(range.start)?;
+        let len = range.end - range.start;
+
+        self.rt.block_on(async {
+            // Stream the staged bytes to Telegram, for as long as they keep going, then send them as a document
+            let last_read = Cell::new(tokio::time::Instant::now());
+            let mut stream = Watched::new(tokio::fs::File::from_std(file).take(len), &last_read);
+            let upload = async { Ok(self.my_client.upload_stream(&mut stream, len as usize, name.to_string()).await?) };
+            let uploaded = deadline::while_reading::<_, Box<dyn std::error::Error>>(&last_read, upload).await?;
+            let input = InputMessage::text(caption).document(uploaded).reply_to(topics::reply_to(topic));
+            deadline::within(async { Ok(self.my_client.send_message(chat, input).await?) }).await
+        })
+    }
+
+    /* Upload the content of `source` as a file named `name` to the chat behind `folder`, in the forum
+    topic `topic` if given, with `meta` in its caption. Files larger than `part_size` are sent as
+    numbered parts, each streamed from its own range of `source`, followed by a manifest listing
+    them (named `name` unless `meta` names the file already), see the `chunks` module; the parts
+    already sent are deleted again if a later one fails. Returns the message standing for the file,
+    which is the document itself or the manifest, and the messages carrying the parts.*/
+    fn upload_file(&self, folder: &str, topic: Option<i32>, name: &str, source: &Staged, mut meta: Meta, part_size: u64) -> Result<(Message, Vec<Message>)> {
+        let size = source.len();
+        // Telegram does not accept empty documents, so an empty file is a manifest without parts
+        if size > 0 && size <= part_size {
+            return Ok((self.upload_document(folder, topic, name, source, 0..size, &meta.to_caption())?, vec![]));
+        }
+
+        let count = size.div_ceil(part_size) as usize;
+        let mut parts = vec![];
+        let mut result = Ok(());
+        for i in 0..count {
+            let offset = i as u64 * part_size;
+            let range = offset..min(offset + part_size, size);
+            match self.upload_document(folder, topic, &part_name(name, i), source, range, &part_caption(i, count)) {
+                Ok(message) => parts.push(message),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        let manifest = Manifest { size, part_size, parts: parts.iter().map(Message::id).collect() };
+        let result = result.and_then(|()| {
+            if meta.get("name").is_none() {
+                meta.set("name", Some(name));
+            }
+            manifest.store(&mut meta);
+            self.send_text(folder, topic, &meta.to_caption())
+        });
+        match result {
+            Ok(message) => Ok((message, parts)),
+            Err(e) => {
+                if !manifest.parts.is_empty()
+                    && let Err(e) = self.delete_messages(folder, &manifest.parts, true)
+                {
+                    log::warn!("failed to delete the parts of {folder}/{name} after a failed upload: {e}");
+                }
+                Err(e)
+            }
+        }
+    }
+
+    // Send a text message to the chat behind `folder`, in the forum topic `topic` if given
+    fn send_text(&self, folder: &str, topic: Option<i32>, text: &str) -> Result<Message> {
+        let chat = match self.chats.read().unwrap().get(folder) {
+            Some(chat) => *chat,
+            None => return Err(format!("unknown chat folder {folder}").into()),
+        };
+        let input = InputMessage::text(text).reply_to(topics::reply_to(topic));
+        self.rt.block_on(deadline::within(async { Ok(self.my_client.send_message(chat, input).await?) }))
+    }
+
+    /* Download `size` bytes of `media` starting at `offset`.
+    Only the chunks overlapping the requested range are fetched, so reading
+    a few bytes from a large video does not download the whole file. The requests are made
+    here rather than through `iter_download`, whose offset is computed in 32 bits and so
+    cannot reach past 2 GiB.*/
+    fn download_range(&self, media: &Media, offset: u64, size: u32) -> Result<Vec<u8>> {
+        let chunk_size = CHUNK_SIZE as u64;
+        let end = offset + size as u64;
+        // Start of the chunk that holds the first requested byte
+        let buf_start = offset / chunk_size * chunk_size;
+        let Some(location) = Downloadable::Media(media.clone()).to_raw_input_location() else {
+            return Err("media has nothing to download".into());
+        };
+
+        self.rt.block_on(deadline::within(async {
+            let mut request = tl::functions::upload::GetFile {
+                precise: false,
+                cdn_supported: false,
+                location,
+                offset: buf_start as i64,
+                limit: CHUNK_SIZE,
+            };
+
+            // Collect chunks until the end of the requested range (or the file) is reached
+            let mut buf: Vec<u8> = vec![];
+            let mut dc = None;
+            while buf_start + (buf.len() as u64) < end {
+                let result = match dc.take() {
+                    None => self.my_client.invoke(&request).await,
+                    Some(dc) => self.my_client.invoke_in_dc(&request, dc).await,
+                };
+                let bytes = match result {
+                    Ok(tl::enums::upload::File::File(file)) => file.bytes,
+                    Ok(tl::enums::upload::File::CdnRedirect(_)) => return Err("Telegram redirected the download to a CDN".into()),
+                    // The file lives in another data center, which tells where
+                    Err(InvocationError::Rpc(rpc)) if rpc.code == FILE_MIGRATE_ERROR && rpc.value.is_some() => {
+                        dc = rpc.value.map(|dc| dc as i32);
+                        continue;
                     }
+                    Err(e) => return Err(e.into()),
+                };
+                let short = bytes.len() < CHUNK_SIZE as usize;
+                buf.extend_from_slice(&bytes);
+                if short {
+                    break;
+                }
+                request.offset += CHUNK_SIZE as i64;
+            }
+
+            // Cut the requested range out of the chunk-aligned buffer
+            let start = min((offset - buf_start) as usize, buf.len());
+            let stop = min((end - buf_start) as usize, buf.len());
+            Ok(buf[start..stop].to_vec())
+        }))
+    }
+
+    /* Delete messages from the chat behind `folder`, for all members of the chat or,
+    if `for_everyone` is false, only for this account. Channels and supergroups only
+    know deleting for everyone, so messages there are always gone for all members.*/
+    fn delete_messages(&self, folder: &str, message_ids: &[i32], for_everyone: bool) -> Result<()> {
+        let chat = match self.chats.read().unwrap().get(folder) {
+            Some(chat) => *chat,
+            None => return Err(format!("unknown chat folder {folder}").into()),
+        };
+
+        self.rt.block_on(deadline::within(async {
+            if for_everyone || chat.is_channel() {
+                self.my_client.delete_messages(chat, message_ids).await?;
+            } else {
+                let request = tl::functions::messages::DeleteMessages { revoke: false, id: message_ids.to_vec() };
+                self.my_client.invoke(&request).await?;
+            }
+            Ok(())
+        }))
+    }
+
+    // Change the metadata in the caption of a message, keeping the rest of the caption
+    fn edit_meta(&self, folder: &str, message_id: i32, edit: impl FnOnce(&mut Meta)) -> Result<()> {
+        let chat = match self.chats.read().unwrap().get(folder) {
+            Some(chat) => *chat,
+            None => return Err(format!("unknown chat folder {folder}").into()),
+        };
+
+        self.rt.block_on(deadline::within(async {
+            let message = self.my_client.get_messages_by_id(chat, &[message_id]).await?.pop().flatten();
+            let Some(message) = message else {
+                return Err(format!("message {message_id} not found in {folder}").into());
+            };
+            let mut meta = Meta::parse(message.text());
+            edit(&mut meta);
+            self.my_client.edit_message(chat, message_id, InputMessage::text(meta.to_caption())).await?;
+            Ok(())
+        }))
+    }
+
+    /* Send the media of a message from the chat behind `from` again to the chat behind `to`,
+    in the forum topic `topic` if given. The caption is copied along, with its metadata
+    changed by `edit`, which also gets to see the media. Returns the new message.*/
+    fn copy_message(&self, from: &str, message_id: i32, to: &str, topic: Option<i32>, edit: impl FnOnce(&mut Meta, &Media)) -> Result<Message> {
+        let chats = self.chats.read().unwrap();
+        let (Some(source), Some(destination)) = (chats.get(from).copied(), chats.get(to).copied()) else {
+            return Err(format!("unknown chat folder {from} or {to}").into());
+        };
+        drop(chats);
+
+        self.rt.block_on(deadline::within(async {
+            let message = self.my_client.get_messages_by_id(source, &[message_id]).await?.pop().flatten();
+            let Some(media) = message.as_ref().and_then(|m| m.media()) else {
+                return Err(format!("message {message_id} in {from} has no media").into());
+            };
+            let mut meta = Meta::parse(message.as_ref().unwrap().text());
+            edit(&mut meta, &media);
+            let input = InputMessage::text(meta.to_caption()).copy_media(&media).reply_to(topics::reply_to(topic));
+            Ok(self.my_client.send_message(destination, input).await?)
+        }))
+    }
+
+    /* Create a new private chat named `title`: a channel, or a basic group with only this account in it.
+    Returns the new chat.*/
+    fn create_chat(&self, title: &str, kind: ChatKind) -> Result<Chat> {
+        self.rt.block_on(deadline::within(async {
+            let updates = match kind {
+                ChatKind::Channel => {
+                    let request = tl::functions::channels::CreateChannel {
+                        broadcast: true,
+                        megagroup: false,
+                        for_import: false,
+                        forum: false,
+                        title: title.to_string(),
+                        about: String::new(),
+                        geo_point: None,
+                        address: None,
+                        ttl_period: None,
+                    };
+                    self.my_client.invoke(&request).await?
+                }
+                ChatKind::Group => {
+                    let request = tl::functions::messages::CreateChat { users: vec![], title: title.to_string(), ttl_period: None };
+                    let tl::enums::messages::InvitedUsers::Users(invited) = self.my_client.invoke(&request).await?;
+                    invited.updates
                 }
-                println!("Signed in!");
+            };
+
+            // The new chat comes back among the chats mentioned by the updates
+            let chats = match updates {
+                tl::enums::Updates::Updates(updates) => updates.chats,
+                tl::enums::Updates::Combined(updates) => updates.chats,
+                _ => vec![],
+            };
+            match chats.into_iter().next() {
+                Some(chat) => Ok(Chat::from_raw(chat)),
+                None => Err("Telegram did not return the new chat".into()),
+            }
+        }))
+    }
+
+    /* Create the topic `title` in the forum behind `folder`.
+    Returns the id of the new topic, which is the id of the message announcing it.*/
+    fn create_topic(&self, folder: &str, title: &str) -> Result<i32> {
+        let chat = match self.chats.read().unwrap().get(folder) {
+            Some(chat) => *chat,
+            None => return Err(format!("unknown chat folder {folder}").into()),
+        };
+        let Some(channel) = chat.try_to_input_channel() else {
+            return Err(format!("{folder} is not a forum").into());
+        };
+
+        self.rt.block_on(deadline::within(async {
+            // Telegram only uses the random id to tell retries of the request apart
+            let random_id = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_nanos() as i64;
+            let request = tl::functions::channels::CreateForumTopic {
+                channel,
+                title: title.to_string(),
+                icon_color: None,
+                icon_emoji_id: None,
+                random_id,
+                send_as: None,
+            };
+            let updates = match self.my_client.invoke(&request).await? {
+                tl::enums::Updates::Updates(updates) => updates.updates,
+                tl::enums::Updates::Combined(updates) => updates.updates,
+                _ => vec![],
+            };
+
+            // The new topic comes back as the service message announcing it
+            let created = updates.into_iter().find_map(|update| match update {
+                tl::enums::Update::NewChannelMessage(update) => match update.message {
+                    tl::enums::Message::Service(m) if matches!(m.action, tl::enums::MessageAction::TopicCreate(_)) => Some(m.id),
+                    _ => None,
+                },
+                _ => None,
+            });
+            created.ok_or_else(|| "Telegram did not return the new topic".into())
+        }))
+    }
+
+    /* Start keeping the cache in sync with Telegram in the background:
+    one task follows the update stream, the other scans all chats once and then
+    reconciles them every `--refresh-interval`, see the `sync` module.*/
+    fn spawn_cache_updater(&self, options: &Options, crypt: Option<Arc<Crypt>>) {
+        let updater = Arc::new(Updater {
+            client: self.my_client.clone(),
+            cache: Arc::clone(&self.cache),
+            chats: Arc::clone(&self.chats),
+            moderated: Arc::clone(&self.moderated),
+            links: Arc::clone(&self.links),
+            inodes: Arc::clone(&self.inodes),
+            names: Arc::clone(&self.names),
+            invalidated: Arc::clone(&self.invalidated),
+            notifier: Arc::clone(&self.notifier),
+            owner: options.owner,
+            crypt,
+            chat_filter: options.chat_filter.clone(),
+            naming: options.naming.clone(),
+            bot: bot::is_bot(&self.my_client),
+            root: self.root,
+            by_id: options.by_id,
+            last_seen: Mutex::new(HashMap::new()),
+            dialog_states: Mutex::new(HashMap::new()),
+            chat_folders: RwLock::new(vec![]),
+        });
+        self.rt.spawn(Arc::clone(&updater).run_reconciliation(options.refresh_interval));
+        self.rt.spawn(updater.run_updates());
+    }
+}
+struct TelegramFS
+{
+    client: TelegramClient,
+    // Recently read blocks, bounded by the memory budget
+    mem_cache: Arc<Mutex<MemoryCache>>,
+    // Downloaded file content, kept on disk across reads and remounts
+    block_cache: BlockCache,
+    // Where the content of files being written is kept, see the `staging` module
+    staging_dir: PathBuf,
+    // Owner reported for every file and folder
+    owner: Owner,
+    // How long the kernel may cache attributes and entries
+    ttl: Duration,
+    // Whom messages are deleted for when their file is removed
+    delete_for: DeleteFor,
+    // Whether the original message stays in its chat when its file is moved to another chat
+    keep_moved_originals: bool,
+    // What `mkdir` at the root creates
+    new_chat_kind: ChatKind,
+    // Whether the root has a directory of links to the chat folders by chat id
+    by_id: bool,
+    // Whether chat folders have directories listing their files by media type, and how names in them are made unique
+    media_dirs: bool,
+    naming: NamingRules,
+    // The chat and media type of every view inode handed to the kernel, and the files of the views listed so far
+    views: Mutex<HashMap<u64, (i64, MediaKind)>>,
+    view_cache: ViewCache,
+    // Files larger than this are uploaded in parts
+    part_size: u64,
+    // Keys for encrypting uploads and decrypting reads, if encryption is enabled
+    crypt: Option<Arc<Crypt>>,
+    // Files created through the mount, keyed by their file handle
+    uploads: HashMap<u64, PendingUpload>,
+    next_fh: u64,
+}
+
+impl TelegramFS {
+    // Initialize TelegramFS for `account` by creating a TelegramClient and starting the cache updater task
+    fn init(options: &Options, account: &Account) -> std::result::Result<Self, String> {
+        let client = TelegramClient::init(account)?;
+        let crypt = match options.secret.clone() {
+            Some(secret) => Some(Arc::new(Crypt::new(secret).map_err(|e| format!("failed to set up encryption: {e}"))?)),
+            None => None,
+        };
+
+        // Every account keeps its blocks apart, so that they do not evict each other's
+        let cache_dir = match &account.name {
+            Some(name) => options.cache_dir.join(name),
+            None => options.cache_dir.clone(),
+        };
+        let block_cache = BlockCache::open(&cache_dir, options.cache_size).map_err(|e| format!("failed to open block cache {}: {e}", cache_dir.display()))?;
+        let staging_dir = cache_dir.join("staging");
+        fs::create_dir_all(&staging_dir).map_err(|e| format!("failed to create staging directory {}: {e}", staging_dir.display()))?;
+        client.spawn_cache_updater(options, crypt.clone()); // start cache update loop in background
+        let mem_cache = Arc::new(Mutex::new(MemoryCache::new(options.mem_cache_size, options.mem_cache_policy)));
+        Self::spawn_stats_reporter(&client, Arc::clone(&mem_cache), options.stats_interval);
+
+        Ok(Self {
+            client,
+            mem_cache,
+            block_cache,
+            staging_dir,
+            owner: options.owner,
+            ttl: options.ttl,
+            delete_for: options.delete_for,
+            keep_moved_originals: options.keep_moved_originals,
+            new_chat_kind: options.new_chat_kind,
+            by_id: options.by_id,
+            media_dirs: options.media_dirs,
+            naming: options.naming.clone(),
+            views: Mutex::new(HashMap::new()),
+            view_cache: ViewCache::default(),
+            part_size: options.part_size,
+            crypt,
+            uploads: HashMap::new(),
+            next_fh: 1,
+        })
+    }
+
+    /* Find the directory with inode `ino` inside a chat folder: returns the folder (chat)
+    name and the path of the directory, which is empty for the chat folder itself.*/
+    fn resolve_dir(&self, ino: u64) -> Option<(String, String)> {
+        let cache = self.client.cache.read().unwrap();
+        cache
+            .iter()
+            .find_map(|(name, folder)| folder.dir_path(ino).map(|path| (name.clone(), path.to_string())))
+    }
+
+    // The chat of the chat folder `folder`
+    fn folder_chat(&self, folder: &str) -> Option<i64> {
+        self.client.cache.read().unwrap().get(folder).map(|f| f.chat_id)
+    }
+
+    // The name of the chat folder of the chat `chat_id`, which changes when the chat is renamed
+    fn folder_name(&self, chat_id: i64) -> Option<String> {
+        let cache = self.client.cache.read().unwrap();
+        cache.iter().find(|(_, f)| f.chat_id == chat_id).map(|(name, _)| name.clone())
+    }
+
+    // A file in the chat folder `folder`, looked up by its directory and name
+    fn find_file(&self, folder: &str, dir: &str, name: &str) -> Option<CachedFile> {
+        let cache = self.client.cache.read().unwrap();
+        cache.get(folder)?.files.iter().find(|f| f.dir == dir && f.name == name).cloned()
+    }
+
+    /* Where files in the directory at `dir` of `folder` are posted: the forum topic to post
+    them in, if any, and the directory to store in their captions, relative to the topic's.*/
+    fn placement(&self, folder: &str, dir: &str) -> (Option<i32>, String) {
+        let cache = self.client.cache.read().unwrap();
+        match cache.get(folder).and_then(|f| f.topic(dir)) {
+            Some((topic, dir)) => (Some(topic), dir.to_string()),
+            None => (None, dir.to_string()),
+        }
+    }
+
+    // Whether `folder` is the chat folder of a forum, which only holds topic directories
+    fn is_forum(&self, folder: &str) -> bool {
+        self.client.cache.read().unwrap().get(folder).is_some_and(|f| f.is_forum())
+    }
+
+    // A file still being written into `folder`, looked up by its directory and name
+    fn pending_fh(&self, folder: &str, dir: &str, name: &str) -> Option<u64> {
+        self.uploads
+            .iter()
+            .find(|(_, u)| u.message_id.is_none() && u.folder == folder && u.dir == dir && u.name == name)
+            .map(|(&fh, _)| fh)
+    }
+
+    // Log the memory cache counters periodically, whenever they changed
+    fn spawn_stats_reporter(client: &TelegramClient, mem_cache: Arc<Mutex<MemoryCache>>, interval: Duration) {
+        client.rt.spawn(async move {
+            let mut last = None;
+            loop {
+                tokio::time::sleep(interval).await;
+                let (stats, used) = {
+                    let mem_cache = mem_cache.lock().unwrap();
+                    (mem_cache.stats(), mem_cache.used())
+                };
+                if last != Some(stats) {
+                    log::info!("memory cache: {used} bytes used, {stats}");
+                    last = Some(stats);
+                }
+            }
+        });
+    }
+
+    /* Read `size` bytes at `offset` of a file whose content lives in Telegram.
+    The range is served block by block: each block is looked up in memory first,
+    then in the block cache on disk, and only downloaded if neither has it.
+    Files split into parts fail as a whole if a part the range needs is missing.*/
+    fn read_remote(&mut self, file: &CachedFile, offset: u64, size: u64) -> Result<Vec<u8>> {
+        self.drop_invalidated();
+        let block_size = CHUNK_SIZE as u64;
+        let end = offset + size;
+        let stored_size = file.sealed_size.unwrap_or(file.attr.size);
+
+        // Where each block comes from: the media itself, or the part holding it
+        let source: Box<dyn Fn(u64) -> (Arc<Media>, u64)> = match &file.content {
+            FileContent::Remote(media) => {
+                let media = Arc::clone(media);
+                Box::new(move |offset| (Arc::clone(&media), offset))
+            }
+            FileContent::Parts(manifest) => {
+                let (first, _) = manifest.locate(offset);
+                let (last, _) = manifest.locate(end - 1);
+                // Blocks never span two parts, as the part size is a multiple of the block size
+                let parts: Vec<Arc<Media>> = {
+                    let cache = self.client.cache.read().unwrap();
+                    let folder = cache.values().find(|f| f.chat_id == file.chat_id);
+                    (first..=last)
+                        .map(|i| {
+                            let id = manifest.parts[i];
+                            let media = folder.and_then(|f| f.parts.get(&id)).map(Arc::clone);
+                            media.ok_or_else(|| format!("part {} of {} (message {id}) is missing", i + 1, file.name))
+                        })
+                        .collect::<std::result::Result<_, _>>()?
+                };
+                let manifest = Arc::clone(manifest);
+                Box::new(move |offset| {
+                    let (index, offset) = manifest.locate(offset);
+                    (Arc::clone(&parts[index - first]), offset)
+                })
+            }
+            FileContent::Inline(_) => return Err("file content is not remote".into()),
+        };
+
+        let mut data = Vec::with_capacity(size as usize);
+        let mut block_offset = offset / block_size * block_size;
+        while block_offset < end {
+            let block_len = min(block_size, stored_size - block_offset);
+            let key = BlockKey {
+                chat_id: file.chat_id,
+                message_id: file.message_id,
+                offset: block_offset,
+            };
+
+            let cached = self.mem_cache.lock().unwrap().get(key);
+            let block = match cached {
+                Some(block) => block,
+                None => {
+                    let block = match self.block_cache.get(key, block_len as usize) {
+                        Some(block) => Arc::new(block),
+                        None => {
+                            let (media, media_offset) = source(block_offset);
+                            let block = self.client.download_range(&media, media_offset, block_len as u32)?;
+                            // Only complete blocks are cached, a short one is served but fetched again next time
+                            if block.len() as u64 == block_len {
+                                self.block_cache.put(key, &block);
+                            }
+                            Arc::new(block)
+                        }
+                    };
+                    if block.len() as u64 == block_len {
+                        self.mem_cache.lock().unwrap().put(key, Arc::clone(&block));
+                    }
+                    block
+                }
+            };
+
+            // Copy the part of the block that overlaps the requested range
+            let from = min(offset.saturating_sub(block_offset) as usize, block.len());
+            let to = min((end - block_offset) as usize, block.len());
+            data.extend_from_slice(&block[from..to]);
+            block_offset += block_size;
+        }
+        Ok(data)
+    }
+
+    /* Read `size` bytes of plaintext at `offset` of an encrypted file: only the encrypted
+    chunks holding the range are fetched, through `read_remote`, and authenticated. Reading
+    nothing at the end of the file still checks its last chunk.*/
+    fn read_sealed(&mut self, file: &CachedFile, offset: u64, size: u64) -> Result<Vec<u8>> {
+        let (Some(crypt), Some(stored_size)) = (self.crypt.clone(), file.sealed_size) else {
+            return Err("file is not encrypted".into());
+        };
+        if crypt::plain_size(stored_size).is_none() {
+            return Err(crypt::Error.into());
+        }
+        let header = self.read_remote(file, 0, crypt::HEADER_LEN)?;
+        let (first, start, len) = crypt::sealed_range(offset, size, stored_size);
+        let sealed = self.read_remote(file, start, len)?;
+        let plain = crypt.open(&header, first, &sealed, stored_size)?;
+
+        let skip = min((offset - crypt::chunk_offset(first)) as usize, plain.len());
+        let end = min(skip + size as usize, plain.len());
+        Ok(plain[skip..end].to_vec())
+    }
+
+    /* Store the directory `dir` and the name `name` of a file in caption metadata, encrypted if
+    encryption is enabled. An empty directory and a missing name are left out.*/
+    fn set_path(&self, meta: &mut Meta, dir: &str, name: Option<&str>) -> io::Result<()> {
+        let dir = if dir.is_empty() { None } else { Some(self.conceal(dir)?) };
+        let name = name.map(|name| self.conceal(name)).transpose()?;
+        meta.set("dir", dir.as_deref());
+        meta.set("name", name.as_deref());
+        Ok(())
+    }
+
+    // Encrypt a name or path for a caption if encryption is enabled
+    fn conceal(&self, value: &str) -> io::Result<String> {
+        match &self.crypt {
+            Some(crypt) => crypt.seal_name(value),
+            None => Ok(value.to_string()),
+        }
+    }
+
+    // Forget the cached blocks of messages the cache updater found changed or deleted
+    fn drop_invalidated(&mut self) {
+        let invalidated = std::mem::take(&mut *self.client.invalidated.lock().unwrap());
+        for (chat_id, message_id) in invalidated {
+            self.mem_cache.lock().unwrap().remove_message(chat_id, message_id);
+            self.block_cache.remove_message(chat_id, message_id);
+        }
+    }
+
+    // Attributes of the chat folder with inode `ino`
+    fn folder_attr(&self, ino: u64, folder: &CachedFolder) -> FileAttr {
+        dir_attr(ino, folder.mtime, self.owner)
+    }
+
+    // Attributes of the root directory, which changes whenever any chat does
+    fn root_attr(&self) -> FileAttr {
+        let cache = self.client.cache.read().unwrap();
+        let mtime = cache.values().map(|f| f.mtime).max().unwrap_or(SystemTime::UNIX_EPOCH);
+        dir_attr(self.client.root, mtime, self.owner)
+    }
+
+    // Inode of the `by-id` directory, if the root has one
+    fn by_id_ino(&self) -> Option<u64> {
+        self.by_id.then(|| self.client.inodes.lock().unwrap().get_or_assign(InodeKey::ById)).flatten()
+    }
+
+    // Inode of the link to the folder of chat `chat_id` in the `by-id` directory
+    fn link_ino(&self, chat_id: i64) -> Option<u64> {
+        self.client.inodes.lock().unwrap().get_or_assign(InodeKey::ChatLink(chat_id))
+    }
+
+    // The chat folder the link with inode `ino` in the `by-id` directory points at, with its name
+    fn link_folder<'a>(&self, cache: &'a HashMap<String, CachedFolder>, ino: u64) -> Option<(&'a String, &'a CachedFolder)> {
+        if !self.by_id {
+            return None;
+        }
+        let inodes = self.client.inodes.lock().unwrap();
+        cache.iter().find(|(_, folder)| inodes.get(&InodeKey::ChatLink(folder.chat_id)) == Some(ino))
+    }
+
+    // Inode of the directory listing the files of `kind` in the folder of chat `chat_id`
+    fn view_ino(&self, chat_id: i64, kind: MediaKind) -> Option<u64> {
+        let ino = self.client.inodes.lock().unwrap().get_or_assign(InodeKey::MediaView(chat_id, kind))?;
+        self.views.lock().unwrap().insert(ino, (chat_id, kind));
+        Some(ino)
+    }
+
+    // The chat folder and media type of the view with inode `ino`
+    fn find_view<'a>(&self, cache: &'a HashMap<String, CachedFolder>, ino: u64) -> Option<(&'a CachedFolder, MediaKind)> {
+        if !self.media_dirs {
+            return None;
+        }
+        // The kernel only learns of a view's inode from `view_ino`
+        let (chat_id, kind) = *self.views.lock().unwrap().get(&ino)?;
+        cache.values().find(|folder| folder.chat_id == chat_id).map(|folder| (folder, kind))
+    }
+
+    // The views of `folder` that no real directory or file hides
+    fn visible_views(&self, folder: &CachedFolder) -> Vec<MediaKind> {
+        if !self.media_dirs {
+            return vec![];
+        }
+        let taken = |name: &str| folder.dirs.iter().any(|d| d.path == name) || folder.files.iter().any(|f| f.dir.is_empty() && f.name == name);
+        MediaKind::ALL.into_iter().filter(|kind| !taken(kind.dir_name())).collect()
+    }
+
+    // The errno for creating or removing something in `parent`, which is not a directory of a chat folder
+    fn not_a_chat_dir(&self, parent: u64) -> i32 {
+        let cache = self.client.cache.read().unwrap();
+        if parent == self.client.root || self.by_id_ino() == Some(parent) || self.find_view(&cache, parent).is_some() {
+            EPERM
+        } else {
+            ENOENT
+        }
+    }
+
+    // Attributes of the link with inode `ino` to the chat folder `name`
+    fn link_attr(&self, ino: u64, name: &str, folder: &CachedFolder) -> FileAttr {
+        FileAttr {
+            size: link_target(name).len() as u64,
+            kind: FileType::Symlink,
+            perm: 0o777,
+            nlink: 1,
+            ..dir_attr(ino, folder.mtime, self.owner)
+        }
+    }
+
+    // Attributes of a file that is still being written
+    fn pending_attr(&self, upload: &PendingUpload) -> FileAttr {
+        file_attr(upload.ino, upload.data.len(), (upload.mtime, upload.mtime), self.owner)
+    }
+
+    // A pending upload that is not in the cache yet, looked up by inode
+    fn pending_by_ino(&self, ino: u64) -> Option<&PendingUpload> {
+        self.uploads.values().find(|u| u.ino == ino && u.message_id.is_none())
+    }
+
+    /* Upload the staged content of the file behind `fh` if it changed since the last flush,
+    in parts if it is larger than `--part-size`. Content that is stored already is not uploaded
+    again: the file refers to the existing copy and shares its inode, see the `dedup` module.
+    On success the file is added to the cache of its folder; a file that was already uploaded
+    once is replaced, and its previous messages deleted.*/
+    fn flush_upload(&mut self, fh: u64) -> std::result::Result<(), i32> {
+        // The chat may have been renamed since the file was created
+        if let Some(upload) = self.uploads.get(&fh)
+            && let Some(folder) = self.folder_name(upload.chat_id)
+        {
+            self.uploads.get_mut(&fh).unwrap().folder = folder;
+        }
+        let upload = match self.uploads.get(&fh) {
+            Some(upload) if upload.dirty => upload,
+            Some(_) => return Ok(()),
+            None => return Err(EBADF),
+        };
+        let (topic, dir) = self.placement(&upload.folder, &upload.dir);
+        let digest = match upload.data.reader().and_then(dedup::digest) {
+            Ok(digest) => digest,
+            Err(e) => {
+                log::error!("failed to read back {}/{}: {e}", upload.folder, upload.name);
+                return Err(EIO);
+            }
+        };
+        let original = {
+            let cache = self.client.cache.read().unwrap();
+            let size = upload.data.len();
+            let found = dedup::find_original(&cache, &digest, size, self.crypt.is_some(), &upload.folder, upload.message_id);
+            found.map(|(folder, file)| (folder.to_string(), file.clone()))
+        };
+        let referred = original.and_then(|(from, original)| {
+            let meta = self.copy_meta(&original, &dir, &upload.name);
+            let sent = meta.map_err(Into::into).and_then(|meta| self.copy_stored(&from, &original, &upload.folder, topic, &meta, &upload.name));
+            match sent {
+                Ok((message, parts)) => Some((message, parts, original.sealed_size, Some(original.ino))),
+                Err(e) => {
+                    log::warn!("failed to refer to {from}/{} for {}/{}, uploading it again: {e}", original.name, upload.folder, upload.name);
+                    None
+                }
+            }
+        });
+        let (message, parts, sealed_size, shared_ino) = match referred {
+            Some(referred) => referred,
+            None => {
+                let (message, parts, sealed_size) = self.upload_content(upload, topic, &dir, &digest)?;
+                (message, parts, sealed_size, None)
+            }
+        };
+        let message_id = message.id();
+        if let Some(old_id) = upload.message_id {
+            // A replaced file that was split takes its parts along
+            let old_ids = {
+                let cache = self.client.cache.read().unwrap();
+                let folder = cache.get(&upload.folder);
+                let old = folder.and_then(|f| f.files.iter().find(|f| f.message_id == old_id));
+                old.map_or_else(|| vec![old_id], |old| message_ids(folder, old))
+            };
+            if let Err(e) = self.client.delete_messages(&upload.folder, &old_ids, true) {
+                log::warn!("failed to delete replaced message {old_id}: {e}");
+            }
+        }
+
+        // A file that was a link of others gets an inode of its own once its content differs
+        let linked = {
+            let cache = self.client.cache.read().unwrap();
+            let replaced = |folder: &str, f: &CachedFile| folder == upload.folder && Some(f.message_id) == upload.message_id;
+            cache.iter().any(|(name, folder)| folder.files.iter().any(|f| f.ino == upload.ino && !replaced(name, f)))
+        };
+        let ino = match shared_ino {
+            Some(ino) => ino,
+            None if linked => self.client.inodes.lock().unwrap().allocate().ok_or(ENOSPC)?,
+            None => upload.ino,
+        };
+
+        // Make the uploaded file visible in its folder right away
+        let content = match (message.media(), chunks::manifest(&message)) {
+            (Some(media), _) => FileContent::Remote(Arc::new(media)),
+            (None, Some(manifest)) => FileContent::Parts(Arc::new(manifest)),
+            (None, None) => FileContent::Inline(Arc::new(upload.data.read_at(0, upload.data.len()).map_err(|_| EIO)?)),
+        };
+        let chat_id = message.chat().id();
+        let file = CachedFile {
+            ino,
+            name: upload.name.clone(),
+            chat_id,
+            message_id,
+            dir: upload.dir.clone(),
+            outgoing: true,
+            content,
+            sealed_size,
+            digest: Some(digest.clone()),
+            attr: file_attr(ino, upload.data.len(), message_times(&message), self.owner),
+        };
+        let mut cache = self.client.cache.write().unwrap();
+        let mut parent = None;
+        if let Some(folder) = cache.get_mut(&upload.folder) {
+            // The update stream may already have reported the new message
+            let links = &self.client.links;
+            links.retain(&mut folder.files, |f| f.message_id != message_id && Some(f.message_id) != upload.message_id);
+            folder.mtime = folder.mtime.max(file.attr.mtime);
+            links.add(&file);
+            folder.files.push(file);
+            folder.touch();
+            for part in &parts {
+                if let Some(media) = part.media() {
+                    folder.parts.insert(part.id(), Arc::new(media));
+                }
+            }
+            parent = folder.dir_ino(&upload.dir);
+        }
+        drop(cache);
+        self.mem_cache.lock().unwrap().remove_message(chat_id, message_id);
+        self.block_cache.remove_message(chat_id, message_id);
+
+        // The file keeps the inode it was created with, unless it became a link, now tied to its message and content
+        let mut inodes = self.client.inodes.lock().unwrap();
+        inodes.bind(InodeKey::Message(chat_id, message_id), ino);
+        inodes.bind(InodeKey::Content(digest), ino);
+        if let Err(e) = inodes.save() {
+            log::error!("failed to save inode table: {e}");
+        }
+        drop(inodes);
+
+        let upload = self.uploads.get_mut(&fh).unwrap();
+        upload.message_id = Some(message_id);
+        upload.dirty = false;
+        if upload.ino != ino {
+            upload.ino = ino;
+            let name = upload.name.clone();
+            if let Some(parent) = parent {
+                self.forget_entry(parent, &name);
+            }
+        }
+        Ok(())
+    }
+
+    /* Upload the staged content of `upload` as a new file in the directory at `dir`, relative to
+    the forum topic `topic` if given, with `digest` in its caption. Encrypted files keep their
+    name in the caption as well, their documents only get a neutral one.
+    Returns the message standing for the file, the messages carrying its parts and, for
+    encrypted files, the size of the content as stored.*/
+    fn upload_content(&self, upload: &PendingUpload, topic: Option<i32>, dir: &str, digest: &str) -> std::result::Result<(Message, Vec<Message>, Option<u64>), i32> {
+        // The caption tells every mount which directory the file belongs in
+        let mut meta = Meta::default();
+        let sealed = match &self.crypt {
+            Some(crypt) => {
+                meta.set("enc", Some("1"));
+                self.set_path(&mut meta, dir, Some(&upload.name)).and_then(|()| self.seal(crypt, &upload.data)).map(Some)
+            }
+            None => self.set_path(&mut meta, dir, None).map(|()| None),
+        };
+        let sealed = match sealed.and_then(|sealed| Ok((sealed, self.conceal(digest)?))) {
+            Ok((sealed, digest)) => {
+                meta.set("sha256", Some(&digest));
+                sealed
+            }
+            Err(e) => {
+                log::error!("failed to encrypt {}/{}: {e}", upload.folder, upload.name);
+                return Err(EIO);
+            }
+        };
+        let (document_name, source) = match &sealed {
+            Some(sealed) => (crypt::DOCUMENT_NAME, sealed),
+            None => (upload.name.as_str(), &upload.data),
+        };
+        match self.client.upload_file(&upload.folder, topic, document_name, source, meta, self.part_size) {
+            Ok((message, parts)) => Ok((message, parts, sealed.map(|sealed| sealed.len()))),
+            Err(e) => {
+                log::error!("failed to upload {}/{}: {e}", upload.folder, upload.name);
+                Err(EIO)
+            }
+        }
+    }
+
+    // Encrypt staged content into a staged file of its own
+    fn seal(&self, crypt: &Crypt, content: &Staged) -> io::Result<Staged> {
+        let mut sealed = Staged::new(&self.staging_dir)?;
+        crypt.seal(content.reader()?, content.len(), &mut sealed)?;
+        Ok(sealed)
+    }
+
+    // Make the kernel look up `name` in the directory `parent` again, without waiting for it here
+    fn forget_entry(&self, parent: u64, name: &str) {
+        let notifier = Arc::clone(&self.client.notifier);
+        let name = name.to_string();
+        self.client.rt.spawn(async move {
+            if let Some(notifier) = notifier.get()
+                && let Err(e) = notifier.inval_entry(parent, OsStr::new(&name))
+            {
+                log::warn!("failed to invalidate kernel cache: {e}");
+            }
+        });
+    }
+
+    /* Delete the message carrying `file`, and the parts of a split file, from the chat behind `folder`
+    and drop the file from the cache.
+    Fails with EACCES if this account cannot delete the message for the members it would be
+    deleted for, and with the error Telegram refused the deletion with otherwise.*/
+    fn delete_file(&mut self, folder: &str, file: &CachedFile) -> std::result::Result<(), i32> {
+        let for_everyone = self.may_delete(folder, file)?;
+        let ids = message_ids(self.client.cache.read().unwrap().get(folder), file);
+        if let Err(e) = self.client.delete_messages(folder, &ids, for_everyone) {
+            log::error!("failed to delete {folder}/{}: {e}", file.name);
+            return Err(errno_for(e.as_ref()));
+        }
+
+        let mut cache = self.client.cache.write().unwrap();
+        if let Some(folder) = cache.get_mut(folder) {
+            self.client.links.retain(&mut folder.files, |f| f.message_id != file.message_id);
+            folder.touch();
+            rebuild_dirs(folder, &mut self.client.inodes.lock().unwrap());
+        }
+        drop(cache);
+        self.mem_cache.lock().unwrap().remove_message(file.chat_id, file.message_id);
+        self.block_cache.remove_message(file.chat_id, file.message_id);
+        Ok(())
+    }
+
+    /* Whether `delete_file` may delete `file` from the chat behind `folder`: fails with EACCES if not,
+    and tells whether the message would be deleted for everyone otherwise.*/
+    fn may_delete(&self, folder: &str, file: &CachedFile) -> std::result::Result<bool, i32> {
+        let chat = self.client.chats.read().unwrap().get(folder).copied().ok_or(ENOENT)?;
+        let for_everyone = self.delete_for == DeleteFor::Everyone || chat.is_channel();
+        // Private chats let both sides delete any message; elsewhere only your own, unless you moderate the chat
+        let allowed = file.outgoing || chat.is_user() || self.client.moderated.read().unwrap().contains(&file.chat_id);
+        if for_everyone && !allowed {
+            return Err(EACCES);
+        }
+        Ok(for_everyone)
+    }
+
+    /* Give `file` in the chat behind `folder` the name `name` in the directory at `dir`,
+    which must be in the same forum topic as the file.
+    The directory and name go into the caption of the message when this account sent it;
+    otherwise, or if Telegram refuses the edit, they are only remembered on this machine.*/
+    fn rename_file(&mut self, folder: &str, file: &CachedFile, dir: &str, name: &str) {
+        let (_, stored_dir) = self.placement(folder, dir);
+        let mut path = Meta::default();
+        let concealed = self.set_path(&mut path, &stored_dir, Some(name));
+        let edit = |meta: &mut Meta| {
+            meta.set("name", path.get("name"));
+            meta.set("dir", path.get("dir"));
+        };
+        let edited = file.outgoing
+            && concealed.is_ok()
+            && match self.client.edit_meta(folder, file.message_id, edit) {
+                Ok(()) => true,
+                Err(e) => {
+                    log::warn!("failed to store the name of {folder}/{} in Telegram, renaming locally: {e}", file.name);
+                    false
+                }
+            };
+
+        let mut names = self.client.names.lock().unwrap();
+        names.set(file.chat_id, file.message_id, (!edited).then(|| join(&stored_dir, name)).as_deref());
+        if let Err(e) = names.save() {
+            log::error!("failed to save local file names: {e}");
+        }
+        drop(names);
+
+        let mut cache = self.client.cache.write().unwrap();
+        if let Some(folder) = cache.get_mut(folder) {
+            if let Some(f) = folder.files.iter_mut().find(|f| f.message_id == file.message_id) {
+                f.dir = dir.to_string();
+                f.name = name.to_string();
+            }
+            folder.touch();
+            rebuild_dirs(folder, &mut self.client.inodes.lock().unwrap());
+        }
+    }
+
+    /* Move `file` from the chat behind `from` to the chat behind `to`, naming it `name` in the directory at `dir`.
+    Both may be the same forum, to move the file to another topic.
+    The file is sent to the destination and shows up there before the original message is
+    deleted, so it never disappears in between. The moved file keeps its inode.*/
+    fn move_file(&mut self, from: &str, file: &CachedFile, to: &str, dir: &str, name: &str) -> std::result::Result<(), i32> {
+        self.link_file(from, file, to, dir, name)?;
+        if let Some(folder) = self.client.cache.write().unwrap().get_mut(from) {
+            self.client.links.retain(&mut folder.files, |f| f.message_id != file.message_id);
+            folder.touch();
+            rebuild_dirs(folder, &mut self.client.inodes.lock().unwrap());
+        }
+
+        // Then get rid of the original, or keep it: as a link of the moved file if its content
+        // is known, as a separate file under a new inode otherwise
+        let deleted = !self.keep_moved_originals
+            && match self.delete_file(from, file) {
+                Ok(()) => true,
+                Err(e) => {
+                    log::warn!("keeping the original of {from}/{} after moving it: errno {e}", file.name);
+                    false
+                }
+            };
+        if !deleted {
+            let mut original = file.clone();
+            // Without a number of its own it stays a link of the moved file, see `InodeTable::allocate`
+            let mut inodes = self.client.inodes.lock().unwrap();
+            if file.digest.is_none()
+                && let Some(ino) = inodes.allocate()
+            {
+                inodes.bind(InodeKey::Message(file.chat_id, file.message_id), ino);
+                original.ino = ino;
+                original.attr.ino = ino;
+            }
+            drop(inodes);
+            if let Some(folder) = self.client.cache.write().unwrap().get_mut(from) {
+                self.client.links.add(&original);
+                folder.files.push(original);
+                folder.touch();
+                rebuild_dirs(folder, &mut self.client.inodes.lock().unwrap());
+            }
+        }
+
+        if let Err(e) = self.client.inodes.lock().unwrap().save() {
+            log::error!("failed to save inode table: {e}");
+        }
+        Ok(())
+    }
+
+    /* Send `file` from the chat behind `from` to the chat behind `to` as another link of it, named
+    `name` in the directory at `dir`. The copy shows up in the cache right away, under the inode
+    of `file`. Returns the attributes of the copy.*/
+    fn link_file(&mut self, from: &str, file: &CachedFile, to: &str, dir: &str, name: &str) -> std::result::Result<FileAttr, i32> {
+        let (topic, stored_dir) = self.placement(to, dir);
+        let (message, parts) = match self.send_copy(from, file, to, topic, &stored_dir, name) {
+            Ok(sent) => sent,
+            Err(e) => {
+                log::error!("failed to copy {from}/{} to {to}: {e}", file.name);
+                return Err(errno_for(e.as_ref()));
+            }
+        };
+
+        let chat_id = message.chat().id();
+        let copy = CachedFile {
+            ino: file.ino,
+            name: name.to_string(),
+            chat_id,
+            message_id: message.id(),
+            dir: dir.to_string(),
+            outgoing: true,
+            content: match (message.media(), chunks::manifest(&message)) {
+                (Some(media), _) => FileContent::Remote(Arc::new(media)),
+                (None, Some(manifest)) => FileContent::Parts(Arc::new(manifest)),
+                (None, None) => file.content.clone(),
+            },
+            sealed_size: file.sealed_size,
+            digest: file.digest.clone(),
+            attr: file_attr(file.ino, file.attr.size, message_times(&message), self.owner),
+        };
+        let mut cache = self.client.cache.write().unwrap();
+        if let Some(folder) = cache.get_mut(to) {
+            // The update stream may already have reported the new message
+            self.client.links.retain(&mut folder.files, |f| f.message_id != copy.message_id);
+            folder.mtime = folder.mtime.max(copy.attr.mtime);
+            self.client.links.add(&copy);
+            folder.files.push(copy.clone());
+            folder.touch();
+            for part in &parts {
+                if let Some(media) = part.media() {
+                    folder.parts.insert(part.id(), Arc::new(media));
+                }
+            }
+            rebuild_dirs(folder, &mut self.client.inodes.lock().unwrap());
+        }
+        let attr = self.client.links.attr(&copy);
+        drop(cache);
+
+        let mut inodes = self.client.inodes.lock().unwrap();
+        inodes.bind(InodeKey::Message(chat_id, message.id()), file.ino);
+        if let Err(e) = inodes.save() {
+            log::error!("failed to save inode table: {e}");
+        }
+        Ok(attr)
+    }
+
+    /* Send `file` from the chat behind `from` again to the chat behind `to`, as a file named `name`
+    in the directory at `dir` (relative to the forum topic `topic`, if given), see `copy_stored`.
+    Chats with protected content refuse to share their media, so the content is downloaded into
+    a staged file and uploaded again from there.
+    Returns the message standing for the copy and the messages carrying its parts.*/
+    fn send_copy(&mut self, from: &str, file: &CachedFile, to: &str, topic: Option<i32>, dir: &str, name: &str) -> Result<(Message, Vec<Message>)> {
+        let meta = self.copy_meta(file, dir, name)?;
+        match self.copy_stored(from, file, to, topic, &meta, name) {
+            Err(e) if e.downcast_ref::<InvocationError>().is_some_and(|e| e.is("CHAT_FORWARDS_RESTRICTED")) => {
+                // Encrypted content is sent on as it is stored, staged block by block on its way
+                let mut staged = Staged::new(&self.staging_dir)?;
+                match &file.content {
+                    FileContent::Inline(data) => staged.write_at(0, data)?,
+                    FileContent::Remote(_) | FileContent::Parts(_) => {
+                        let size = file.sealed_size.unwrap_or(file.attr.size);
+                        let block_size = CHUNK_SIZE as u64;
+                        for offset in (0..size).step_by(block_size as usize) {
+                            let block = self.read_remote(file, offset, min(block_size, size - offset))?;
+                            staged.write_at(offset, &block)?;
+                        }
+                    }
+                }
+                let document_name = if file.sealed_size.is_some() { crypt::DOCUMENT_NAME } else { name };
+                self.client.upload_file(to, topic, document_name, &staged, meta, self.part_size)
+            }
+            copied => copied,
+        }
+    }
+
+    /* Send `file` from the chat behind `from` to the chat behind `to` without uploading its content
+    again, with the caption metadata `meta` for the file `name`. The message is forwarded, and so
+    are the parts of a split file, unless it stays in the same chat: then a new manifest points at
+    the same parts.
+    Returns the message standing for the copy and the messages carrying its parts.*/
+    fn copy_stored(&self, from: &str, file: &CachedFile, to: &str, topic: Option<i32>, meta: &Meta, name: &str) -> Result<(Message, Vec<Message>)> {
+        match &file.content {
+            FileContent::Parts(manifest) if from == to => {
+                let mut meta = meta.clone();
+                manifest.store(&mut meta);
+                self.client.send_text(to, topic, &meta.to_caption()).map(|message| (message, vec![]))
+            }
+            FileContent::Parts(manifest) => self.copy_parts(from, manifest, to, topic, meta.clone(), name),
+            _ => {
+                // Documents carrying the name need none in the caption, unless names are encrypted
+                let encrypted = self.crypt.is_some();
+                let edit = |copied: &mut Meta, media: &Media| {
+                    let renamed = encrypted || document_name(media).as_deref() != Some(name);
+                    copied.set("name", meta.get("name").filter(|_| renamed));
+                    copied.set("dir", meta.get("dir"));
+                };
+                self.client.copy_message(from, file.message_id, to, topic, edit).map(|message| (message, vec![]))
+            }
+        }
+    }
+
+    // Caption metadata for a copy of `file` named `name` in the stored directory `dir`
+    fn copy_meta(&self, file: &CachedFile, dir: &str, name: &str) -> io::Result<Meta> {
+        let mut meta = Meta::default();
+        self.set_path(&mut meta, dir, Some(name))?;
+        meta.set("enc", file.sealed_size.map(|_| "1"));
+        let digest = file.digest.as_deref().map(|digest| self.conceal(digest)).transpose()?;
+        meta.set("sha256", digest.as_deref());
+        Ok(meta)
+    }
+
+    /* Send the parts `manifest` lists from the chat behind `from` again to the chat behind `to`,
+    then a manifest of the copies for the file `name`, with its caption metadata in `meta`.
+    The copied parts are deleted again if a later step fails.*/
+    fn copy_parts(&self, from: &str, manifest: &Manifest, to: &str, topic: Option<i32>, mut meta: Meta, name: &str) -> Result<(Message, Vec<Message>)> {
+        let mut parts = vec![];
+        let mut result = Ok(());
+        for &id in &manifest.parts {
+            match self.client.copy_message(from, id, to, topic, |_, _| {}) {
+                Ok(message) => parts.push(message),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        let copy = Manifest { parts: parts.iter().map(Message::id).collect(), ..manifest.clone() };
+        let result = result.and_then(|()| {
+            copy.store(&mut meta);
+            self.client.send_text(to, topic, &meta.to_caption())
+        });
+        match result {
+            Ok(message) => Ok((message, parts)),
+            Err(e) => {
+                if !copy.parts.is_empty()
+                    && let Err(e) = self.client.delete_messages(to, &copy.parts, true)
+                {
+                    log::warn!("failed to delete the copied parts of {from}/{name}: {e}");
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /* Create the directory `name` inside the directory with inode `parent` of a chat folder
+    by posting its marker message, or a new topic when `parent` is the chat folder of a forum.
+    Returns the attributes of the new directory.*/
+    fn make_dir(&mut self, parent: u64, name: &str) -> std::result::Result<FileAttr, i32> {
+        let (folder, dir) = self.resolve_dir(parent).ok_or(ENOENT)?;
+        let path = join(&dir, name);
+        let exists = {
+            let cache = self.client.cache.read().unwrap();
+            let folder = cache.get(&folder).ok_or(ENOENT)?;
+            folder.dirs.iter().any(|d| d.path == path) || folder.files.iter().any(|f| f.dir == dir && f.name == name)
+        };
+        if exists || self.pending_fh(&folder, &dir, name).is_some() {
+            return Err(EEXIST);
+        }
+
+        if dir.is_empty() && self.is_forum(&folder) {
+            return self.make_topic(&folder, name);
+        }
+
+        let (topic, stored_path) = self.placement(&folder, &path);
+        let stored_path = self.conceal(&stored_path).map_err(|_| EIO)?;
+        let message = match self.client.send_text(&folder, topic, &tree::marker_caption(&stored_path)) {
+            Ok(message) => message,
+            Err(e) => {
+                log::error!("failed to create directory {folder}/{path}: {e}");
+                return Err(errno_for(e.as_ref()));
+            }
+        };
+
+        let mut cache = self.client.cache.write().unwrap();
+        let cached = cache.get_mut(&folder).ok_or(ENOENT)?;
+        let chat_id = cached.chat_id;
+        let mut inodes = self.client.inodes.lock().unwrap();
+        let ino = inodes.get_or_assign(InodeKey::Dir(chat_id, path.clone())).ok_or(ENOSPC)?;
+        let mtime = SystemTime::from(message.date());
+        cached.dirs.retain(|d| d.path != path);
+        cached.dirs.push(CachedDir { ino, path, marker: Some(message.id()), topic: None, mtime });
+        cached.mtime = cached.mtime.max(mtime);
+        rebuild_dirs(cached, &mut inodes);
+        if let Err(e) = inodes.save() {
+            log::error!("failed to save inode table: {e}");
+        }
+        Ok(dir_attr(ino, mtime, self.owner))
+    }
+
+    /* Create the topic `name` in the forum behind `folder` and show it as an empty directory.
+    Returns the attributes of the new directory.*/
+    fn make_topic(&mut self, folder: &str, name: &str) -> std::result::Result<FileAttr, i32> {
+        // Telegram limits topic titles like chat titles
+        if name.chars().count() > MAX_TITLE_LEN {
+            return Err(ENAMETOOLONG);
+        }
+        let topic = match self.client.create_topic(folder, name) {
+            Ok(topic) => topic,
+            Err(e) => {
+                log::error!("failed to create topic {folder}/{name}: {e}");
+                return Err(errno_for(e.as_ref()));
             }
-            
-            // Return the connected and authorized client
-            client
-        });
+        };
 
-        // 3. Return the TelegramClient structure with client, runtime, and empty cache
-        Self {
-            my_client: client,
-            rt,
-            cache: Arc::new(RwLock::new(HashMap::new())),
+        let mut cache = self.client.cache.write().unwrap();
+        let cached = cache.get_mut(folder).ok_or(ENOENT)?;
+        let mut inodes = self.client.inodes.lock().unwrap();
+        let ino = inodes.get_or_assign(InodeKey::Dir(cached.chat_id, name.to_string())).ok_or(ENOSPC)?;
+        let mtime = SystemTime::now();
+        cached.dirs.push(CachedDir { ino, path: name.to_string(), marker: None, topic: Some(topic), mtime });
+        cached.mtime = cached.mtime.max(mtime);
+        if let Err(e) = inodes.save() {
+            log::error!("failed to save inode table: {e}");
         }
+        Ok(dir_attr(ino, mtime, self.owner))
     }
 
-    fn spawn_cache_updater(&self) {
-        // Clone the Telegram client and the shared cache so they can be moved into the async task
-        let my_client = self.my_client.clone();
-        let cache = Arc::clone(&self.cache);
-
-        // Spawn an asynchronous task on the runtime to update the cache continuously
-        self.rt.spawn(async move {
-            loop {
-                // Iterate over all Telegram dialogs (chats, channels, groups)
-                let mut dialogs = my_client.iter_dialogs();
-                
-                // Process each dialog one by one
-                while let Some(dialog) = dialogs.next().await.unwrap() {
-                    let name = dialog.chat().name();
-                    if !name.is_empty() {
-                        let mut files = vec![];
-
-                        // Calculate a base inode number for this folder (chat) to uniquely identify files
-                        let folder_ino = TelegramFS::folder_ino(&name);
-                        let mut ino_counter = folder_ino + 1;
-                        
-                        // Iterate over all messages in the dialog
-                        let mut messages = my_client.iter_messages(&dialog.chat);
-
-                        while let Some(msg) = messages.next().await.unwrap() {
-                            // Check if message contains media (file/photo/video/etc.)
-                            if let Some(media) = msg.media() {
-                                // Construct a filename using message ID and media file extension
-                                let file_name = format!("msg-{}{}", msg.id(), get_file_extension(&media));
-                                
-                                // Create a Downloadable object for the media
-                                let downloadable = Downloadable::Media(media.clone()); // <-- Poprawka
-                                // Buffer to accumulate downloaded bytes
-                                let mut buf: Vec<u8> = vec![];
-                                // Create a stream to download the media in chunks
-                                let mut stream = my_client.iter_download(&downloadable); // <-- Poprawka
-                                // Download chunks and append them to the buffer
-                                while let Some(chunk) = stream.next().await.unwrap() {
-                                    buf.extend_from_slice(&chunk);
-                                }
-                                // Wrap the buffer in an Arc for shared ownership
-                                let content = Arc::new(buf);
-                                // Create file attributes for this cached file (metadata)
-                                let attr = FileAttr {
-                                    ino: ino_counter,
-                                    size: content.len() as u64,
-                                    blocks: 1,
-                                    atime: UNIX_EPOCH,
-                                    mtime: UNIX_EPOCH,
-                                    ctime: UNIX_EPOCH,
-                                    crtime: UNIX_EPOCH,
-                                    kind: FileType::RegularFile,
-                                    perm: 0o644,
-                                    nlink: 1,
-                                    uid: 501,
-                                    gid: 20,
-                                    rdev: 0,
-                                    flags: 0,
-                                    blksize: 512,
-                                };
-                                 // Push the cached file representation into the files vector
-                                files.push(CachedFile {
-                                    ino: ino_counter,
-                                    name: file_name,
-                                    content,
-                                    attr,
-                                });
-                                // Increment inode counter for next file
-                                ino_counter += 1;
-                            }
-                        }
-                        // If there are files found in this dialog, update the cache with them
-                        if !files.is_empty() {
-                            cache.write().unwrap().insert(name.to_string(), files);
-                        }
-                    }
-                }
-                // Sleep for 10 seconds before refreshing the cache again
-                tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+    /* Remove the empty directory `name` inside the directory with inode `parent` of a chat folder
+    by deleting its marker message. Topic directories cannot be removed.*/
+    fn remove_dir(&mut self, parent: u64, name: &str) -> std::result::Result<(), i32> {
+        let (folder, dir) = self.resolve_dir(parent).ok_or(ENOENT)?;
+        let path = join(&dir, name);
+        let (marker, chat_id) = {
+            let cache = self.client.cache.read().unwrap();
+            let cached = cache.get(&folder).ok_or(ENOENT)?;
+            let found = cached.dirs.iter().find(|d| d.path == path).ok_or(ENOENT)?;
+            if found.topic.is_some() {
+                return Err(EPERM);
             }
-        });
-    }
-}
-struct TelegramFS
-{
-    client: TelegramClient,
-}
+            if !cached.is_empty_dir(&path) {
+                return Err(ENOTEMPTY);
+            }
+            (found.marker, cached.chat_id)
+        };
+        if self.uploads.values().any(|u| u.message_id.is_none() && u.folder == folder && u.dir == path) {
+            return Err(ENOTEMPTY);
+        }
 
-impl TelegramFS {
-    // Initialize TelegramFS by creating a TelegramClient and starting the cache updater task
-    pub fn init() -> Self {
-        let client = TelegramClient::init();
-        client.spawn_cache_updater(); // start cache update loop in background
+        // Directories without a marker only exist while they hold something, so an empty one has one
+        if let Some(marker) = marker
+            && let Err(e) = self.client.delete_messages(&folder, &[marker], true)
+        {
+            log::error!("failed to remove directory {folder}/{path}: {e}");
+            return Err(errno_for(e.as_ref()));
+        }
 
-        Self { client }
-    }
-    // Generate a unique inode number for a folder (chat) based on its name
-    fn folder_ino(name: &str) -> u64 {
-        use std::collections::hash_map::DefaultHasher;
-        use std::hash::{Hash, Hasher};
-        let mut hasher = DefaultHasher::new();
-        name.hash(&mut hasher);
-        hasher.finish()
+        let mut cache = self.client.cache.write().unwrap();
+        if let Some(cached) = cache.get_mut(&folder) {
+            cached.dirs.retain(|d| d.path != path);
+            let mut inodes = self.client.inodes.lock().unwrap();
+            inodes.forget(&InodeKey::Dir(chat_id, path));
+            rebuild_dirs(cached, &mut inodes);
+            if let Err(e) = inodes.save() {
+                log::error!("failed to save inode table: {e}");
+            }
+        }
+        Ok(())
     }
 }
 
 impl Filesystem for TelegramFS {
 
+    // Called when the filesystem is unmounted; report the final cache counters
+    fn destroy(&mut self) {
+        let mem_cache = self.mem_cache.lock().unwrap();
+        log::info!("memory cache: {} bytes used, {}", mem_cache.used(), mem_cache.stats());
+    }
+
     /*The `lookup` method is called by the filesystem when
     the OS wants to resolve a filename within a given directory (inode).
     It checks if the requested name exists as a folder (Telegram chat) when
-    the parent inode is 1 (root folder), or as a file inside a folder otherwise.
+    the parent inode is 1 (root folder), or as a directory or file inside a folder otherwise.
     If found, it replies with the file or folder metadata (attributes),
     otherwise returns a "not found" error.*/
     fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
@@ -274,38 +2041,61 @@ impl Filesystem for TelegramFS {
         // Acquire a read lock on the cached files
         let cache = self.client.cache.read().unwrap();
 
-        if parent == 1 {
+        let by_id = self.by_id_ino();
+        if parent == self.client.root {
             // Parent inode 1 means we are looking for a folder (Telegram chat)
-            if cache.contains_key(name) {
-                let attr = FileAttr {
-                    ino: Self::folder_ino(name), // generate inode for the folder
-                    size: 0,
-                    blocks: 0,
-                    atime: UNIX_EPOCH,
-                    mtime: UNIX_EPOCH,
-                    ctime: UNIX_EPOCH,
-                    crtime: UNIX_EPOCH,
-                    kind: FileType::Directory,
-                    perm: 0o755,
-                    nlink: 2,
-                    uid: 501,
-                    gid: 20,
-                    rdev: 0,
-                    flags: 0,
-                    blksize: 512,
-                };
+            if let Some(folder) = cache.get(name) {
+                let attr = self.folder_attr(folder.ino, folder);
                 // Reply with the directory entry and TTL (cache timeout)
-                reply.entry(&TTL, &attr, 0);
+                reply.entry(&self.ttl, &attr, 0);
+                return;
+            }
+            if let Some(by_id) = by_id.filter(|_| name == BY_ID_DIR) {
+                let mtime = cache.values().map(|f| f.mtime).max().unwrap_or(SystemTime::UNIX_EPOCH);
+                reply.entry(&self.ttl, &dir_attr(by_id, mtime, self.owner), 0);
+                return;
+            }
+        } else if let Some((folder, kind)) = self.find_view(&cache, parent) {
+            // A file in a media type view of a chat folder
+            if let Some(file) = self.view_cache.files(folder, kind, &self.naming).iter().find(|f| f.name == name) {
+                reply.entry(&self.ttl, &self.client.links.attr(file), 0);
+                return;
+            }
+        } else if Some(parent) == by_id {
+            // A link in the `by-id` directory, named after the chat id
+            let found = name.parse::<i64>().ok().and_then(|id| cache.iter().find(|(_, folder)| folder.chat_id == id));
+            if let Some((folder_name, folder)) = found
+                && let Some(link) = self.link_ino(folder.chat_id)
+            {
+                reply.entry(&self.ttl, &self.link_attr(link, folder_name, folder), 0);
                 return;
             }
         } else {
-            // Otherwise, we are looking for a file inside a folder
-            // Find the folder name by matching the inode number
-            if let Some((_, files)) = cache.iter().find(|(folder_name, _)| Self::folder_ino(folder_name) == parent) {
-                // Find the file by its name inside the folder's files
-                if let Some(file) = files.iter().find(|f| f.name == name) {
+            // Otherwise, we are looking for something inside a folder or one of its directories
+            // Find the folder and the directory by matching the inode number
+            let found = cache.iter().find_map(|(folder_name, folder)| Some((folder_name, folder, folder.dir_path(parent)?)));
+            if let Some((folder_name, folder, dir)) = found {
+                let path = join(dir, name);
+                if let Some(sub) = folder.dirs.iter().find(|d| d.path == path) {
+                    reply.entry(&self.ttl, &dir_attr(sub.ino, sub.mtime, self.owner), 0);
+                    return;
+                }
+                // Find the file by its name inside the directory
+                if let Some(file) = folder.files.iter().find(|f| f.dir == dir && f.name == name) {
                     // Reply with the file entry attributes and TTL
-                    reply.entry(&TTL, &file.attr, 0);
+                    reply.entry(&self.ttl, &self.client.links.attr(file), 0);
+                    return;
+                }
+                // The file may also be one that is still being written
+                if let Some(fh) = self.pending_fh(folder_name, dir, name) {
+                    reply.entry(&self.ttl, &self.pending_attr(&self.uploads[&fh]), 0);
+                    return;
+                }
+                // Or a view of the files of one media type
+                if let Some(kind) = MediaKind::from_dir_name(name).filter(|kind| dir.is_empty() && self.visible_views(folder).contains(kind))
+                    && let Some(view) = self.view_ino(folder.chat_id, kind)
+                {
+                    reply.entry(&self.ttl, &dir_attr(view, folder.mtime, self.owner), 0);
                     return;
                 }
             }
@@ -318,50 +2108,57 @@ impl Filesystem for TelegramFS {
     /*  The `getattr` method returns the metadata (attributes) of a file or directory
     identified by its inode number `ino`.
     If the inode is 1, it means the root directory, so reply with predefined root directory attributes.
-    Otherwise, check if the inode matches a Telegram chat folder or a file in the cache.
+    Otherwise, check if the inode matches a Telegram chat folder, a directory or a file in the cache.
     If found, respond with the appropriate attributes; if not, return an error.*/
     fn getattr(&mut self, _req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
-        if ino == 1 {
+        if ino == self.client.root {
             // Root directory inode
-            reply.attr(&TTL, &DIR_ATTR);
+            reply.attr(&self.ttl, &self.root_attr());
             return;
         }
         // Acquire read lock on the cache to access cached Telegram chats and files
         let cache = self.client.cache.read().unwrap();
 
-        // Check if inode corresponds to a folder (Telegram chat)
-        for folder_name in cache.keys() {
-            if TelegramFS::folder_ino(folder_name) == ino {
-                let attr = FileAttr {
-                    ino,
-                    size: 0,
-                    blocks: 0,
-                    atime: UNIX_EPOCH,
-                    mtime: UNIX_EPOCH,
-                    ctime: UNIX_EPOCH,
-                    crtime: UNIX_EPOCH,
-                    kind: FileType::Directory,
-                    perm: 0o755,
-                    nlink: 2,
-                    uid: 501,
-                    gid: 20,
-                    rdev: 0,
-                    flags: 0,
-                    blksize: 512,
-                };
-                reply.attr(&TTL, &attr);
+        if self.by_id_ino() == Some(ino) {
+            let mtime = cache.values().map(|f| f.mtime).max().unwrap_or(SystemTime::UNIX_EPOCH);
+            reply.attr(&self.ttl, &dir_attr(ino, mtime, self.owner));
+            return;
+        }
+        if let Some((name, folder)) = self.link_folder(&cache, ino) {
+            reply.attr(&self.ttl, &self.link_attr(ino, name, folder));
+            return;
+        }
+        if let Some((folder, _)) = self.find_view(&cache, ino) {
+            reply.attr(&self.ttl, &dir_attr(ino, folder.mtime, self.owner));
+            return;
+        }
+
+        // Check if inode corresponds to a folder (Telegram chat) or a directory inside one
+        for folder in cache.values() {
+            if folder.ino == ino {
+                reply.attr(&self.ttl, &self.folder_attr(ino, folder));
+                return;
+            }
+            if let Some(dir) = folder.dirs.iter().find(|d| d.ino == ino) {
+                reply.attr(&self.ttl, &dir_attr(ino, dir.mtime, self.owner));
                 return;
             }
         }
 
         
         // Otherwise, look for a file with matching inode inside cached folders
-        for files in cache.values() {
-            if let Some(file) = files.iter().find(|f| f.ino == ino) {
-                reply.attr(&TTL, &file.attr);
+        for folder in cache.values() {
+            if let Some(file) = folder.files.iter().find(|f| f.ino == ino) {
+                reply.attr(&self.ttl, &self.client.links.attr(file));
                 return;
             }
         }
+
+        // Or for a file that is still being written
+        if let Some(upload) = self.pending_by_ino(ino) {
+            reply.attr(&self.ttl, &self.pending_attr(upload));
+            return;
+        }
         
         // If inode not found, return "not found" error
         reply.error(ENOENT);
@@ -370,29 +2167,58 @@ impl Filesystem for TelegramFS {
 
     /* The `readdir` method lists directory contents based on the given `ino` (inode number).
     If `ino == 1`, this is the root directory, and we return a list of chat folders (Telegram dialogs).
-    Otherwise, we treat it as a chat folder and return the list of media files associated with that chat.
+    Otherwise, we treat it as a chat folder or a directory inside one and return its
+    directories and the media files stored in it.
     The `offset` is used by FUSE for pagination; we skip entries up to the given offset.
     We must call `reply.add()` for each entry, and finally `reply.ok()` to finish.*/
     fn readdir( &mut self, _req: &fuser::Request<'_>, ino: u64, _fh: u64, offset: i64, mut reply: fuser::ReplyDirectory,) {
         let cache = self.client.cache.read().unwrap();
 
-        // Initial entries: "." (self) and ".." (parent)
-        let mut entries: Vec<(u64, FileType, String)> = vec![
-            (ino, FileType::Directory, ".".to_string()),
-            (1, FileType::Directory, "..".to_string()),
-        ];
+        // Initial entry: "." (self); ".." (parent) depends on where the directory is
+        let mut entries: Vec<(u64, FileType, String)> = vec![(ino, FileType::Directory, ".".to_string())];
 
-        if ino == 1 {
+        if ino == self.client.root {
+            entries.push((ROOT_INO, FileType::Directory, "..".to_string()));
             // We're in the root directory. List all folders (Telegram chats).
-            for folder_name in cache.keys() {
-                entries.push((Self::folder_ino(folder_name), FileType::Directory, folder_name.clone()));
+            for (folder_name, folder) in cache.iter() {
+                entries.push((folder.ino, FileType::Directory, folder_name.clone()));
+            }
+            entries.extend(self.by_id_ino().map(|by_id| (by_id, FileType::Directory, BY_ID_DIR.to_string())));
+        } else if let Some((folder, kind)) = self.find_view(&cache, ino) {
+            // The files of one media type, from anywhere in the chat folder
+            entries.push((folder.ino, FileType::Directory, "..".to_string()));
+            for file in self.view_cache.files(folder, kind, &self.naming).iter() {
+                entries.push((file.ino, FileType::RegularFile, file.name.clone()));
+            }
+        } else if self.by_id_ino() == Some(ino) {
+            // The links to the chat folders by chat id
+            entries.push((self.client.root, FileType::Directory, "..".to_string()));
+            for folder in cache.values() {
+                entries.extend(self.link_ino(folder.chat_id).map(|link| (link, FileType::Symlink, folder.chat_id.to_string())));
             }
         } else {
-            // We're in a chat folder. Find the matching chat and list its media files.
-            if let Some((_, files)) = cache.iter().find(|(name, _)| Self::folder_ino(name) == ino) {
-                for file in files {
+            // We're in a chat folder or below. Find the matching directory and list what it holds.
+            let found = cache.values().find_map(|folder| Some((folder, folder.dir_path(ino)?)));
+            if let Some((folder, dir)) = found {
+                let parent = if dir.is_empty() { self.client.root } else { folder.dir_ino(split(dir).0).unwrap_or(folder.ino) };
+                entries.push((parent, FileType::Directory, "..".to_string()));
+                for sub in folder.dirs.iter().filter(|d| split(&d.path).0 == dir) {
+                    entries.push((sub.ino, FileType::Directory, split(&sub.path).1.to_string()));
+                }
+                for file in folder.files.iter().filter(|f| f.dir == dir) {
                     entries.push((file.ino, FileType::RegularFile, file.name.clone()));
                 }
+                if dir.is_empty() {
+                    for kind in self.visible_views(folder) {
+                        entries.extend(self.view_ino(folder.chat_id, kind).map(|view| (view, FileType::Directory, kind.dir_name().to_string())));
+                    }
+                }
+                // Files that are still being written show up as well
+                for upload in self.uploads.values() {
+                    if upload.message_id.is_none() && upload.chat_id == folder.chat_id && upload.dir == dir {
+                        entries.push((upload.ino, FileType::RegularFile, upload.name.clone()));
+                    }
+                }
             } else {
                 // No matching chat folder found → return error
                 reply.error(ENOENT);
@@ -410,76 +2236,691 @@ impl Filesystem for TelegramFS {
         reply.ok(); // Signal successful directory listing
     }
 
+    // `readlink` returns where a link in the `by-id` directory points: the folder of its chat
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        let cache = self.client.cache.read().unwrap();
+        match self.link_folder(&cache, ino) {
+            Some((name, _)) => reply.data(link_target(name).as_bytes()),
+            None => reply.error(ENOENT),
+        }
+    }
+
     /* This method handles reading data from a file identified by `ino` (inode number).
     It returns up to `size` bytes starting from `offset`.
-    The file content is retrieved from the in-memory cache.*/
-    fn read( &mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, size: u32, _flags: i32, _lock: Option<u64>, reply: ReplyData,) {
-        let cache = self.client.cache.read().unwrap();
-        
-        // Search all cached chat folders
-        for files in cache.values() {
-            // Look for the file with the matching inode number
-            if let Some(file) = files.iter().find(|f| f.ino == ino) {
-                let data = &file.content;
-                let start = offset as usize;
-                // Ensure we don't read past the end of the file
-                let end = std::cmp::min(start + size as usize, data.len());
-                // Return the slice of data from offset to end
+    Media is read through the block cache, downloading only the blocks that cover
+    the requested range and are not cached yet; inline content is served from memory.*/
+    fn read( &mut self, _req: &Request, ino: u64, fh: u64, offset: i64, size: u32, _flags: i32, _lock: Option<u64>, reply: ReplyData,) {
+        // A file still being written is read back from its staging buffer, also when opened for reading only
+        if let Some(upload) = self.uploads.get(&fh).or_else(|| self.pending_by_ino(ino)) {
+            match upload.data.read_at(offset as u64, size as u64) {
+                Ok(data) => reply.data(&data),
+                Err(e) => reply.error(io_errno(&e)),
+            }
+            return;
+        }
+
+        // Search all cached chat folders for the file with the matching inode number.
+        // The lock is released before downloading, so the cache updater is not blocked meanwhile.
+        let found = {
+            let cache = self.client.cache.read().unwrap();
+            cache.values().flat_map(|folder| &folder.files).find(|f| f.ino == ino).cloned()
+        };
+        let Some(file) = found else {
+            // If no matching file was found, return an error
+            reply.error(ENOENT);
+            return;
+        };
+
+        // Ensure we don't read past the end of the file; the end of encrypted files is checked as well
+        let offset = min(offset as u64, file.attr.size);
+        let size = min(size as u64, file.attr.size - offset);
+        if size == 0 && file.sealed_size.is_none() {
+            reply.data(&[]);
+            return;
+        }
+
+        match &file.content {
+            FileContent::Inline(data) => {
+                let start = min(offset as usize, data.len());
+                let end = min(start + size as usize, data.len());
                 reply.data(&data[start..end]);
+            }
+            FileContent::Remote(_) | FileContent::Parts(_) => {
+                let data = match file.sealed_size {
+                    Some(_) => self.read_sealed(&file, offset, size),
+                    None => self.read_remote(&file, offset, size),
+                };
+                match data {
+                    Ok(data) => reply.data(&data),
+                    Err(e) => {
+                        log::error!("failed to download inode {ino} at offset {offset}: {e}");
+                        reply.error(EIO);
+                    }
+                }
+            }
+        }
+    }
+
+    /* `open` is called before reading or writing an existing file.
+    Files that already live in Telegram can only be read; changing them
+    in place is not supported, so write access is refused.*/
+    fn open(&mut self, _req: &Request<'_>, _ino: u64, flags: i32, reply: ReplyOpen) {
+        if flags & libc::O_ACCMODE != libc::O_RDONLY {
+            reply.error(EPERM);
+            return;
+        }
+        reply.opened(0, 0);
+    }
+
+    /* `create` makes a new, empty file inside a chat folder or one of its directories and opens it.
+    The content is staged in a temporary file until the file is flushed, at which
+    point it is uploaded to the chat. Files cannot be created in the root,
+    since it only holds chat folders, nor directly in the chat folder of a forum,
+    which only holds topics.*/
+    fn create(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, _mode: u32, _umask: u32, _flags: i32, reply: ReplyCreate) {
+        let Some((folder, dir)) = self.resolve_dir(parent) else {
+            reply.error(self.not_a_chat_dir(parent));
+            return;
+        };
+        if dir.is_empty() && self.is_forum(&folder) {
+            reply.error(EPERM);
+            return;
+        }
+        let Some(name) = name.to_str() else {
+            reply.error(libc::EINVAL);
+            return;
+        };
+        let Some(chat_id) = self.folder_chat(&folder) else {
+            reply.error(ENOENT);
+            return;
+        };
+
+        let data = match Staged::new(&self.staging_dir) {
+            Ok(data) => data,
+            Err(e) => {
+                log::error!("failed to create a staging file in {}: {e}", self.staging_dir.display());
+                reply.error(io_errno(&e));
                 return;
             }
+        };
+
+        // The inode is tied to the message once the file is uploaded
+        let Some(ino) = self.client.inodes.lock().unwrap().allocate() else {
+            reply.error(ENOSPC);
+            return;
+        };
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.uploads.insert(fh, PendingUpload {
+            ino,
+            folder,
+            chat_id,
+            dir,
+            name: name.to_string(),
+            data,
+            dirty: true,
+            message_id: None,
+            mtime: SystemTime::now(),
+        });
+
+        reply.created(&self.ttl, &self.pending_attr(&self.uploads[&fh]), 0, fh, 0);
+    }
+
+    /* `write` stores `data` at `offset` in the staging buffer of a file opened by `create`.
+    Gaps left by writing past the end are filled with zeros; files cannot grow beyond `MAX_FILE_SIZE`.*/
+    fn write(&mut self, _req: &Request<'_>, _ino: u64, fh: u64, offset: i64, data: &[u8], _write_flags: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyWrite) {
+        let Some(upload) = self.uploads.get_mut(&fh) else {
+            reply.error(EBADF);
+            return;
+        };
+
+        let start = u64::try_from(offset).unwrap_or(u64::MAX);
+        if start.saturating_add(data.len() as u64) > MAX_FILE_SIZE {
+            reply.error(EFBIG);
+            return;
         }
-        // If no matching file was found, return an error
-        reply.error(ENOENT);
-    }    
+        if let Err(e) = upload.data.write_at(start, data) {
+            reply.error(io_errno(&e));
+            return;
+        }
+        upload.dirty = true;
+        upload.mtime = SystemTime::now();
+
+        reply.written(data.len() as u32);
+    }
+
+    /* `setattr` is used for truncation and by tools like `touch` or `cp -p`.
+    Only the size of a file that is still being written can change; other
+    attributes are fixed by Telegram, so the request is answered with the
+    current attributes.*/
+    fn setattr(&mut self, _req: &Request<'_>, ino: u64, _mode: Option<u32>, _uid: Option<u32>, _gid: Option<u32>, size: Option<u64>, _atime: Option<TimeOrNow>, _mtime: Option<TimeOrNow>, _ctime: Option<SystemTime>, fh: Option<u64>, _crtime: Option<SystemTime>, _chgtime: Option<SystemTime>, _bkuptime: Option<SystemTime>, _flags: Option<u32>, reply: ReplyAttr) {
+        let fh = fh.or_else(|| self.uploads.iter().find(|(_, u)| u.ino == ino).map(|(fh, _)| *fh));
+        if let Some(fh) = fh
+            && let Some(upload) = self.uploads.get_mut(&fh)
+        {
+            if let Some(size) = size {
+                if size > MAX_FILE_SIZE {
+                    reply.error(EFBIG);
+                    return;
+                }
+                if let Err(e) = upload.data.set_len(size) {
+                    reply.error(io_errno(&e));
+                    return;
+                }
+                upload.dirty = true;
+                upload.mtime = SystemTime::now();
+            }
+            reply.attr(&self.ttl, &self.pending_attr(&self.uploads[&fh]));
+            return;
+        }
+
+        if size.is_some() {
+            reply.error(EPERM);
+            return;
+        }
+        let cache = self.client.cache.read().unwrap();
+        match cache.values().flat_map(|folder| &folder.files).find(|f| f.ino == ino) {
+            Some(file) => reply.attr(&self.ttl, &self.client.links.attr(file)),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    /* `unlink` removes a file by deleting the message that carries it, see `delete_file`.
+    A file that is still being written and was never uploaded is simply dropped.*/
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let name = name.to_str().unwrap_or("");
+        // The root only holds chat folders, and the views and `by-id` links cannot be changed
+        let Some((folder, dir)) = self.resolve_dir(parent) else {
+            reply.error(self.not_a_chat_dir(parent));
+            return;
+        };
+
+        if let Some(fh) = self.pending_fh(&folder, &dir, name) {
+            self.uploads.remove(&fh);
+            reply.ok();
+            return;
+        }
+
+        let Some(file) = self.find_file(&folder, &dir, name) else {
+            reply.error(ENOENT);
+            return;
+        };
+        match self.delete_file(&folder, &file) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    /* `link` gives the file with inode `ino` another name, `newname` in the directory `newparent`,
+    by forwarding it there, see `link_file`. Both names share the inode, so links of a file can
+    only be made once it is uploaded. Like files, links cannot be made in the root nor directly
+    in the chat folder of a forum.*/
+    fn link(&mut self, _req: &Request<'_>, ino: u64, newparent: u64, newname: &OsStr, reply: ReplyEntry) {
+        let Some((to, dir)) = self.resolve_dir(newparent) else {
+            reply.error(self.not_a_chat_dir(newparent));
+            return;
+        };
+        if dir.is_empty() && self.is_forum(&to) {
+            reply.error(EPERM);
+            return;
+        }
+        let Some(name) = newname.to_str() else {
+            reply.error(libc::EINVAL);
+            return;
+        };
+        if self.find_file(&to, &dir, name).is_some() || self.pending_fh(&to, &dir, name).is_some() {
+            reply.error(EEXIST);
+            return;
+        }
+
+        let found = {
+            let cache = self.client.cache.read().unwrap();
+            cache.iter().find_map(|(folder, f)| Some((folder.clone(), f.files.iter().find(|f| f.ino == ino)?.clone())))
+        };
+        let Some((from, file)) = found else {
+            reply.error(if self.pending_by_ino(ino).is_some() { EPERM } else { ENOENT });
+            return;
+        };
+        match self.link_file(&from, &file, &to, &dir, name) {
+            Ok(attr) => reply.entry(&self.ttl, &attr, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    /* `mkdir` at the root creates a new private chat, see `--mkdir-creates`,
+    and shows it as an empty folder right away. Anywhere below, it creates a
+    directory by posting its marker message to the chat, see the `tree` module.*/
+    fn mkdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, _mode: u32, _umask: u32, reply: ReplyEntry) {
+        let Some(name) = name.to_str() else {
+            reply.error(EINVAL);
+            return;
+        };
+        if parent != self.client.root {
+            match self.make_dir(parent, name) {
+                Ok(attr) => reply.entry(&self.ttl, &attr, 0),
+                Err(e) => reply.error(e),
+            }
+            return;
+        }
+        // Telegram limits chat titles to 128 characters
+        if name.chars().count() > MAX_TITLE_LEN {
+            reply.error(ENAMETOOLONG);
+            return;
+        }
+        if self.client.cache.read().unwrap().contains_key(name) || (self.by_id && name == BY_ID_DIR) {
+            reply.error(EEXIST);
+            return;
+        }
+
+        let chat = match self.client.create_chat(name, self.new_chat_kind) {
+            Ok(chat) => chat,
+            Err(e) => {
+                log::error!("failed to create chat {name}: {e}");
+                reply.error(errno_for(e.as_ref()));
+                return;
+            }
+        };
+
+        let Some(ino) = self.client.inodes.lock().unwrap().get_or_assign(InodeKey::Chat(chat.id())) else {
+            reply.error(ENOSPC);
+            return;
+        };
+        if let Err(e) = self.client.inodes.lock().unwrap().save() {
+            log::error!("failed to save inode table: {e}");
+        }
+        self.client.chats.write().unwrap().insert(name.to_string(), chat.pack());
+        self.client.moderated.write().unwrap().insert(chat.id());
+        let folder = CachedFolder {
+            ino,
+            chat_id: chat.id(),
+            files: vec![],
+            dirs: vec![],
+            parts: HashMap::new(),
+            mtime: SystemTime::now(),
+            version: next_folder_version(),
+        };
+        let attr = self.folder_attr(ino, &folder);
+        self.client.cache.write().unwrap().insert(name.to_string(), folder);
+        reply.entry(&self.ttl, &attr, 0);
+    }
+
+    /* `rmdir` removes a chat folder once it is empty, e.g. at the end of `rm -r`.
+    The chat itself is left alone; its folder comes back when a file is posted to it.
+    The chat also forgets its inode, so an empty chat does not come back on the next mount.
+    Empty directories inside a chat folder are removed by deleting their marker message.*/
+    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let name = name.to_str().unwrap_or("");
+        if parent != self.client.root {
+            match self.remove_dir(parent, name) {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(e),
+            }
+            return;
+        }
+        if self.uploads.values().any(|u| u.message_id.is_none() && u.folder == name) {
+            reply.error(ENOTEMPTY);
+            return;
+        }
+
+        let mut cache = self.client.cache.write().unwrap();
+        match cache.get(name) {
+            Some(folder) if !folder.is_empty_dir("") => reply.error(ENOTEMPTY),
+            Some(folder) => {
+                let mut inodes = self.client.inodes.lock().unwrap();
+                inodes.forget(&InodeKey::Chat(folder.chat_id));
+                if let Err(e) = inodes.save() {
+                    log::error!("failed to save inode table: {e}");
+                }
+                drop(inodes);
+                cache.remove(name);
+                reply.ok();
+            }
+            None => reply.error(ENOENT),
+        }
+    }
+
+    /* `rename` renames a file within its chat folder or moves it to another directory or
+    chat folder, see `rename_file` and `move_file`; moving a file to another forum topic
+    re-sends it like moving it to another chat. A file that already has the new name is
+    deleted once the renamed file is in its place, and the rename fails if it cannot be deleted;
+    a file of that name still being written is dropped like by `unlink`. Files still being
+    written only change where they will be uploaded to.
+    Chat folders and directories cannot be renamed.*/
+    fn rename(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, flags: u32, reply: ReplyEmpty) {
+        let (name, newname) = (name.to_str().unwrap_or(""), newname.to_str().unwrap_or(""));
+        if flags & libc::RENAME_EXCHANGE != 0 {
+            reply.error(EINVAL);
+            return;
+        }
+        if parent == self.client.root || newparent == self.client.root {
+            reply.error(EPERM);
+            return;
+        }
+        let (Some((from, from_dir)), Some((to, to_dir))) = (self.resolve_dir(parent), self.resolve_dir(newparent)) else {
+            reply.error(ENOENT);
+            return;
+        };
+        if from == to && from_dir == to_dir && name == newname {
+            reply.ok();
+            return;
+        }
+
+        // Directories would have to move with everything below them; `mv` copies them instead
+        let is_dir = |folder: &str, path: String| {
+            let cache = self.client.cache.read().unwrap();
+            cache.get(folder).is_some_and(|f| f.dirs.iter().any(|d| d.path == path))
+        };
+        if is_dir(&from, join(&from_dir, name)) {
+            reply.error(EXDEV);
+            return;
+        }
+        if is_dir(&to, join(&to_dir, newname)) {
+            reply.error(EISDIR);
+            return;
+        }
+        if to_dir.is_empty() && self.is_forum(&to) {
+            reply.error(EPERM);
+            return;
+        }
+
+        let target = self.find_file(&to, &to_dir, newname);
+        let pending_target = self.pending_fh(&to, &to_dir, newname);
+        if (target.is_some() || pending_target.is_some()) && flags & libc::RENAME_NOREPLACE != 0 {
+            reply.error(EEXIST);
+            return;
+        }
+        let source_exists = self.pending_fh(&from, &from_dir, name).is_some() || self.find_file(&from, &from_dir, name).is_some();
+        if !source_exists {
+            reply.error(ENOENT);
+            return;
+        }
+        // A target that could not be deleted afterwards fails the rename before anything changed
+        if let Some(target) = &target
+            && let Err(e) = self.may_delete(&to, target)
+        {
+            reply.error(e);
+            return;
+        }
+
+        let result = if let Some(fh) = self.pending_fh(&from, &from_dir, name) {
+            let chat_id = self.folder_chat(&to);
+            let upload = self.uploads.get_mut(&fh).unwrap();
+            upload.name = newname.to_string();
+            upload.folder = to.clone();
+            upload.chat_id = chat_id.unwrap_or(upload.chat_id);
+            upload.dir = to_dir.clone();
+            Ok(())
+        } else {
+            match self.find_file(&from, &from_dir, name) {
+                Some(file) if from == to && self.placement(&from, &from_dir).0 == self.placement(&to, &to_dir).0 => {
+                    self.rename_file(&from, &file, &to_dir, newname);
+                    Ok(())
+                }
+                Some(file) => self.move_file(&from, &file, &to, &to_dir, newname),
+                None => Err(ENOENT),
+            }
+        };
+
+        // The replaced file goes only once the renamed one is in its place, so a failure to send it leaves both files as they were
+        if let Some(fh) = pending_target.filter(|_| result.is_ok()) {
+            self.uploads.remove(&fh);
+        }
+        let result = match target {
+            Some(target) if result.is_ok() => self.delete_file(&to, &target).inspect_err(|e| {
+                log::error!("failed to delete {to}/{} to replace it by rename: errno {e}", join(&to_dir, newname));
+            }),
+            _ => result,
+        };
+        match result {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    /* `flush` is called on every close() of a file descriptor.
+    This is where staged writes are uploaded, so that upload errors
+    are reported back to the program closing the file.*/
+    fn flush(&mut self, _req: &Request<'_>, _ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        if !self.uploads.contains_key(&fh) {
+            // Files opened for reading have nothing to flush
+            reply.ok();
+            return;
+        }
+        match self.flush_upload(fh) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    /* `release` is called once the last descriptor of an open file is closed.
+    Anything not uploaded by `flush` yet is uploaded now and the staging buffer is dropped.*/
+    fn release(&mut self, _req: &Request<'_>, _ino: u64, fh: u64, _flags: i32, _lock_owner: Option<u64>, _flush: bool, reply: ReplyEmpty) {
+        if !self.uploads.contains_key(&fh) {
+            reply.ok();
+            return;
+        }
+        let result = self.flush_upload(fh);
+        self.uploads.remove(&fh);
+        match result {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
 }
 
 fn main() {
-    // Expect a mountpoint as the first argument (after the binary name)
-    let mountpoint = env::args().nth(1).expect("Usage: ./program <mountpoint>");
+    // `login` signs in and saves the session the daemon is started with
+    if env::args().nth(1).as_deref() == Some("login") {
+        if let Err(e) = login::run(env::args().skip(2).collect()) {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Expect a mountpoint as the first argument (after the binary name), followed by options
+    let options = Options::from_args().unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(2);
+    });
+
+    SimpleLogger::new()
+        .with_level(log::LevelFilter::Info)
+        .init()
+        .unwrap();
+
+    match options.accounts.as_slice() {
+        // A single account: its chats are the top-level directories
+        [account] if account.name.is_none() => {
+            // Initialize our custom filesystem (which connects to Telegram and spawns a cache updater)
+            let fs = TelegramFS::init(&options, account).unwrap_or_else(|e| {
+                eprintln!("{e}");
+                std::process::exit(1);
+            });
+            // Keep a handle to the notifier slot, the filesystem itself moves into the session
+            let notifier = Arc::clone(&fs.client.notifier);
+            mount(fs, &options, vec![notifier]);
+        }
+        // Several accounts: each is a top-level directory, and one that fails is left out
+        accounts => {
+            let mut mounted = vec![];
+            for account in accounts {
+                let name = account.name.clone().unwrap_or_default();
+                match TelegramFS::init(&options, account) {
+                    Ok(fs) => mounted.push((name, fs)),
+                    Err(e) => log::error!("leaving out account {name}: {e}"),
+                }
+            }
+            if mounted.is_empty() {
+                eprintln!("none of the accounts could be mounted");
+                std::process::exit(1);
+            }
+            let fs = Accounts::new(mounted, options.owner, options.ttl);
+            let notifiers = fs.notifiers();
+            mount(fs, &options, notifiers);
+        }
+    }
+}
 
-    // Initialize our custom filesystem (which connects to Telegram and spawns a cache updater)
-    let fs = TelegramFS::init();
+// Mount `fs` and serve it until it is unmounted, telling `notifiers` where to send changes
+fn mount(fs: impl Filesystem, options: &Options, notifiers: Vec<Arc<OnceLock<Notifier>>>) {
+    let mut mount_options = vec![
+        MountOption::RW, // Files can be written to upload them
+        MountOption::FSName("telegramfs".into()), // Filesystem name shown in system tools
+        MountOption::AutoUnmount, // Auto-unmount on process exit
+    ];
+    if options.allow_other {
+        mount_options.push(MountOption::AllowOther); // Allow users other than the mounter to access
+    }
 
     // Mount the filesystem using FUSE (via fuser crate)
-    fuser::mount2(
+    let mut session = fuser::Session::new(
         fs, // our filesystem implementation
-        mountpoint, // where to mount it in the system
-        &[
-            MountOption::RO, // Read-only filesystem
-            MountOption::FSName("telegramfs".into()), // Filesystem name shown in system tools
-            MountOption::AutoUnmount, // Auto-unmount on process exit
-            MountOption::AllowOther,  // Allow users other than the mounter to access
-        ],
+        &options.mountpoint, // where to mount it in the system
+        &mount_options,
     ).unwrap(); // Panic if mounting fails
+
+    // From now on the cache updaters can tell the kernel about changed chats
+    for notifier in notifiers {
+        let _ = notifier.set(session.notifier());
+    }
+    session.run().unwrap();
 }
 
 /* helper functions */
-fn get_file_extension(media: &Media) -> String {
+// The errno for a failed operation on a local file
+fn io_errno(e: &io::Error) -> i32 {
+    e.raw_os_error().unwrap_or(EIO)
+}
+
+// Where the link to the chat folder `name` in the `by-id` directory points
+fn link_target(name: &str) -> String {
+    format!("../{name}")
+}
+
+// Parse a size such as `4096`, `512K`, `512M` or `2G` into bytes
+fn parse_size(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let (number, unit) = match value.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => value.split_at(i),
+        None => (value, ""),
+    };
+    let multiplier = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1024,
+        "M" | "MB" | "MIB" => 1024 * 1024,
+        "G" | "GB" | "GIB" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+// Default location of the block cache: $XDG_CACHE_HOME/telegramfs or ~/.cache/telegramfs
+fn default_cache_dir() -> PathBuf {
+    let base = env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .unwrap_or_else(env::temp_dir);
+    base.join("telegramfs")
+}
+
+/* The errno reported for an error from Telegram:
+EPERM when the operation is not allowed on that message or for bots, EACCES when
+this account lacks the rights for it in the chat, EIO for anything else.*/
+fn errno_for(e: &(dyn std::error::Error + 'static)) -> i32 {
+    let Some(InvocationError::Rpc(rpc)) = e.downcast_ref::<InvocationError>() else {
+        return EIO;
+    };
+    match rpc.name.as_str() {
+        "MESSAGE_DELETE_FORBIDDEN" | "MESSAGE_ID_INVALID" | "BOT_METHOD_INVALID" => EPERM,
+        "CHAT_ADMIN_REQUIRED" | "CHANNEL_PRIVATE" | "CHAT_WRITE_FORBIDDEN" | "USER_BANNED_IN_CHANNEL" => EACCES,
+        _ => EIO,
+    }
+}
+
+// Describe how the content of `media` can be read, together with its size in bytes.
+// Media that has nothing to download (polls, locations, ...) is not exposed as a file.
+fn file_content(media: Media) -> Option<(FileContent, u64)> {
     match media {
-        Photo(_) => ".jpg".to_string(), // If the media is a photo, use .jpg extension
-        Sticker(sticker) => get_mime_extension(sticker.document.mime_type()), // If it's a sticker, determine extension from MIME type (e.g., .webp)
-        Document(document) => get_mime_extension(document.mime_type()), // If it's a document, extract extension from its MIME type
-        Contact(_) => ".vcf".to_string(),  // If it's a contact (vCard), use .vcf extension
-        _ => String::new(), // For all other media types, return an empty string (no extension)
-    }
-}
-
-fn get_mime_extension(mime_type: Option<&str>) -> String {
-    mime_type
-        .and_then(|m| m.parse::<Mime>().ok()) // Try to parse the MIME type string into a `Mime` object
-        .map(|mime| format!(".{}", mime.subtype())) // If successful, get the subtype (like "pdf", "jpeg", "mp4", etc.) and prepend a dot
-        .unwrap_or(".bin".to_string())  // If parsing fails or MIME type is missing, default to ".bin"
-}
-
-fn prompt(message: &str) -> Result<String> {
-    let stdout = io::stdout();
-    let mut stdout = stdout.lock();
-    stdout.write_all(message.as_bytes())?;
-    stdout.flush()?;
-    let stdin = io::stdin();
-    let mut stdin = stdin.lock();
-    let mut line = String::new();
-    stdin.read_line(&mut line)?;
-    Ok(line)
-}
\ No newline at end of file
+        Contact(contact) => {
+            let vcard = contact.vcard().as_bytes().to_vec();
+            let size = vcard.len() as u64;
+            Some((FileContent::Inline(Arc::new(vcard)), size))
+        }
+        Photo(ref photo) => {
+            let size = photo.size() as u64;
+            Some((FileContent::Remote(Arc::new(media)), size))
+        }
+        Sticker(ref sticker) => {
+            let size = sticker.document.size() as u64;
+            Some((FileContent::Remote(Arc::new(media)), size))
+        }
+        Document(ref document) => {
+            let size = document.size() as u64;
+            Some((FileContent::Remote(Arc::new(media)), size))
+        }
+        _ => None,
+    }
+}
+
+/* The messages `file` is stored in: its own, and those of its parts if it was split.
+Parts that another manifest in its folder lists as well are left to that one.*/
+fn message_ids(folder: Option<&CachedFolder>, file: &CachedFile) -> Vec<i32> {
+    let mut ids = vec![file.message_id];
+    if let FileContent::Parts(manifest) = &file.content {
+        let shared = folder.map(|folder| dedup::shared_parts(folder, file.message_id)).unwrap_or_default();
+        ids.extend(manifest.parts.iter().filter(|id| !shared.contains(id)));
+    }
+    ids
+}
+
+// File attributes of a regular file with the given inode and size.
+// `times` are the modification and change times; the file was created when it was last modified.
+fn file_attr(ino: u64, size: u64, times: (SystemTime, SystemTime), owner: Owner) -> FileAttr {
+    let (mtime, ctime) = times;
+    FileAttr {
+        ino,
+        size,
+        blocks: size.div_ceil(512),
+        atime: mtime,
+        mtime,
+        ctime,
+        crtime: mtime,
+        kind: FileType::RegularFile,
+        perm: 0o644,
+        nlink: 1,
+        uid: owner.uid,
+        gid: owner.gid,
+        rdev: 0,
+        flags: 0,
+        blksize: 512,
+    }
+}
+
+// This describes a directory inode with standard permissions, last modified at `mtime`
+fn dir_attr(ino: u64, mtime: SystemTime, owner: Owner) -> FileAttr {
+    FileAttr {
+        ino,
+        size: 0,
+        blocks: 0,
+        atime: mtime,
+        mtime,
+        ctime: mtime,
+        crtime: mtime,
+        kind: FileType::Directory,
+        perm: 0o755,
+        nlink: 2,
+        uid: owner.uid,
+        gid: owner.gid,
+        rdev: 0,
+        flags: 0,
+        blksize: 512,
+    }
+}
+
+// Modification and change time of a file taken from its message: the date it was sent and last edited
+fn message_times(msg: &Message) -> (SystemTime, SystemTime) {
+    let mtime = SystemTime::from(msg.date());
+    let ctime = msg.edit_date().map(SystemTime::from).unwrap_or(mtime);
+    (mtime, ctime)
+}
@@ -0,0 +1,135 @@
+//! Directories showing the files of a chat folder sorted by media type.
+//!
+//! With `--media-dirs` (or `media_dirs = true` in `[mount]`), every chat folder also holds
+//! `photos`, `videos`, `documents`, `audio`, `voice` and `stickers`. Each lists the files of
+//! its type from anywhere in the chat, topics and directories included, as hard links of the
+//! files themselves, so tools such as photo importers can be pointed at just the files they
+//! handle. The type comes from the kind of media (photo, sticker, voice message) and otherwise
+//! from the MIME type of the document, or from the file name when that tells nothing, as for
+//! encrypted files and files split into parts.
+//!
+//! Names that are taken twice in a view are numbered like in any directory, see `NamingRules`.
+//! The views are read-only; a real directory or file of the same name hides its view.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use grammers_client::types::media::Document as TgDocument;
+use grammers_client::grammers_tl_types as tl;
+use grammers_client::types::Media::{Document, Photo, Sticker};
+
+use crate::naming::{dedup_names, mime_essence, NamingRules};
+use crate::{CachedFile, CachedFolder, FileContent};
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum MediaKind {
+    Photos,
+    Videos,
+    Documents,
+    Audio,
+    Voice,
+    Stickers,
+}
+
+impl MediaKind {
+    pub const ALL: [Self; 6] = [Self::Photos, Self::Videos, Self::Documents, Self::Audio, Self::Voice, Self::Stickers];
+
+    // Name of the directory of this view
+    pub fn dir_name(self) -> &'static str {
+        match self {
+            Self::Photos => "photos",
+            Self::Videos => "videos",
+            Self::Documents => "documents",
+            Self::Audio => "audio",
+            Self::Voice => "voice",
+            Self::Stickers => "stickers",
+        }
+    }
+
+    pub fn from_dir_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.dir_name() == name)
+    }
+
+    // The view `file` shows up in
+    pub fn of(file: &CachedFile) -> Self {
+        match &file.content {
+            FileContent::Remote(media) => match media.as_ref() {
+                Photo(_) => Self::Photos,
+                Sticker(_) => Self::Stickers,
+                Document(document) if is_voice(document) => Self::Voice,
+                Document(document) => by_mime(document.mime_type()).unwrap_or_else(|| by_name(&file.name)),
+                _ => Self::Documents,
+            },
+            // Contacts, as vCards
+            FileContent::Inline(_) => Self::Documents,
+            FileContent::Parts(_) => by_name(&file.name),
+        }
+    }
+}
+
+/* The files of `folder` in the view of `kind`, all in its top directory and with names
+made unique by `rules`.*/
+pub fn view_files(folder: &CachedFolder, kind: MediaKind, rules: &NamingRules) -> Vec<CachedFile> {
+    let mut files: Vec<CachedFile> = folder
+        .files
+        .iter()
+        .filter(|file| MediaKind::of(file) == kind)
+        .map(|file| CachedFile { dir: String::new(), ..file.clone() })
+        .collect();
+    dedup_names(&mut files, rules);
+    files
+}
+
+/* The views listed so far, by chat folder inode and media type, with the version of the
+folder they were made from, see `CachedFolder`. Numbering the names of a view looks at every
+file of the chat, so it is done again only once the folder changes.*/
+#[derive(Default)]
+pub struct ViewCache(Mutex<HashMap<(u64, MediaKind), CachedView>>);
+
+// The version of a chat folder and its files in one view
+type CachedView = (u64, Arc<Vec<CachedFile>>);
+
+impl ViewCache {
+    // The files of `folder` in the view of `kind`, see `view_files`
+    pub fn files(&self, folder: &CachedFolder, kind: MediaKind, rules: &NamingRules) -> Arc<Vec<CachedFile>> {
+        let mut views = self.0.lock().unwrap();
+        match views.get(&(folder.ino, kind)) {
+            Some((version, files)) if *version == folder.version => files.clone(),
+            _ => {
+                let files = Arc::new(view_files(folder, kind, rules));
+                views.insert((folder.ino, kind), (folder.version, files.clone()));
+                files
+            }
+        }
+    }
+}
+
+// Whether `document` was recorded as a voice message
+fn is_voice(document: &TgDocument) -> bool {
+    if document.raw.voice {
+        return true;
+    }
+    let Some(tl::enums::Document::Document(raw)) = &document.raw.document else {
+        return false;
+    };
+    raw.attributes.iter().any(|attr| matches!(attr, tl::enums::DocumentAttribute::Audio(audio) if audio.voice))
+}
+
+// The view for the MIME type `mime`, None if the type tells nothing about the content
+fn by_mime(mime: Option<&str>) -> Option<MediaKind> {
+    let essence = mime_essence(mime?);
+    let kind = match essence.split_once('/').map(|(top, _)| top) {
+        Some("image") => MediaKind::Photos,
+        Some("video") => MediaKind::Videos,
+        Some("audio") => MediaKind::Audio,
+        _ if essence.is_empty() || essence == "application/octet-stream" => return None,
+        _ => MediaKind::Documents,
+    };
+    Some(kind)
+}
+
+// The view for a file named `name`, from the MIME type its extension suggests
+fn by_name(name: &str) -> MediaKind {
+    let guessed = mime_guess::from_path(name).first_raw();
+    by_mime(guessed).unwrap_or(MediaKind::Documents)
+}
@@ -0,0 +1,155 @@
+//! Bounded in-memory cache for blocks of file content.
+//!
+//! Sits in front of the on-disk block cache so hot blocks are served without
+//! touching the disk. File metadata is not stored here and stays resident in
+//! the chat cache; only content blocks count towards the memory budget. When the
+//! budget is exceeded, blocks are evicted according to the configured policy.
+//! Hits, misses and evictions are counted so the budget can be tuned.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::block_cache::BlockKey;
+
+/* Which block to drop when the memory budget is exceeded:
+- `Lru` evicts the block that was used least recently
+- `Lfu` evicts the block that was used least often, oldest first on ties*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    Lru,
+    Lfu,
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "lru" => Ok(Self::Lru),
+            "lfu" => Ok(Self::Lfu),
+            _ => Err(format!("unknown eviction policy {s:?}, expected lru or lfu")),
+        }
+    }
+}
+
+// Counters describing how well the cache performs
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lookups = self.hits + self.misses;
+        let ratio = if lookups == 0 { 0.0 } else { self.hits as f64 * 100.0 / lookups as f64 };
+        write!(
+            f,
+            "{} hits, {} misses ({ratio:.1}% hit ratio), {} evictions",
+            self.hits, self.misses, self.evictions
+        )
+    }
+}
+
+struct Entry {
+    data: Arc<Vec<u8>>,
+    last_use: u64,
+    uses: u64,
+}
+
+pub struct MemoryCache {
+    budget: u64,
+    used: u64,
+    policy: EvictionPolicy,
+    entries: HashMap<BlockKey, Entry>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+impl MemoryCache {
+    // Create an empty cache holding at most `budget` bytes of content
+    pub fn new(budget: u64, policy: EvictionPolicy) -> Self {
+        Self {
+            budget,
+            used: 0,
+            policy,
+            entries: HashMap::new(),
+            tick: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    // Return the block stored under `key`, counting the lookup as a hit or a miss
+    pub fn get(&mut self, key: BlockKey) -> Option<Arc<Vec<u8>>> {
+        self.tick += 1;
+        match self.entries.get_mut(&key) {
+            Some(entry) => {
+                entry.last_use = self.tick;
+                entry.uses += 1;
+                self.stats.hits += 1;
+                Some(Arc::clone(&entry.data))
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    // Store `data` under `key`, evicting other blocks if the budget would be exceeded
+    pub fn put(&mut self, key: BlockKey, data: Arc<Vec<u8>>) {
+        let size = data.len() as u64;
+        if size > self.budget {
+            return;
+        }
+
+        self.tick += 1;
+        if let Some(old) = self.entries.remove(&key) {
+            self.used -= old.data.len() as u64;
+        }
+        while self.used + size > self.budget && self.evict_one() {}
+
+        self.used += size;
+        self.entries.insert(key, Entry { data, last_use: self.tick, uses: 1 });
+    }
+
+    // Drop all blocks of the file carried by message `message_id` in chat `chat_id`
+    pub fn remove_message(&mut self, chat_id: i64, message_id: i32) {
+        let used = &mut self.used;
+        self.entries.retain(|key, entry| {
+            let stale = key.chat_id == chat_id && key.message_id == message_id;
+            if stale {
+                *used -= entry.data.len() as u64;
+            }
+            !stale
+        });
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    // Amount of content currently held, in bytes
+    pub fn used(&self) -> u64 {
+        self.used
+    }
+
+    // Drop the block chosen by the eviction policy, returning false if there is none
+    fn evict_one(&mut self) -> bool {
+        let victim = match self.policy {
+            EvictionPolicy::Lru => self.entries.iter().min_by_key(|(_, e)| e.last_use),
+            EvictionPolicy::Lfu => self.entries.iter().min_by_key(|(_, e)| (e.uses, e.last_use)),
+        };
+        let Some((&key, _)) = victim else {
+            return false;
+        };
+
+        let entry = self.entries.remove(&key).unwrap();
+        self.used -= entry.data.len() as u64;
+        self.stats.evictions += 1;
+        true
+    }
+}
@@ -0,0 +1,111 @@
+//! Filesystem metadata stored in message captions.
+//!
+//! Anything the filesystem needs to remember about a file that Telegram has no field
+//! for (like a name set by `mv`) is kept in the caption of its message, so every mount
+//! of the account sees the same thing. The metadata is the last line of the caption:
+//!
+//! ```text
+//! Whatever the sender wrote stays untouched
+//! tgfs: name=Quarterly%20report.pdf
+//! ```
+//!
+//! Values are percent-encoded where they contain whitespace, `%` or `=`.
+
+// Marks the caption line that holds the metadata
+const PREFIX: &str = "tgfs:";
+
+/* The metadata of one message, as key/value pairs in the order they appear.
+`text` is the rest of the caption, which is kept as it is when the metadata changes.*/
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Meta {
+    pub text: String,
+    fields: Vec<(String, String)>,
+}
+
+impl Meta {
+    // Split a caption into the text written by the sender and the metadata line, if any
+    pub fn parse(caption: &str) -> Self {
+        let (text, line) = match caption.rsplit_once('\n') {
+            Some((text, line)) if line.starts_with(PREFIX) => (text, line),
+            None if caption.starts_with(PREFIX) => ("", caption),
+            _ => return Self { text: caption.to_string(), fields: vec![] },
+        };
+
+        let fields = line[PREFIX.len()..]
+            .split_whitespace()
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(key, value)| (key.to_string(), decode(value)))
+            .collect();
+        Self { text: text.to_string(), fields }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.fields.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    // Set `key` to `value`, or remove it if `value` is None
+    pub fn set(&mut self, key: &str, value: Option<&str>) {
+        let position = self.fields.iter().position(|(k, _)| k == key);
+        match (position, value) {
+            (Some(i), Some(value)) => self.fields[i].1 = value.to_string(),
+            (None, Some(value)) => self.fields.push((key.to_string(), value.to_string())),
+            (Some(i), None) => {
+                self.fields.remove(i);
+            }
+            (None, None) => {}
+        }
+    }
+
+    // The caption carrying the text and the metadata
+    pub fn to_caption(&self) -> String {
+        if self.fields.is_empty() {
+            return self.text.clone();
+        }
+        let line: Vec<String> = self.fields.iter().map(|(k, v)| format!("{k}={}", encode(v))).collect();
+        if self.text.is_empty() {
+            format!("{PREFIX} {}", line.join(" "))
+        } else {
+            format!("{}\n{PREFIX} {}", self.text, line.join(" "))
+        }
+    }
+}
+
+// Percent-encode the characters that would break the `key=value` syntax
+pub fn encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c.is_whitespace() || c == '%' || c == '=' {
+            let mut buf = [0; 4];
+            for byte in c.encode_utf8(&mut buf).bytes() {
+                encoded.push_str(&format!("%{byte:02X}"));
+            }
+        } else {
+            encoded.push(c);
+        }
+    }
+    encoded
+}
+
+// Undo `encode`; malformed escapes are kept as they are
+pub fn decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| value.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
@@ -0,0 +1,283 @@
+//! File names for media in a chat folder.
+//!
+//! A name given by `mv` wins, whether it is stored in the caption or locally.
+//! Otherwise documents keep the name they were uploaded with (their DocumentAttributeFilename).
+//! Media without one is named after its message, `msg-<id>.<ext>`, with the extension
+//! taken from a table of common MIME types and falling back to `mime_guess`.
+//! Names longer than the 255 bytes a file name may have are cut short before their extension.
+//! When several messages in one directory of a chat end up with the same name, the oldest message
+//! keeps it and the others get a numbered suffix, e.g. `report (2).pdf`.
+//! Both patterns can be changed in the `[naming]` section of the configuration, see `NamingRules`.
+
+use std::collections::HashSet;
+
+use grammers_client::types::Media::{self, Contact, Document, Photo, Sticker};
+
+use crate::{CachedFile, FileContent};
+
+// Default pattern for media without a name, see `NamingRules`
+const DEFAULT_UNNAMED: &str = "msg-{id}";
+
+// Default pattern for files whose name is taken, see `NamingRules`
+const DEFAULT_NUMBERED: &str = "{stem} ({n})";
+
+// Longest file name the kernel accepts, in bytes
+pub const MAX_NAME_LEN: usize = 255;
+
+// Longest extension kept when a name is cut short; a longer one is cut like the rest of the name
+const MAX_EXTENSION_LEN: usize = 16;
+
+/* How files are named when their message does not name them:
+- `unnamed` names media without a name; `{id}` is replaced by the message id, and the
+  extension for the media type is appended
+- `numbered` renames files whose name is already taken in their directory; `{stem}` is
+  replaced by the name without its extension, `{n}` by a number counting from 2, and the
+  extension is kept*/
+#[derive(Clone, Debug)]
+pub struct NamingRules {
+    unnamed: String,
+    numbered: String,
+}
+
+impl NamingRules {
+    /* Rules from the given patterns, the defaults for those that are None.
+    Fails if a pattern lacks its placeholder, as names would not be unique, or has a `/`.*/
+    pub fn new(unnamed: Option<&str>, numbered: Option<&str>) -> Result<Self, String> {
+        let unnamed = unnamed.unwrap_or(DEFAULT_UNNAMED);
+        let numbered = numbered.unwrap_or(DEFAULT_NUMBERED);
+        if !unnamed.contains("{id}") || unnamed.contains('/') {
+            return Err(format!("unnamed = {unnamed:?}, expected a name containing {{id}}"));
+        }
+        if !numbered.contains("{n}") || numbered.contains('/') {
+            return Err(format!("numbered = {numbered:?}, expected a name containing {{n}}"));
+        }
+        Ok(Self { unnamed: unnamed.to_string(), numbered: numbered.to_string() })
+    }
+
+    // Name of media without a name in message `message_id`, before its extension
+    fn unnamed(&self, message_id: i32) -> String {
+        self.unnamed.replace("{id}", &message_id.to_string())
+    }
+
+    // The `n`th name for files named `stem` followed by `ext`
+    fn numbered(&self, stem: &str, ext: &str, n: u32) -> String {
+        format!("{}{ext}", self.numbered.replace("{stem}", stem).replace("{n}", &n.to_string()))
+    }
+}
+
+/* Preferred extensions for common MIME types.
+`mime_guess` knows many more types, but lists their extensions alphabetically,
+which would turn `image/jpeg` into `.jfif`, so the usual ones are listed here.*/
+const MIME_EXTENSIONS: &[(&str, &str)] = &[
+    ("application/msword", "doc"),
+    ("application/octet-stream", "bin"),
+    ("application/pdf", "pdf"),
+    ("application/vnd.ms-excel", "xls"),
+    ("application/vnd.ms-powerpoint", "ppt"),
+    ("application/vnd.openxmlformats-officedocument.presentationml.presentation", "pptx"),
+    ("application/vnd.openxmlformats-officedocument.spreadsheetml.sheet", "xlsx"),
+    ("application/vnd.openxmlformats-officedocument.wordprocessingml.document", "docx"),
+    ("application/x-7z-compressed", "7z"),
+    ("application/x-bad-tgsticker", "tgs"),
+    ("application/x-rar-compressed", "rar"),
+    ("application/x-tgsticker", "tgs"),
+    ("application/zip", "zip"),
+    ("audio/mp4", "m4a"),
+    ("audio/mpeg", "mp3"),
+    ("audio/ogg", "ogg"),
+    ("image/gif", "gif"),
+    ("image/heic", "heic"),
+    ("image/jpeg", "jpg"),
+    ("image/png", "png"),
+    ("image/svg+xml", "svg"),
+    ("image/webp", "webp"),
+    ("text/plain", "txt"),
+    ("text/x-vcard", "vcf"),
+    ("video/mp4", "mp4"),
+    ("video/quicktime", "mov"),
+    ("video/webm", "webm"),
+    ("video/x-matroska", "mkv"),
+];
+
+/* Name of the file for the media carried by message `message_id`.
+`renamed` is the name the file was given by `mv`, if any. Otherwise the original
+document name is used when the document has one; everything else is named after the message
+following `rules`.*/
+pub fn file_name(message_id: i32, content: &FileContent, renamed: Option<&str>, rules: &NamingRules) -> String {
+    if let Some(name) = renamed.and_then(sanitize) {
+        return name;
+    }
+    match content {
+        FileContent::Remote(media) => {
+            if let Some(name) = document_name(media) {
+                return name;
+            }
+            format!("{}{}", rules.unnamed(message_id), get_file_extension(media))
+        }
+        FileContent::Inline(_) => format!("{}.vcf", rules.unnamed(message_id)),
+        // Manifests always carry the name of the file they were split from
+        FileContent::Parts(_) => format!("{}.bin", rules.unnamed(message_id)),
+    }
+}
+
+// The name a document was uploaded with, if it has a usable one
+pub fn document_name(media: &Media) -> Option<String> {
+    match media {
+        Document(document) => sanitize(document.name()),
+        _ => None,
+    }
+}
+
+/* Make the names of `files` unique within their directory.
+Files are processed from the oldest message to the newest, so the oldest
+file keeps its name and the result does not depend on listing order.*/
+pub fn dedup_names(files: &mut [CachedFile], rules: &NamingRules) {
+    files.sort_by_key(|f| f.message_id);
+
+    let mut taken = HashSet::new();
+    for file in files.iter_mut() {
+        if taken.insert((file.dir.clone(), file.name.clone())) {
+            continue;
+        }
+
+        let (stem, ext) = split_extension(&file.name);
+        let mut n = 2;
+        let name = loop {
+            let mut candidate = rules.numbered(stem, ext, n);
+            if candidate.len() > MAX_NAME_LEN {
+                let stem = cut(stem, stem.len().saturating_sub(candidate.len() - MAX_NAME_LEN));
+                candidate = rules.numbered(stem, ext, n);
+            }
+            if taken.insert((file.dir.clone(), candidate.clone())) {
+                break candidate;
+            }
+            n += 1;
+        };
+        file.name = name;
+    }
+}
+
+pub fn get_file_extension(media: &Media) -> String {
+    match media {
+        Photo(_) => ".jpg".to_string(), // If the media is a photo, use .jpg extension
+        Sticker(sticker) => get_mime_extension(sticker.document.mime_type()), // If it's a sticker, determine extension from MIME type (e.g., .webp)
+        Document(document) => get_mime_extension(document.mime_type()), // If it's a document, extract extension from its MIME type
+        Contact(_) => ".vcf".to_string(),  // If it's a contact (vCard), use .vcf extension
+        _ => String::new(), // For all other media types, return an empty string (no extension)
+    }
+}
+
+// The MIME type `mime` without its parameters (`text/plain; charset=utf-8`), in lower case as types are case-insensitive
+pub fn mime_essence(mime: &str) -> String {
+    mime.split(';').next().unwrap_or("").trim().to_ascii_lowercase()
+}
+
+pub fn get_mime_extension(mime_type: Option<&str>) -> String {
+    let Some(essence) = mime_type.map(mime_essence) else {
+        return ".bin".to_string(); // If the MIME type is missing, default to ".bin"
+    };
+
+    MIME_EXTENSIONS
+        .iter()
+        .find(|(mime, _)| *mime == essence)
+        .map(|(_, ext)| *ext)
+        .or_else(|| mime_guess::get_mime_extensions_str(&essence).and_then(|exts| exts.first().copied()))
+        .map(|ext| format!(".{ext}"))
+        .unwrap_or(".bin".to_string()) // Unknown MIME types also get ".bin"
+}
+
+/* Turn a name sent by Telegram, of a document, chat or topic, into a valid file name, or None if
+nothing usable is left: `/` becomes `_`, NUL is dropped and other control characters become
+spaces, whitespace around the name is trimmed, and a name longer than `MAX_NAME_LEN` is cut
+short before its extension.*/
+pub fn sanitize(name: &str) -> Option<String> {
+    let name: String = name
+        .chars()
+        .filter(|&c| c != '\0')
+        .map(|c| match c {
+            '/' => '_',
+            c if c.is_control() => ' ',
+            c => c,
+        })
+        .collect();
+    let name = name.trim();
+    if name.is_empty() || name == "." || name == ".." {
+        return None;
+    }
+    if name.len() <= MAX_NAME_LEN {
+        return Some(name.to_string());
+    }
+    let (stem, ext) = match split_extension(name) {
+        (stem, ext) if ext.len() <= MAX_EXTENSION_LEN => (stem, ext),
+        _ => (name, ""),
+    };
+    Some(format!("{}{ext}", cut(stem, MAX_NAME_LEN - ext.len())))
+}
+
+// The longest start of `text` that is at most `max_len` bytes long and ends on a character boundary
+pub fn cut(text: &str, max_len: usize) -> &str {
+    let end = (0..=max_len.min(text.len())).rev().find(|&i| text.is_char_boundary(i)).unwrap_or(0);
+    &text[..end]
+}
+
+// Split `report.pdf` into `report` and `.pdf`; dot files like `.bashrc` have no extension
+fn split_extension(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(i) if i > 0 => name.split_at(i),
+        _ => (name, ""),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_names_keep_their_extension() {
+        let name = sanitize(&format!("{}.pdf", "a".repeat(300))).unwrap();
+        assert_eq!(name.len(), MAX_NAME_LEN);
+        assert!(name.ends_with("aaa.pdf"));
+
+        // Cut between characters, never inside one
+        let name = sanitize(&format!("{}.txt", "ж".repeat(200))).unwrap();
+        assert_eq!(name, format!("{}.txt", "ж".repeat(125)));
+
+        // An extension that long is a part of the name like any other
+        let name = sanitize(&format!("report.{}", "x".repeat(300))).unwrap();
+        assert_eq!(name, format!("report.{}", "x".repeat(248)));
+
+        let name = "b".repeat(MAX_NAME_LEN);
+        assert_eq!(sanitize(&name), Some(name));
+    }
+
+    #[test]
+    fn names_cut_alike_are_numbered() {
+        let file = |message_id: i32, name: String| CachedFile {
+            ino: message_id as u64,
+            name,
+            chat_id: 1,
+            message_id,
+            dir: String::new(),
+            outgoing: true,
+            content: FileContent::Inline(Default::default()),
+            sealed_size: None,
+            digest: None,
+            attr: crate::file_attr(message_id as u64, 0, (std::time::UNIX_EPOCH, std::time::UNIX_EPOCH), crate::Owner { uid: 0, gid: 0 }),
+        };
+        let long = "a".repeat(300);
+        let mut files = vec![
+            file(1, sanitize(&format!("{long}1.pdf")).unwrap()),
+            file(2, sanitize(&format!("{long}2.pdf")).unwrap()),
+        ];
+        dedup_names(&mut files, &NamingRules::new(None, None).unwrap());
+        assert_eq!(files[0].name, format!("{}.pdf", "a".repeat(251)));
+        assert_eq!(files[1].name, format!("{} (2).pdf", "a".repeat(247)));
+    }
+
+    #[test]
+    fn cut() {
+        assert_eq!(super::cut("abc", 5), "abc");
+        assert_eq!(super::cut("aж", 2), "a");
+        assert_eq!(super::cut("aж", 3), "aж");
+    }
+}
@@ -0,0 +1,149 @@
+//! Shell globs and regular expressions for choosing chats by name in `[chats]`.
+//!
+//! Globs match the whole name: `*` stands for any text, `?` for one character,
+//! `[abc]`, `[a-z]` or `[!abc]` for one character of a set, and `{a,b}` for one of several
+//! alternatives; `\` takes the next character literally.
+//!
+//! Regular expressions match anywhere in the name unless anchored with `^` and `$`, in the
+//! syntax of the `regex` crate: character classes, groups, alternation, repetitions such as
+//! `{n,m}`, and flags like a leading `(?i)` to ignore case. Backreferences and lookaround are
+//! not supported. Matching takes time linear in the length of the name, whatever the pattern.
+
+use globset::{GlobBuilder, GlobMatcher};
+use serde::Deserialize;
+
+// A regular expression, see the module documentation
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Regex(regex::Regex);
+
+// A glob, matched against the whole name
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Glob(GlobMatcher);
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Self, String> {
+        regex::Regex::new(pattern).map(Self).map_err(|e| format!("invalid regex {pattern:?}: {e}"))
+    }
+
+    // Whether the expression matches anywhere in `text`
+    pub fn is_match(&self, text: &str) -> bool {
+        self.0.is_match(text)
+    }
+}
+
+impl TryFrom<String> for Regex {
+    type Error = String;
+
+    fn try_from(pattern: String) -> Result<Self, String> {
+        Self::new(&pattern)
+    }
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Result<Self, String> {
+        // Chat names are not paths, so `*` matches `/` as well
+        let glob = GlobBuilder::new(pattern).literal_separator(false).backslash_escape(true).build();
+        glob.map(|glob| Self(glob.compile_matcher())).map_err(|e| format!("invalid glob {pattern:?}: {}", e.kind()))
+    }
+
+    // Whether the glob matches all of `text`
+    pub fn is_match(&self, text: &str) -> bool {
+        self.0.is_match(text)
+    }
+}
+
+impl TryFrom<String> for Glob {
+    type Error = String;
+
+    fn try_from(pattern: String) -> Result<Self, String> {
+        Self::new(&pattern)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob(pattern: &str) -> Glob {
+        Glob::new(pattern).unwrap()
+    }
+
+    fn regex(pattern: &str) -> Regex {
+        Regex::new(pattern).unwrap()
+    }
+
+    #[test]
+    fn globs_match_the_whole_name() {
+        assert!(glob("Work*").is_match("Work chat"));
+        assert!(!glob("Work").is_match("Work chat"));
+        assert!(glob("*chat").is_match("Work / chat"));
+        assert!(glob("Team ?").is_match("Team A"));
+        assert!(!glob("Team ?").is_match("Team AB"));
+    }
+
+    #[test]
+    fn glob_sets() {
+        assert!(glob("Team [A-C]").is_match("Team B"));
+        assert!(!glob("Team [A-C]").is_match("Team D"));
+        assert!(glob("Team [!A-C]").is_match("Team D"));
+        assert!(!glob("Team [!A-C]").is_match("Team A"));
+        assert!(glob("[]]").is_match("]"));
+        assert!(glob("a[-x]").is_match("a-"));
+        assert!(glob("{News,Blog} *").is_match("Blog posts"));
+    }
+
+    #[test]
+    fn glob_escapes() {
+        assert!(glob(r"What\?").is_match("What?"));
+        assert!(!glob(r"What\?").is_match("Whats"));
+        assert!(glob(r"\*").is_match("*"));
+        assert!(Glob::new("[abc").unwrap_err().starts_with("invalid glob \"[abc\""));
+    }
+
+    #[test]
+    fn regexes_match_anywhere() {
+        assert!(regex("chat").is_match("Work chat 2"));
+        assert!(!regex("^chat").is_match("Work chat"));
+        assert!(regex("^Work").is_match("Work chat"));
+        assert!(regex("2$").is_match("Work chat 2"));
+        assert!(!regex("^Work$").is_match("Work chat"));
+    }
+
+    #[test]
+    fn regex_classes_and_repetitions() {
+        assert!(regex(r"^\d{3}$").is_match("123"));
+        assert!(!regex(r"^\d{3}$").is_match("1234"));
+        assert!(regex("^a{2,3}$").is_match("aaa"));
+        assert!(!regex("^a{2,3}$").is_match("aaaa"));
+        assert!(regex("^a{2,}$").is_match("aaaaa"));
+        assert!(regex("^[a-c]+[^0-9]$").is_match("abcx"));
+        assert!(!regex("^[a-c]+[^0-9]$").is_match("abc1"));
+        assert!(regex(r"^\w+\s\W$").is_match("team !"));
+        assert!(regex("^(?:news|blog)-(en|de)$").is_match("blog-de"));
+    }
+
+    #[test]
+    fn regex_ignoring_case() {
+        assert!(regex("(?i)^work").is_match("WORK chat"));
+        assert!(!regex("^work").is_match("WORK chat"));
+    }
+
+    #[test]
+    fn regex_errors() {
+        assert!(Regex::new("(a").unwrap_err().starts_with("invalid regex \"(a\""));
+        assert!(Regex::new("a{2,1}").is_err());
+        assert!(Regex::new(r"(a)\1").is_err());
+        // Too large to compile, rather than too slow to run
+        assert!(Regex::new("((a{1000}){1000}){1000}").is_err());
+    }
+
+    #[test]
+    fn pathological_regexes_finish() {
+        let text = format!("{}!", "a".repeat(10_000));
+        assert!(!regex("^(a+)+$").is_match(&text));
+        assert!(!regex("^(a|a)*b").is_match(&text));
+        assert!(!regex("(a*)*b").is_match(&text));
+    }
+}
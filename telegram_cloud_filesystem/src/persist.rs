@@ -0,0 +1,21 @@
+//! Replacing files on disk in one step.
+//!
+//! The inode table, the local names and the blocks of the block cache are written through
+//! `write`: the new contents go to a temporary file with a name of its own in the same
+//! directory, which then takes the place of the old file. A crash while writing leaves the
+//! old file intact, and files written at the same time, such as the inode table on the
+//! updater task and the local names on the filesystem thread, never share a temporary file.
+
+use std::io::{self, Write};
+use std::path::Path;
+
+use tempfile::NamedTempFile;
+
+// Replace the file at `path` with `contents`
+pub fn write(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let mut tmp = NamedTempFile::new_in(dir)?;
+    tmp.write_all(contents.as_ref())?;
+    tmp.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
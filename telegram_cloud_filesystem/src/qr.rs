@@ -0,0 +1,524 @@
+//! A minimal QR code encoder for printing login links in the terminal.
+//!
+//! Only what `login` needs is supported: byte mode at error correction level L in versions
+//! 1 to 6, which holds up to 134 bytes (a `tg://login` link takes about 60). Codes are
+//! drawn with half block characters, two rows of modules per line of text.
+
+// Data codewords, error correction codewords per block and number of blocks of versions 1 to 6 at level L
+const DATA_CODEWORDS: [usize; 6] = [19, 34, 55, 80, 108, 136];
+const ECC_PER_BLOCK: [usize; 6] = [7, 10, 15, 20, 26, 18];
+const BLOCKS: [usize; 6] = [1, 1, 1, 1, 1, 2];
+
+// Modules of light margin drawn around the code, as many as the standard asks for
+const QUIET_ZONE: usize = 4;
+
+pub struct QrCode {
+    size: usize,
+    modules: Vec<Vec<bool>>,
+}
+
+impl QrCode {
+    // Encode `data` in the smallest version it fits in, None if it is too long
+    pub fn encode(data: &[u8]) -> Option<Self> {
+        let version = (1..=DATA_CODEWORDS.len()).find(|&v| data.len() + 2 <= DATA_CODEWORDS[v - 1])?;
+        let codewords = add_ecc(&data_codewords(data, DATA_CODEWORDS[version - 1]), version);
+
+        let size = 17 + 4 * version;
+        let mut code = Self { size, modules: vec![vec![false; size]; size] };
+        let mut function = vec![vec![false; size]; size];
+        code.draw_function_patterns(version, &mut function);
+        code.draw_codewords(&codewords, &function);
+
+        // Keep the mask that leaves the fewest patterns confusing to readers
+        let mut best = None;
+        for mask in 0..8 {
+            let mut candidate = Self { size, modules: code.modules.clone() };
+            candidate.apply_mask(mask, &function);
+            candidate.draw_format(mask);
+            let penalty = candidate.penalty();
+            if best.as_ref().is_none_or(|(lowest, _)| penalty < *lowest) {
+                best = Some((penalty, candidate));
+            }
+        }
+        best.map(|(_, code)| code)
+    }
+
+    /* The code as text: dark modules are blank and light ones are filled, so that it reads
+    as dark on light in a terminal with light text on a dark background.*/
+    pub fn render(&self) -> String {
+        let span = self.size + 2 * QUIET_ZONE;
+        let light = |x: usize, y: usize| {
+            let inside = (QUIET_ZONE..QUIET_ZONE + self.size).contains(&x) && (QUIET_ZONE..QUIET_ZONE + self.size).contains(&y);
+            !inside || !self.modules[y - QUIET_ZONE][x - QUIET_ZONE]
+        };
+        let mut text = String::new();
+        for y in (0..span).step_by(2) {
+            for x in 0..span {
+                let lower = y + 1 < span && light(x, y + 1);
+                text.push(match (light(x, y), lower) {
+                    (true, true) => '█',
+                    (true, false) => '▀',
+                    (false, true) => '▄',
+                    (false, false) => ' ',
+                });
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    fn set(&mut self, function: &mut [Vec<bool>], x: usize, y: usize, dark: bool) {
+        self.modules[y][x] = dark;
+        function[y][x] = true;
+    }
+
+    // Finder, separator, alignment and timing patterns, and the space for format information
+    fn draw_function_patterns(&mut self, version: usize, function: &mut [Vec<bool>]) {
+        let size = self.size;
+        for i in 0..size {
+            self.set(function, 6, i, i % 2 == 0);
+            self.set(function, i, 6, i % 2 == 0);
+        }
+        for (cx, cy) in [(3, 3), (size - 4, 3), (3, size - 4)] {
+            for dy in -4i32..=4 {
+                for dx in -4i32..=4 {
+                    let (x, y) = (cx as i32 + dx, cy as i32 + dy);
+                    if (0..size as i32).contains(&x) && (0..size as i32).contains(&y) {
+                        let ring = dx.abs().max(dy.abs());
+                        self.set(function, x as usize, y as usize, ring != 2 && ring != 4);
+                    }
+                }
+            }
+        }
+        if version >= 2 {
+            let center = 4 * version + 10;
+            for dy in -2i32..=2 {
+                for dx in -2i32..=2 {
+                    let ring = dx.abs().max(dy.abs());
+                    self.set(function, (center as i32 + dx) as usize, (center as i32 + dy) as usize, ring != 1);
+                }
+            }
+        }
+        // Reserve the format areas now, they are written once the mask is known
+        function[8][..9].fill(true);
+        function[8][size - 8..].fill(true);
+        for (y, row) in function.iter_mut().enumerate() {
+            if y < 9 || y >= size - 8 {
+                row[8] = true;
+            }
+        }
+    }
+
+    // Place the codewords in the zigzag order, upwards and downwards in columns of two
+    fn draw_codewords(&mut self, codewords: &[u8], function: &[Vec<bool>]) {
+        let size = self.size as i32;
+        let mut bit = 0;
+        let mut right = size - 1;
+        while right >= 1 {
+            if right == 6 {
+                right = 5;
+            }
+            for vertical in 0..size {
+                for j in 0..2 {
+                    let x = (right - j) as usize;
+                    let upward = (right + 1) & 2 == 0;
+                    let y = (if upward { size - 1 - vertical } else { vertical }) as usize;
+                    if !function[y][x] && bit < codewords.len() * 8 {
+                        self.modules[y][x] = (codewords[bit / 8] >> (7 - bit % 8)) & 1 == 1;
+                        bit += 1;
+                    }
+                }
+            }
+            right -= 2;
+        }
+    }
+
+    fn apply_mask(&mut self, mask: usize, function: &[Vec<bool>]) {
+        for (y, row) in function.iter().enumerate() {
+            for (x, &fixed) in row.iter().enumerate() {
+                let invert = match mask {
+                    0 => (x + y) % 2 == 0,
+                    1 => y % 2 == 0,
+                    2 => x % 3 == 0,
+                    3 => (x + y) % 3 == 0,
+                    4 => (x / 3 + y / 2) % 2 == 0,
+                    5 => x * y % 2 + x * y % 3 == 0,
+                    6 => (x * y % 2 + x * y % 3) % 2 == 0,
+                    _ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+                };
+                if invert && !fixed {
+                    self.modules[y][x] ^= true;
+                }
+            }
+        }
+    }
+
+    // The level and mask, protected by a BCH code, in both copies, and the dark module
+    fn draw_format(&mut self, mask: usize) {
+        let bits = format_bits(mask);
+        let bit = |i: usize| (bits >> i) & 1 == 1;
+        let size = self.size;
+
+        for i in 0..6 {
+            self.modules[i][8] = bit(i);
+        }
+        self.modules[7][8] = bit(6);
+        self.modules[8][8] = bit(7);
+        self.modules[8][7] = bit(8);
+        for i in 9..15 {
+            self.modules[8][14 - i] = bit(i);
+        }
+        for i in 0..8 {
+            self.modules[8][size - 1 - i] = bit(i);
+        }
+        for i in 8..15 {
+            self.modules[size - 15 + i][8] = bit(i);
+        }
+        self.modules[size - 8][8] = true;
+    }
+
+    // The penalty score of the standard, lower is easier to read
+    fn penalty(&self) -> usize {
+        let size = self.size;
+        let at = |x: usize, y: usize, transposed: bool| if transposed { self.modules[x][y] } else { self.modules[y][x] };
+        let finder = [true, false, true, true, true, false, true];
+        let mut penalty = 0;
+
+        for transposed in [false, true] {
+            for y in 0..size {
+                // Runs of five or more modules of one color
+                let mut run = 1;
+                for x in 1..=size {
+                    if x < size && at(x, y, transposed) == at(x - 1, y, transposed) {
+                        run += 1;
+                        continue;
+                    }
+                    if run >= 5 {
+                        penalty += run - 2;
+                    }
+                    run = 1;
+                }
+                // Patterns looking like a finder, with four light modules on one side
+                for x in 0..=size - 7 {
+                    if (0..7).any(|i| at(x + i, y, transposed) != finder[i]) {
+                        continue;
+                    }
+                    let light = |from: usize, to: usize| (from..to).all(|i| !at(i, y, transposed));
+                    if (x >= 4 && light(x - 4, x)) || (x + 11 <= size && light(x + 7, x + 11)) {
+                        penalty += 40;
+                    }
+                }
+            }
+        }
+
+        // Blocks of 2x2 modules of one color
+        for y in 0..size - 1 {
+            for x in 0..size - 1 {
+                let color = self.modules[y][x];
+                if self.modules[y][x + 1] == color && self.modules[y + 1][x] == color && self.modules[y + 1][x + 1] == color {
+                    penalty += 3;
+                }
+            }
+        }
+
+        // Imbalance of dark and light modules, per 5% away from half
+        let dark = self.modules.iter().flatten().filter(|&&dark| dark).count();
+        let total = size * size;
+        penalty + (dark * 20).abs_diff(total * 10) / total * 10
+    }
+}
+
+// The 15 format bits for level L and `mask`: the BCH-protected level and mask, XORed with the fixed pattern
+fn format_bits(mask: usize) -> u32 {
+    let data = (0b01 << 3 | mask) as u32;
+    let mut rem = data;
+    for _ in 0..10 {
+        rem = (rem << 1) ^ ((rem >> 9) * 0x537);
+    }
+    (data << 10 | rem) ^ 0x5412
+}
+
+// `data` in byte mode, terminated and padded to `capacity` codewords
+fn data_codewords(data: &[u8], capacity: usize) -> Vec<u8> {
+    let mut bits: Vec<bool> = Vec::with_capacity(capacity * 8);
+    let mut push = |value: u32, len: usize| bits.extend((0..len).rev().map(|i| (value >> i) & 1 == 1));
+    push(0b0100, 4);
+    push(data.len() as u32, 8);
+    for &byte in data {
+        push(byte as u32, 8);
+    }
+    let terminator = (capacity * 8 - bits.len()).min(4);
+    bits.extend(std::iter::repeat_n(false, terminator));
+    bits.resize(bits.len().div_ceil(8) * 8, false);
+
+    let mut codewords: Vec<u8> = bits.chunks(8).map(|byte| byte.iter().fold(0, |acc, &bit| acc << 1 | bit as u8)).collect();
+    for pad in [0xEC, 0x11].into_iter().cycle() {
+        if codewords.len() == capacity {
+            break;
+        }
+        codewords.push(pad);
+    }
+    codewords
+}
+
+// Split the data codewords into blocks, add Reed-Solomon codewords to each and interleave them
+fn add_ecc(data: &[u8], version: usize) -> Vec<u8> {
+    let ecc_len = ECC_PER_BLOCK[version - 1];
+    let blocks: Vec<&[u8]> = data.chunks(data.len() / BLOCKS[version - 1]).collect();
+    let generator = generator(ecc_len);
+    let eccs: Vec<Vec<u8>> = blocks.iter().map(|block| remainder(block, &generator)).collect();
+
+    let mut interleaved = Vec::with_capacity(data.len() + ecc_len * blocks.len());
+    for i in 0..blocks[0].len() {
+        interleaved.extend(blocks.iter().map(|block| block[i]));
+    }
+    for i in 0..ecc_len {
+        interleaved.extend(eccs.iter().map(|ecc| ecc[i]));
+    }
+    interleaved
+}
+
+// Multiply in GF(256) modulo x^8 + x^4 + x^3 + x^2 + 1
+fn multiply(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 == 1 {
+            product ^= a;
+        }
+        a = (a << 1) ^ if a & 0x80 != 0 { 0x1D } else { 0 };
+        b >>= 1;
+    }
+    product
+}
+
+// Coefficients of the generator polynomial of degree `degree`, highest first and without the leading 1
+fn generator(degree: usize) -> Vec<u8> {
+    let mut coefficients = vec![0; degree];
+    coefficients[degree - 1] = 1;
+    let mut root = 1;
+    for _ in 0..degree {
+        for i in 0..degree {
+            coefficients[i] = multiply(coefficients[i], root);
+            if i + 1 < degree {
+                coefficients[i] ^= coefficients[i + 1];
+            }
+        }
+        root = multiply(root, 2);
+    }
+    coefficients
+}
+
+// The remainder of `data` divided by the generator, which are the error correction codewords
+fn remainder(data: &[u8], generator: &[u8]) -> Vec<u8> {
+    let mut result = vec![0; generator.len()];
+    for &byte in data {
+        let factor = byte ^ result.remove(0);
+        result.push(0);
+        for (r, &g) in result.iter_mut().zip(generator) {
+            *r ^= multiply(g, factor);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The format bits of level L for masks 0 to 7, from the table of the standard
+    const FORMAT_L: [u32; 8] = [
+        0b111011111000100,
+        0b111001011110011,
+        0b111110110101010,
+        0b111100010011101,
+        0b110011000101111,
+        0b110001100011000,
+        0b110110001000001,
+        0b110100101110110,
+    ];
+
+    // Longest data of versions 1 to 6, what is left after the mode and length
+    const MAX_LEN: [usize; 6] = [17, 32, 53, 78, 106, 134];
+
+    fn version(code: &QrCode) -> usize {
+        (code.size - 17) / 4
+    }
+
+    // Value of `codewords` as a polynomial at `x`, highest coefficient first
+    fn evaluate(codewords: &[u8], x: u8) -> u8 {
+        codewords.iter().fold(0, |acc, &c| multiply(acc, x) ^ c)
+    }
+
+    // Read the format bits from the copy around the top left finder and the split one
+    fn read_format(code: &QrCode) -> (u32, u32) {
+        let (m, size) = (&code.modules, code.size);
+        let mut first = [false; 15];
+        let mut second = [false; 15];
+        for i in 0..6 {
+            first[i] = m[i][8];
+        }
+        first[6] = m[7][8];
+        first[7] = m[8][8];
+        first[8] = m[8][7];
+        for i in 9..15 {
+            first[i] = m[8][14 - i];
+        }
+        for i in 0..8 {
+            second[i] = m[8][size - 1 - i];
+        }
+        for i in 8..15 {
+            second[i] = m[size - 15 + i][8];
+        }
+        let value = |bits: [bool; 15]| bits.iter().rev().fold(0, |acc, &bit| acc << 1 | bit as u32);
+        (value(first), value(second))
+    }
+
+    /* Decode `code` independently of how it was drawn: find the mask from the format bits,
+    undo it, read the codewords, check every block against its error correction codewords
+    and return the bytes of the byte mode segment.*/
+    fn decode(code: &QrCode) -> Vec<u8> {
+        let version = version(code);
+        let (format, copy) = read_format(code);
+        assert_eq!(format, copy, "the two copies of the format bits differ");
+        let mask = FORMAT_L.iter().position(|&bits| bits == format).expect("format bits of level L");
+
+        let mut function = vec![vec![false; code.size]; code.size];
+        let mut unmasked = QrCode { size: code.size, modules: code.modules.clone() };
+        unmasked.draw_function_patterns(version, &mut function);
+        unmasked.modules = code.modules.clone();
+        unmasked.apply_mask(mask, &function);
+
+        // Columns of two from the right, skipping the vertical timing pattern, alternately upwards and downwards
+        let mut bits = vec![];
+        let columns: Vec<usize> = (0..code.size).filter(|&x| x != 6).collect();
+        for (pair, xs) in columns.rchunks(2).enumerate() {
+            let mut rows: Vec<usize> = (0..code.size).collect();
+            if pair % 2 == 0 {
+                rows.reverse();
+            }
+            for y in rows {
+                for &x in xs.iter().rev() {
+                    if !function[y][x] {
+                        bits.push(unmasked.modules[y][x]);
+                    }
+                }
+            }
+        }
+        let codewords: Vec<u8> = bits.chunks_exact(8).map(|byte| byte.iter().fold(0, |acc, &bit| acc << 1 | bit as u8)).collect();
+
+        let (blocks, ecc_len, data_len) = (BLOCKS[version - 1], ECC_PER_BLOCK[version - 1], DATA_CODEWORDS[version - 1]);
+        assert_eq!(codewords.len(), data_len + blocks * ecc_len);
+        let block_len = data_len / blocks;
+        let mut data = vec![];
+        for block in 0..blocks {
+            let mut received: Vec<u8> = (0..block_len).map(|i| codewords[i * blocks + block]).collect();
+            data.extend_from_slice(&received);
+            received.extend((0..ecc_len).map(|i| codewords[data_len + i * blocks + block]));
+            let mut root = 1;
+            for _ in 0..ecc_len {
+                assert_eq!(evaluate(&received, root), 0, "block {block} does not check out");
+                root = multiply(root, 2);
+            }
+        }
+
+        assert_eq!(data[0] >> 4, 0b0100, "byte mode");
+        let len = ((data[0] & 0x0F) << 4 | data[1] >> 4) as usize;
+        (0..len).map(|i| data[1 + i] << 4 | data[2 + i] >> 4).collect()
+    }
+
+    #[test]
+    fn format_bits_match_the_standard() {
+        for (mask, &bits) in FORMAT_L.iter().enumerate() {
+            assert_eq!(format_bits(mask), bits, "mask {mask}");
+        }
+    }
+
+    #[test]
+    fn reed_solomon_known_vectors() {
+        // "01234567" at 1-M, the example of the standard
+        let data = [16, 32, 12, 86, 97, 128, 236, 17, 236, 17, 236, 17, 236, 17, 236, 17];
+        assert_eq!(remainder(&data, &generator(10)), [165, 36, 212, 193, 237, 54, 199, 135, 44, 85]);
+        // "HELLO WORLD" at 1-M
+        let data = [32, 91, 11, 120, 209, 114, 220, 77, 67, 64, 236, 17, 236, 17, 236, 17];
+        assert_eq!(remainder(&data, &generator(10)), [196, 35, 39, 119, 235, 215, 231, 226, 93, 23]);
+    }
+
+    #[test]
+    fn byte_mode_codewords() {
+        assert_eq!(data_codewords(b"", 19), [0x40, 0x00, 0xEC, 0x11, 0xEC, 0x11, 0xEC, 0x11, 0xEC, 0x11, 0xEC, 0x11, 0xEC, 0x11, 0xEC, 0x11, 0xEC, 0x11, 0xEC]);
+        assert_eq!(data_codewords(b"a", 19)[..5], [0x40, 0x16, 0x10, 0xEC, 0x11]);
+        // A full version 1 leaves no room for the terminator
+        assert_eq!(data_codewords(&[0xFF; 17], 19)[17..], [0xFF, 0xF0]);
+    }
+
+    #[test]
+    fn round_trip_in_every_version() {
+        let samples: [&[u8]; 8] = [
+            b"",
+            b"Hello, world!",
+            "こんにちwa、世界！ αβγδ".as_bytes(),
+            b"https://www.nayuki.io/",
+            b"tg://login?token=AQIDBAUGBwgJCgsMDQ4PEBESExQVFhcYGRobHB0eHyAhIiMkJSYnKA",
+            b"Alice was beginning to get very tired of sitting by her sister on the bank, and of having nothing to do",
+            &[0; 134],
+            &[0xFF; 100],
+        ];
+        for data in samples {
+            let code = QrCode::encode(data).unwrap();
+            assert_eq!(decode(&code), data);
+        }
+        for (v, &max) in MAX_LEN.iter().enumerate() {
+            let data: Vec<u8> = (0..max).map(|i| (i * 7) as u8).collect();
+            let code = QrCode::encode(&data).unwrap();
+            assert_eq!(version(&code), v + 1, "{max} bytes");
+            assert_eq!(decode(&code), data);
+        }
+    }
+
+    #[test]
+    fn smallest_version_that_fits() {
+        for (v, &max) in MAX_LEN.iter().enumerate() {
+            assert_eq!(version(&QrCode::encode(&vec![b'x'; max]).unwrap()), v + 1);
+            if v + 1 < MAX_LEN.len() {
+                assert_eq!(version(&QrCode::encode(&vec![b'x'; max + 1]).unwrap()), v + 2);
+            }
+        }
+        assert!(QrCode::encode(&[b'x'; 135]).is_none());
+        assert!(QrCode::encode(&[b'x'; 1000]).is_none());
+    }
+
+    #[test]
+    fn function_patterns() {
+        let code = QrCode::encode(&[b'x'; 60]).unwrap();
+        let (m, size) = (&code.modules, code.size);
+        assert_eq!(size, 33);
+        for (cx, cy) in [(3, 3), (size - 4, 3), (3, size - 4)] {
+            for d in 0..=3 {
+                let dark = d != 2;
+                assert_eq!(m[cy - d][cx], dark);
+                assert_eq!(m[cy][cx + d], dark);
+            }
+        }
+        // Alignment pattern of version 4, timing patterns and the dark module
+        assert!(m[26][26] && !m[25][26] && m[24][26]);
+        assert!((8..size - 8).all(|i| m[6][i] == (i % 2 == 0) && m[i][6] == (i % 2 == 0)));
+        assert!(m[size - 8][8]);
+    }
+
+    #[test]
+    fn render_has_two_rows_per_line() {
+        let code = QrCode::encode(b"tg://login").unwrap();
+        let text = code.render();
+        let lines: Vec<&str> = text.lines().collect();
+        let span = code.size + 2 * QUIET_ZONE;
+        assert_eq!(lines.len(), span.div_ceil(2));
+        assert!(lines.iter().all(|line| line.chars().count() == span));
+        // Four light modules all around: two lines at the top, and at the bottom the last half line and one more
+        let light = |line: &&str| line.chars().all(|c| c == '█');
+        let (last, rest) = lines.split_last().unwrap();
+        assert!(lines[..2].iter().all(light) && light(&rest[rest.len() - 1]));
+        assert!(!light(&lines[2]) && !light(&rest[rest.len() - 2]));
+        assert!(last.chars().all(|c| c == '▀'));
+        assert!(rest.iter().all(|line| line.starts_with("████") && line.ends_with("████")));
+    }
+}
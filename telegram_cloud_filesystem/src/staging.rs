@@ -0,0 +1,76 @@
+//! Content of files being written through the mount.
+//!
+//! Writes are staged in a temporary file in the `staging` directory of the cache directory
+//! rather than in memory, so a file of any size only takes its size on disk, and it is
+//! uploaded from there part by part when it is closed. Encrypted files are sealed into a
+//! second temporary file, see the `crypt` module. Growing a file without writing to it (as
+//! `truncate -s` does) leaves a hole that takes no space. The temporary files have no name,
+//! so they go away with the daemon, even if it crashes.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+// Staged content: a temporary file and how much of it is the file's content
+pub struct Staged {
+    file: File,
+    len: u64,
+}
+
+impl Staged {
+    // A new, empty file in the directory `dir`
+    pub fn new(dir: &Path) -> io::Result<Self> {
+        Ok(Self { file: tempfile::tempfile_in(dir)?, len: 0 })
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    // Store `data` at `offset`; a gap left before it reads as zeros
+    pub fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.file.write_all_at(data, offset)?;
+        self.len = self.len.max(offset + data.len() as u64);
+        Ok(())
+    }
+
+    // Grow or cut the content to `size` bytes, growing it with zeros
+    pub fn set_len(&mut self, size: u64) -> io::Result<()> {
+        self.file.set_len(size)?;
+        self.len = size;
+        Ok(())
+    }
+
+    // Up to `size` bytes of the content at `offset`
+    pub fn read_at(&self, offset: u64, size: u64) -> io::Result<Vec<u8>> {
+        let size = size.min(self.len.saturating_sub(offset));
+        let mut buf = vec![0; size as usize];
+        self.file.read_exact_at(&mut buf, offset)?;
+        Ok(buf)
+    }
+
+    // The content from the start, as a reader of its own
+    pub fn reader(&self) -> io::Result<impl Read> {
+        Ok(self.at(0)?.take(self.len))
+    }
+
+    // A handle on the file positioned at `offset`; handles share their position, so one is used at a time
+    pub fn at(&self, offset: u64) -> io::Result<File> {
+        let mut file = self.file.try_clone()?;
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(file)
+    }
+}
+
+// Appending, for filling a staged file from a stream
+impl Write for Staged {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.write_at(self.len, data)?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
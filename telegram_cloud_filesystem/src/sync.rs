@@ -0,0 +1,768 @@
+//! Keeping the chat cache in sync with Telegram.
+//!
+//! On startup every dialog is scanned once, recording the newest message id of each chat.
+//! From then on the cache follows Telegram's update stream: new, edited and deleted
+//! messages are applied as soon as they arrive, so an idle mount makes almost no API
+//! calls. Every refresh interval a reconciliation pass walks the dialog list as a
+//! fallback and rescans only the chats whose newest message differs from the recorded
+//! one, e.g. because updates were missed while the connection was down. Edits and
+//! deletions of older messages leave the newest one alone, so every `FULL_SCAN_EVERY`
+//! passes all chats are scanned again regardless. Chats that are no longer in the dialog
+//! list, because they were left or deleted, lose their folder once a walk completes.
+//!
+//! Directories made by `mkdir` are marker messages (see the `tree` module) and are
+//! picked up by the same scans and updates as files. Forums are rescanned along with their
+//! list of topics whenever a topic is created or renamed, see the `topics` module. Parts of
+//! split files are kept aside for reading their manifest's file, see the `chunks` module.
+//! Encrypted names and directories in captions are decrypted as messages are read, see the
+//! `crypt` module. Chats left out by the `[chats]` section of the configuration are skipped,
+//! see the `config` module.
+//!
+//! Bots cannot list dialogs or read history, so under a bot account chats are found and
+//! scanned differently, see the `bot` module.
+//!
+//! Whenever a folder gains, loses or changes a file or directory, the kernel is told to drop the
+//! entries and attributes it cached for it, so directory listings and programs
+//! watching the mount see the change right away instead of after the attribute TTL.
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::{Duration, SystemTime};
+
+use fuser::Notifier;
+use grammers_client::grammers_tl_types as tl;
+use grammers_client::client::messages::MessageIter;
+use grammers_client::types::{Chat, Media, Message, MessageDeletion, PackedChat};
+use grammers_client::{Client, InvocationError, Update};
+
+use crate::bot::{self, IdScan};
+use crate::chat_folders::{self, ChatFolder, DialogState};
+use crate::chat_names::{dir_name, BY_ID_DIR};
+use crate::chunks::{self, is_part};
+use crate::config::{ChatFilter, ChatRef, Listing};
+use crate::crypt::{self, Crypt};
+use crate::dedup::{self, Links};
+use crate::inodes::{InodeKey, InodeTable};
+use crate::local_names::LocalNames;
+use crate::meta::Meta;
+use crate::naming::{dedup_names, file_name, NamingRules};
+use crate::topics::{changes_topics, fetch_topics, in_topic, is_forum, topic_dirs};
+use crate::tree::{self, rebuild_dirs, split};
+use crate::{file_attr, file_content, message_times, next_folder_version, CachedDir, CachedFile, CachedFolder, FileContent, Owner};
+
+// How long to wait before asking for updates again after the update stream failed
+const UPDATE_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+// Every this many reconciliation passes, all chats are scanned again in full
+const FULL_SCAN_EVERY: u32 = 12;
+// The same for bots, whose scans take many more requests: about once a day at the default interval
+const BOT_FULL_SCAN_EVERY: u32 = 288;
+
+/* State shared between the cache updater tasks:
+- the Telegram client and the caches it fills (`cache`, `chats`, `moderated`, `inodes`), and
+  the index of files sharing an inode it keeps along with `cache` (`links`)
+- the names of files renamed locally (`names`)
+- messages whose content changed or went away (`invalidated`), so the filesystem
+  can drop their downloaded blocks
+- the channel to the kernel for cache invalidations, set once the filesystem is mounted
+- the owner reported for new files
+- the keys for encrypted files and names, if encryption is enabled (`crypt`)
+- which chats get a folder (`chat_filter`) and how files without a name are named (`naming`)
+- whether the client is a bot, which finds its chats and their messages differently (`bot`)
+- the inode of the directory the chat folders are in (`root`), and whether it has a `by-id`
+  directory (`by_id`), whose name no chat folder may take
+- the newest message id seen per scanned chat (`last_seen`); for bots, the highest id asked
+  for, which may belong to another chat
+- where each chat is in the chat list (`dialog_states`) and the chat folders of the account
+  (`chat_folders`), which `chat_filter` may choose chats by*/
+pub struct Updater {
+    pub client: Client,
+    pub cache: Arc<RwLock<HashMap<String, CachedFolder>>>,
+    pub chats: Arc<RwLock<HashMap<String, PackedChat>>>,
+    pub moderated: Arc<RwLock<HashSet<i64>>>,
+    pub links: Arc<Links>,
+    pub inodes: Arc<Mutex<InodeTable>>,
+    pub names: Arc<Mutex<LocalNames>>,
+    pub invalidated: Arc<Mutex<Vec<(i64, i32)>>>,
+    pub notifier: Arc<OnceLock<Notifier>>,
+    pub owner: Owner,
+    pub crypt: Option<Arc<Crypt>>,
+    pub chat_filter: ChatFilter,
+    pub naming: NamingRules,
+    pub bot: bool,
+    pub root: u64,
+    pub by_id: bool,
+    pub last_seen: Mutex<HashMap<i64, i32>>,
+    pub dialog_states: Mutex<HashMap<i64, DialogState>>,
+    pub chat_folders: RwLock<Vec<ChatFolder>>,
+}
+
+// Where the messages of a chat being scanned come from
+enum History {
+    // Its history, newest first
+    Messages(MessageIter),
+    // Its message ids, one by one, for bots
+    Ids(IdScan),
+}
+
+impl History {
+    async fn next(&mut self, client: &Client) -> Result<Option<Message>, InvocationError> {
+        match self {
+            Self::Messages(messages) => messages.next().await,
+            Self::Ids(scan) => scan.next(client).await,
+        }
+    }
+}
+
+// Something the kernel may have cached that no longer matches the chat cache
+enum Invalidation {
+    // The entry `name` in directory `parent`, which appeared, disappeared or points elsewhere now
+    Entry(u64, String),
+    // The attributes and content of an inode
+    Inode(u64),
+}
+
+impl Updater {
+    /* Scan all dialogs, then reconcile every `interval`, scanning every chat in full every
+    `FULL_SCAN_EVERY` passes, or `BOT_FULL_SCAN_EVERY` for bots. The first pass is the full initial
+    scan, since no chat has been seen yet.*/
+    pub async fn run_reconciliation(self: Arc<Self>, interval: Duration) {
+        let every = if self.bot { BOT_FULL_SCAN_EVERY } else { FULL_SCAN_EVERY };
+        for pass in 0u32.. {
+            self.reconcile(pass % every == 0).await;
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    // Apply updates from Telegram to the cache as they arrive
+    pub async fn run_updates(self: Arc<Self>) {
+        loop {
+            match self.client.next_update().await {
+                Ok(Update::NewMessage(msg)) | Ok(Update::MessageEdited(msg)) if self.needs_rescan(&msg) => self.rescan(&msg).await,
+                Ok(Update::NewMessage(msg)) | Ok(Update::MessageEdited(msg)) => self.apply_message(&msg),
+                Ok(Update::MessageDeleted(deletion)) => self.apply_deletion(&deletion),
+                Ok(_) => {}
+                Err(e) => {
+                    log::warn!("failed to receive updates: {e}");
+                    tokio::time::sleep(UPDATE_RETRY_DELAY).await;
+                }
+            }
+        }
+    }
+
+    // Bring every chat up to date, see `reconcile_dialogs` and `reconcile_bot`; `full` scans every chat
+    async fn reconcile(&self, full: bool) {
+        if self.bot {
+            self.reconcile_bot(full).await;
+        } else {
+            self.reconcile_dialogs(full).await;
+        }
+
+        // Keep the inodes handed out during this pass for the next mount
+        if let Err(e) = self.inodes.lock().unwrap().save() {
+            log::error!("failed to save inode table: {e}");
+        }
+    }
+
+    /* Walk the dialog list and rescan every chat whose newest message is not the one
+    recorded for it, or every chat if `full`. On the first pass nothing is recorded yet, so
+    every chat is scanned. Folders of chats the completed walk did not come across are dropped.*/
+    async fn reconcile_dialogs(&self, full: bool) {
+        if self.chat_filter.uses_folders() {
+            match chat_folders::fetch(&self.client).await {
+                Ok(folders) => *self.chat_folders.write().unwrap() = folders,
+                Err(e) => log::error!("failed to fetch chat folders: {e}"),
+            }
+        }
+
+        // Only folders there before the walk can be missing from it; chats made meanwhile may have been passed
+        let known: Vec<i64> = self.cache.read().unwrap().values().map(|folder| folder.chat_id).collect();
+        let mut seen = HashSet::new();
+
+        // Iterate over all Telegram dialogs (chats, channels, groups)
+        let mut dialogs = self.client.iter_dialogs();
+
+        // Process each dialog one by one
+        loop {
+            let dialog = match dialogs.next().await {
+                Ok(Some(dialog)) => dialog,
+                Ok(None) => break,
+                Err(e) => {
+                    log::error!("failed to list dialogs: {e}");
+                    return;
+                }
+            };
+            seen.insert(dialog.chat().id());
+            self.dialog_states.lock().unwrap().insert(dialog.chat().id(), DialogState::of(&dialog));
+            if !self.allows(dialog.chat()) {
+                // Chats can leave the filter, e.g. when they are archived or moved out of a chat folder
+                self.forget_chat(dialog.chat().id());
+                continue;
+            }
+
+            let name = self.remember_chat(dialog.chat());
+
+            // Chats without new messages since they were last seen are up to date
+            let chat_id = dialog.chat().id();
+            let newest = dialog.last_message.as_ref().map(|msg| msg.id());
+            if !full && newest.is_some() && self.last_seen.lock().unwrap().get(&chat_id).copied() == newest {
+                continue;
+            }
+
+            let last_date = dialog.last_message.as_ref().map(|msg| SystemTime::from(msg.date()));
+            if let Err(e) = self.scan_chat(dialog.chat(), last_date).await {
+                log::error!("failed to scan chat {name}: {e}");
+            }
+        }
+
+        // Chats that were left or deleted are no longer in the dialog list
+        for chat_id in known.into_iter().filter(|chat_id| !seen.contains(chat_id)) {
+            self.forget_chat(chat_id);
+        }
+    }
+
+    /* Bring every chat of a bot up to date: those listed by id in the `[chats]` section, which are
+    looked up until the bot is a member, and those updates came from. Chats not scanned yet, and
+    every chat if `full`, are scanned in full; the others only get the messages above the newest
+    one seen, see `catch_up`. Bots are not told about every deletion, which only full scans find.*/
+    async fn reconcile_bot(&self, full: bool) {
+        let mut chats: Vec<PackedChat> = self.chat_filter.include.iter().filter_map(ChatRef::packed).collect();
+        chats.extend(self.chats.read().unwrap().values().copied());
+        chats.sort_by_key(|packed| packed.id);
+        chats.dedup_by_key(|packed| packed.id);
+        for packed in chats {
+            let chat = match self.client.unpack_chat(packed).await {
+                Ok(chat) => chat,
+                Err(e) => {
+                    log::warn!("failed to look up chat {}, is the bot a member? {e}", packed.id);
+                    continue;
+                }
+            };
+            if !self.allows(&chat) {
+                continue;
+            }
+            let seen = self.last_seen.lock().unwrap().get(&chat.id()).copied();
+            let result = match seen {
+                Some(seen) if !full => self.catch_up(&chat, seen).await,
+                _ => self.scan_chat(&chat, None).await,
+            };
+            if let Err(e) = result {
+                log::error!("failed to scan chat {}: {e}", chat.name());
+            }
+        }
+    }
+
+    /* Apply the messages of `chat` above `seen`, the newest id seen in it, as a bot: it misses
+    no update as long as it stays connected, so this only finds what came in while it was not.*/
+    async fn catch_up(&self, chat: &Chat, seen: i32) -> Result<(), InvocationError> {
+        let top = self.bot_top_id(chat).await;
+        let mut scan = IdScan::new(chat.pack(), top, seen);
+        while let Some(msg) = scan.next(&self.client).await? {
+            self.apply_message(&msg);
+        }
+        // Ids up to `top` are taken, in this chat or another of the bot's
+        let mut last_seen = self.last_seen.lock().unwrap();
+        let newest = last_seen.entry(chat.id()).or_insert(seen);
+        *newest = (*newest).max(top);
+        Ok(())
+    }
+
+    // Whether `chat` gets a folder, see `ChatFilter`
+    fn allows(&self, chat: &Chat) -> bool {
+        let state = self.dialog_states.lock().unwrap().get(&chat.id()).copied().unwrap_or_default();
+        let folders = self
+            .chat_folders
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|folder| folder.contains(chat, &state))
+            .map(|folder| folder.title.clone())
+            .collect();
+        self.chat_filter.allows(chat, &Listing { archived: state.archived, folders })
+    }
+
+    // Drop the folder of a chat that is no longer shown; it keeps its inode should it come back
+    fn forget_chat(&self, chat_id: i64) {
+        let mut cache = self.cache.write().unwrap();
+        let Some(name) = cache.iter().find(|(_, folder)| folder.chat_id == chat_id).map(|(name, _)| name.clone()) else {
+            return;
+        };
+        if let Some(folder) = cache.remove(&name) {
+            self.links.replace(&folder.files, &[]);
+        }
+        drop(cache);
+        self.chats.write().unwrap().remove(&name);
+        self.last_seen.lock().unwrap().remove(&chat_id);
+        self.notify(vec![Invalidation::Entry(self.root, name), Invalidation::Inode(self.root)]);
+    }
+
+    /* Remember how to reach `chat`, so files can be uploaded into it, and whether this account moderates it.
+    Returns the name of its folder, see the `chat_names` module; when that changed, e.g. because
+    the chat was renamed, the folder moves to the new name.*/
+    fn remember_chat(&self, chat: &Chat) -> String {
+        let mut chats = self.chats.write().unwrap();
+        let name = dir_name(&chats, chat, self.by_id.then_some(BY_ID_DIR));
+        let old = chats.iter().find(|(old, packed)| packed.id == chat.id() && **old != name).map(|(old, _)| old.clone());
+        if let Some(old) = &old {
+            chats.remove(old);
+        }
+        chats.insert(name.clone(), chat.pack());
+        drop(chats);
+        if let Some(old) = old {
+            self.move_folder(&old, &name);
+        }
+
+        if can_delete_others(chat) {
+            self.moderated.write().unwrap().insert(chat.id());
+        } else {
+            self.moderated.write().unwrap().remove(&chat.id());
+        }
+        name
+    }
+
+    // Show the folder `old` as `new`; it keeps its inode, so what is open in it stays usable
+    fn move_folder(&self, old: &str, new: &str) {
+        let mut cache = self.cache.write().unwrap();
+        let Some(folder) = cache.remove(old) else {
+            return;
+        };
+        log::info!("chat folder {old} is now {new}");
+        cache.insert(new.to_string(), folder);
+        drop(cache);
+        self.notify(vec![
+            Invalidation::Entry(self.root, old.to_string()),
+            Invalidation::Entry(self.root, new.to_string()),
+            Invalidation::Inode(self.root),
+        ]);
+    }
+
+    /* Whether `msg` needs its whole chat scanned again rather than being applied on its own:
+    when it changed the topics of a forum, or is the first file of a forum without a folder yet,
+    as the folder would miss its topic directories. A bot scans every chat it first hears from.*/
+    fn needs_rescan(&self, msg: &Message) -> bool {
+        let chat = msg.chat();
+        if self.bot {
+            return self.allows(&chat) && !self.last_seen.lock().unwrap().contains_key(&chat.id());
+        }
+        if !is_forum(&chat) || !self.allows(&chat) {
+            return false;
+        }
+        let has_folder = self.cache.read().unwrap().values().any(|folder| folder.chat_id == chat.id());
+        changes_topics(msg) || (!has_folder && (msg.media().is_some() || tree::marker_path(msg).is_some()))
+    }
+
+    // Scan the chat of `msg` again, see `needs_rescan`
+    async fn rescan(&self, msg: &Message) {
+        let chat = msg.chat();
+        if self.bot {
+            // The scan goes at least as far as this message
+            self.last_seen.lock().unwrap().insert(chat.id(), msg.id());
+        }
+        if let Err(e) = self.scan_chat(&chat, Some(SystemTime::from(msg.date()))).await {
+            log::error!("failed to scan chat {}: {e}", chat.name());
+            if self.bot {
+                self.last_seen.lock().unwrap().remove(&chat.id());
+            }
+        }
+        if let Err(e) = self.inodes.lock().unwrap().save() {
+            log::error!("failed to save inode table: {e}");
+        }
+    }
+
+    /* Rebuild the folder of one chat from all of its messages, and its topics if it is a forum.
+    `last_date` is the date of the newest message in the chat, if known.*/
+    async fn scan_chat(&self, chat: &Chat, last_date: Option<SystemTime>) -> Result<(), InvocationError> {
+        let chat_id = chat.id();
+        let name = self.remember_chat(chat);
+        let name = name.as_str();
+        // Bots cannot list the topics of a forum
+        let topics = if self.bot { vec![] } else { fetch_topics(&self.client, chat).await? };
+        let topics = topic_dirs(chat_id, &topics, &mut self.inodes.lock().unwrap());
+        let mut files = vec![];
+        let mut markers = vec![];
+        let mut parts = HashMap::new();
+        let mut newest = 0;
+
+        // Iterate over all messages in the dialog
+        let mut messages = if self.bot {
+            let top = self.bot_top_id(chat).await;
+            // Ids up to `top` are taken, in this chat or another of the bot's
+            newest = top;
+            History::Ids(IdScan::new(chat.pack(), top, 0))
+        } else {
+            History::Messages(self.client.iter_messages(chat))
+        };
+        while let Some(msg) = messages.next(&self.client).await? {
+            newest = newest.max(msg.id());
+            // Check if message contains media (file/photo/video/etc.) that can be exposed as a file
+            if is_part(&msg) {
+                parts.extend(msg.media().map(|media| (msg.id(), Arc::new(media))));
+            } else if let Some(file) = self.cached_file(chat_id, &msg, &topics) {
+                files.push(file);
+            } else if let Some(dir) = self.marker_dir(chat_id, &msg, &topics) {
+                markers.push(dir);
+            }
+        }
+        self.last_seen.lock().unwrap().insert(chat_id, newest);
+
+        // If there are files found in this dialog, update the cache with them. Chats without
+        // files only get a folder if they had one before, e.g. because it was made by `mkdir`.
+        let had_folder = self.inodes.lock().unwrap().contains(&InodeKey::Chat(chat_id));
+        if files.is_empty() && markers.is_empty() && !had_folder {
+            return Ok(());
+        }
+        dedup_names(&mut files, &self.naming);
+        // The folder was last modified when the latest message arrived
+        let mtime = last_date
+            .or_else(|| files.iter().map(|f| f.attr.mtime).max())
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        // Without an inode the chat cannot be shown, see `InodeTable::allocate`
+        let Some(ino) = self.inodes.lock().unwrap().get_or_assign(InodeKey::Chat(chat_id)) else {
+            return Ok(());
+        };
+        let mut dirs = topics;
+        dirs.extend(markers);
+        let mut folder = CachedFolder { ino, chat_id, files, dirs, parts, mtime, version: next_folder_version() };
+        rebuild_dirs(&mut folder, &mut self.inodes.lock().unwrap());
+
+        let mut cache = self.cache.write().unwrap();
+        self.links.replace(cache.get(name).map_or(&[][..], |old| &old.files), &folder.files);
+        let changes = match cache.get(name) {
+            Some(old) => {
+                // Content of messages that disappeared or changed their media is no longer valid
+                for old_file in &old.files {
+                    let current = folder.files.iter().find(|f| f.message_id == old_file.message_id);
+                    if current.is_none_or(|f| media_id(&f.content) != media_id(&old_file.content)) {
+                        self.invalidated.lock().unwrap().push((chat_id, old_file.message_id));
+                    }
+                }
+                folder_changes(old, &folder, self.root)
+            }
+            None => vec![Invalidation::Entry(self.root, name.to_string()), Invalidation::Inode(self.root)],
+        };
+        cache.insert(name.to_string(), folder);
+        drop(cache);
+
+        self.notify(changes);
+        Ok(())
+    }
+
+    /* The newest message id of `chat` to scan it from as a bot: the highest of what Telegram
+    tells and what this mount has seen in the chat. Outside channels and supergroups, message ids
+    count up across all chats of the bot, so the newest one seen in any of those counts as well.*/
+    async fn bot_top_id(&self, chat: &Chat) -> i32 {
+        let told = match bot::top_message_id(&self.client, chat.pack()).await {
+            Ok(top) => top.unwrap_or(0),
+            Err(e) => {
+                log::warn!("failed to look up the newest message of {}: {e}", chat.name());
+                0
+            }
+        };
+        let mut sharing = vec![chat.id()];
+        if !chat.pack().is_channel() {
+            sharing.extend(self.chats.read().unwrap().values().filter(|packed| !packed.is_channel()).map(|packed| packed.id));
+        }
+        let last_seen = self.last_seen.lock().unwrap();
+        sharing.iter().filter_map(|chat_id| last_seen.get(chat_id).copied()).fold(told, i32::max)
+    }
+
+    // Add, replace or remove the file or directory of a new or edited message
+    fn apply_message(&self, msg: &Message) {
+        let chat = msg.chat();
+        if !self.allows(&chat) {
+            return;
+        }
+        let name = self.remember_chat(&chat);
+        let chat_id = chat.id();
+        if let Some(newest) = self.last_seen.lock().unwrap().get_mut(&chat_id) {
+            *newest = (*newest).max(msg.id());
+        }
+        // Files and directories of forums go into the directory of their topic
+        let topics: Vec<CachedDir> = self
+            .cache
+            .read()
+            .unwrap()
+            .values()
+            .find(|folder| folder.chat_id == chat_id)
+            .map(|folder| folder.dirs.iter().filter(|d| d.topic.is_some()).cloned().collect())
+            .unwrap_or_default();
+        let file = self.cached_file(chat_id, msg, &topics);
+        let marker = self.marker_dir(chat_id, msg, &topics);
+        let part = msg.media().filter(|_| is_part(msg));
+
+        let mut cache = self.cache.write().unwrap();
+        let mut changes = vec![];
+        let existing = cache.iter().find(|(_, folder)| folder.chat_id == chat_id).map(|(name, _)| name.clone());
+        let folder = match (existing, file.is_some() || marker.is_some() || part.is_some()) {
+            (Some(name), _) => cache.get_mut(&name).unwrap(),
+            // A chat that had no files yet gets its folder with the first one
+            (None, true) => {
+                let Some(ino) = self.inodes.lock().unwrap().get_or_assign(InodeKey::Chat(chat_id)) else {
+                    return;
+                };
+                changes.push(Invalidation::Entry(self.root, name.clone()));
+                cache.entry(name).or_insert(CachedFolder {
+                    ino,
+                    chat_id,
+                    files: vec![],
+                    dirs: vec![],
+                    parts: HashMap::new(),
+                    mtime: SystemTime::UNIX_EPOCH,
+                    version: next_folder_version(),
+                })
+            }
+            _ => return,
+        };
+        let old = folder.clone();
+        folder.touch();
+
+        if let Some(i) = folder.files.iter().position(|f| f.message_id == msg.id()) {
+            let old = folder.files.remove(i);
+            self.links.remove(&old);
+            if file.as_ref().is_none_or(|f| media_id(&f.content) != media_id(&old.content)) {
+                self.invalidated.lock().unwrap().push((chat_id, old.message_id));
+            }
+        }
+        if let Some(file) = file {
+            folder.mtime = folder.mtime.max(file.attr.mtime);
+            self.links.add(&file);
+            folder.files.push(file);
+            dedup_names(&mut folder.files, &self.naming);
+        }
+        match part {
+            Some(media) => folder.parts.insert(msg.id(), Arc::new(media)),
+            None => folder.parts.remove(&msg.id()),
+        };
+        // A marker that was edited into something else no longer makes a directory
+        folder.dirs.retain(|d| d.marker != Some(msg.id()));
+        if let Some(dir) = marker {
+            folder.mtime = folder.mtime.max(dir.mtime);
+            folder.dirs.push(dir);
+        }
+        rebuild_dirs(folder, &mut self.inodes.lock().unwrap());
+        changes.extend(folder_changes(&old, folder, self.root));
+        drop(cache);
+
+        self.notify(changes);
+    }
+
+    // Remove the files and directories of deleted messages
+    fn apply_deletion(&self, deletion: &MessageDeletion) {
+        let chats = self.chats.read().unwrap();
+        let mut cache = self.cache.write().unwrap();
+        let mut changes = vec![];
+        for (name, folder) in cache.iter_mut() {
+            // Deletions outside channels do not say which chat they happened in, but
+            // message ids are unique across all private chats and small groups
+            let in_chat = match deletion.channel_id() {
+                Some(channel_id) => folder.chat_id == channel_id,
+                None => chats.get(name).is_some_and(|chat| !chat.is_channel()),
+            };
+            if !in_chat {
+                continue;
+            }
+            let old = folder.clone();
+            folder.touch();
+            self.links.retain(&mut folder.files, |f| {
+                let deleted = deletion.messages().contains(&f.message_id);
+                if deleted {
+                    self.invalidated.lock().unwrap().push((folder.chat_id, f.message_id));
+                }
+                !deleted
+            });
+            folder.dirs.retain(|d| d.marker.is_none_or(|m| !deletion.messages().contains(&m)));
+            folder.parts.retain(|id, _| !deletion.messages().contains(id));
+            rebuild_dirs(folder, &mut self.inodes.lock().unwrap());
+            changes.extend(folder_changes(&old, folder, self.root));
+        }
+        drop(cache);
+        drop(chats);
+
+        self.notify(changes);
+    }
+
+    /* Tell the kernel to forget what it cached for `changes`.
+    Must be called without holding the cache locks: the kernel may ask the
+    filesystem about the invalidated entries before the call returns.*/
+    fn notify(&self, changes: Vec<Invalidation>) {
+        // Nothing to invalidate before the filesystem is mounted
+        let Some(notifier) = self.notifier.get() else {
+            return;
+        };
+        for change in changes {
+            let result = match &change {
+                Invalidation::Entry(parent, name) => notifier.inval_entry(*parent, OsStr::new(name)),
+                Invalidation::Inode(ino) => notifier.inval_inode(*ino, 0, 0),
+            };
+            if let Err(e) = result {
+                log::warn!("failed to invalidate kernel cache: {e}");
+            }
+        }
+    }
+
+    // The directory `msg` marks as made by `mkdir`, or None if it is not a marker
+    fn marker_dir(&self, chat_id: i64, msg: &Message, topics: &[CachedDir]) -> Option<CachedDir> {
+        let path = tree::normalize(&self.reveal(msg, &tree::marker_path(msg)?));
+        if path.is_empty() {
+            return None;
+        }
+        let path = in_topic(topics, msg, &path);
+        let ino = self.inodes.lock().unwrap().get_or_assign(InodeKey::Dir(chat_id, path.clone()))?;
+        Some(CachedDir {
+            ino,
+            path,
+            marker: Some(msg.id()),
+            topic: None,
+            mtime: SystemTime::from(msg.date()),
+        })
+    }
+
+    /* The cached file for the media in `msg`, or None if it carries nothing to expose.
+    In forums, `topics` are the topic directories the file may go into.*/
+    fn cached_file(&self, chat_id: i64, msg: &Message, topics: &[CachedDir]) -> Option<CachedFile> {
+        // Split files are shown through their manifest, their parts are not files of their own
+        let (content, size) = match chunks::manifest(msg) {
+            Some(manifest) => {
+                let size = manifest.size;
+                (FileContent::Parts(Arc::new(manifest)), size)
+            }
+            None if is_part(msg) => return None,
+            None => msg.media().and_then(file_content)?,
+        };
+        // Use the name given by `mv`, the original document name, or one built from the message ID and media type.
+        // The directory and name from the caption win over the ones only known on this machine.
+        let meta = Meta::parse(msg.text());
+        let names = self.names.lock().unwrap();
+        let (dir, renamed) = match names.get(chat_id, msg.id()) {
+            Some(path) if meta.get("dir").is_none() && meta.get("name").is_none() => {
+                let (dir, name) = split(path);
+                (dir.to_string(), Some(name.to_string()))
+            }
+            _ => (self.reveal(msg, meta.get("dir").unwrap_or_default()), meta.get("name").map(|name| self.reveal(msg, name))),
+        };
+        drop(names);
+        let dir = in_topic(topics, msg, &tree::normalize(&dir));
+        let name = file_name(msg.id(), &content, renamed.as_deref(), &self.naming);
+        // Inode numbers come from the chat and message ids, so they do not change between refreshes.
+        // Files this account stored with the same content share the inode of their digest instead.
+        let digest = Some(&meta).filter(|_| msg.outgoing()).and_then(|meta| meta.get("sha256"));
+        let digest = digest.map(|digest| self.reveal(msg, digest)).filter(|digest| dedup::is_digest(digest));
+        let key = match &digest {
+            Some(digest) => InodeKey::Content(digest.clone()),
+            None => InodeKey::Message(chat_id, msg.id()),
+        };
+        let ino = self.inodes.lock().unwrap().get_or_assign(key)?;
+        // Encrypted files show the size of their plaintext, if they can be decrypted at all, see `reveal`
+        let sealed_size = (meta.get("enc").is_some() && msg.outgoing() && self.crypt.is_some()).then_some(size);
+        // Content cut short has no plaintext size, reading it fails
+        let size = sealed_size.map_or(Some(size), crypt::plain_size).unwrap_or(0);
+        // Create file attributes for this cached file (metadata only, nothing is downloaded here)
+        let attr = file_attr(ino, size, message_times(msg), self.owner);
+        Some(CachedFile {
+            ino,
+            name,
+            chat_id,
+            message_id: msg.id(),
+            dir,
+            outgoing: msg.outgoing(),
+            content,
+            sealed_size,
+            digest,
+            attr,
+        })
+    }
+
+    /* Decrypt a name or path from the caption of `msg`, if it is encrypted and encryption is enabled.
+    Only messages this account sent are decrypted, see the `crypt` module.*/
+    fn reveal(&self, msg: &Message, value: &str) -> String {
+        match &self.crypt {
+            Some(crypt) if msg.outgoing() => crypt.open_name(value),
+            _ => value.to_string(),
+        }
+    }
+}
+
+/* What the kernel has to forget when a chat folder changes from `old` to `new`:
+- names that were added, removed or now belong to something else, in any directory
+- files whose size, timestamps or media changed
+- the directories whose listings changed, the folder itself and `root`, the directory it is in, as their timestamps did*/
+fn folder_changes(old: &CachedFolder, new: &CachedFolder, root: u64) -> Vec<Invalidation> {
+    let old_entries = entries(old);
+    let new_entries = entries(new);
+    let mut changes = vec![];
+    let mut parents = vec![];
+    for (parent, name, ino) in &old_entries {
+        if !new_entries.contains(&(*parent, name, *ino)) {
+            changes.push(Invalidation::Entry(*parent, name.to_string()));
+            parents.push(*parent);
+        }
+    }
+    for (parent, name, ino) in &new_entries {
+        if !old_entries.contains(&(*parent, name, *ino)) {
+            changes.push(Invalidation::Entry(*parent, name.to_string()));
+            parents.push(*parent);
+        }
+    }
+    for file in &old.files {
+        if let Some(current) = new.files.iter().find(|f| f.ino == file.ino)
+            && (current.attr.size != file.attr.size
+                || current.attr.mtime != file.attr.mtime
+                || current.attr.ctime != file.attr.ctime
+                || media_id(&current.content) != media_id(&file.content))
+        {
+            changes.push(Invalidation::Inode(file.ino));
+        }
+    }
+
+    if !changes.is_empty() {
+        parents.sort();
+        parents.dedup();
+        parents.retain(|&ino| ino != new.ino);
+        changes.extend(parents.into_iter().map(Invalidation::Inode));
+        changes.push(Invalidation::Inode(new.ino));
+        changes.push(Invalidation::Inode(root));
+    }
+    changes
+}
+
+// Every name in the tree of `folder`, as the inode of its directory, the name and its own inode
+fn entries(folder: &CachedFolder) -> Vec<(u64, &str, u64)> {
+    let parent = |path: &str| folder.dir_ino(path).unwrap_or(folder.ino);
+    let files = folder.files.iter().map(|f| (parent(&f.dir), f.name.as_str(), f.ino));
+    let dirs = folder.dirs.iter().map(|d| {
+        let (dir, name) = split(&d.path);
+        (parent(dir), name, d.ino)
+    });
+    files.chain(dirs).collect()
+}
+
+// Whether this account may delete messages sent by others in `chat` for everyone
+fn can_delete_others(chat: &Chat) -> bool {
+    match chat {
+        // Either side of a private chat can delete the whole conversation
+        Chat::User(_) => true,
+        Chat::Group(group) => match &group.raw {
+            tl::enums::Chat::Chat(chat) => chat.creator || chat.admin_rights.is_some(),
+            tl::enums::Chat::Channel(channel) => {
+                channel.creator
+                    || matches!(&channel.admin_rights, Some(tl::enums::ChatAdminRights::Rights(r)) if r.delete_messages)
+            }
+            _ => false,
+        },
+        Chat::Channel(channel) => channel.admin_rights().is_some_and(|r| r.delete_messages),
+    }
+}
+
+// Identifies the media behind a file, to tell whether an edit replaced it
+fn media_id(content: &FileContent) -> Option<i64> {
+    match content {
+        FileContent::Remote(media) => match media.as_ref() {
+            Media::Photo(photo) => Some(photo.id()),
+            Media::Document(document) => Some(document.id()),
+            Media::Sticker(sticker) => Some(sticker.document.id()),
+            _ => None,
+        },
+        FileContent::Inline(_) | FileContent::Parts(_) => None,
+    }
+}
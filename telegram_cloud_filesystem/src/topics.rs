@@ -0,0 +1,150 @@
+//! Forum topics as directories.
+//!
+//! Supergroups with topics enabled show every topic as a top-level directory of their chat
+//! folder, named after the topic, and `iter_messages` results are sorted into them by the
+//! topic each message was posted in. Files and directories inside a topic keep their
+//! paths relative to the topic's directory in captions and markers (see the `tree`
+//! module), so renaming a topic in Telegram moves everything along with it.
+//!
+//! Writing into a topic's directory posts into that topic, and `mkdir` directly in the chat
+//! folder of a forum creates a new topic. Messages cannot move between topics, so moving a
+//! file to another topic re-sends it, like moving it to another chat.
+
+use std::collections::HashSet;
+use std::time::UNIX_EPOCH;
+
+use grammers_client::grammers_tl_types as tl;
+use grammers_client::types::{Chat, Message};
+use grammers_client::{Client, InvocationError};
+
+use crate::inodes::{InodeKey, InodeTable};
+use crate::naming::{cut, sanitize, MAX_NAME_LEN};
+use crate::tree::join;
+use crate::CachedDir;
+
+// Every forum has this topic; messages posted outside any other topic belong to it
+pub const GENERAL_TOPIC: i32 = 1;
+
+// How many topics to ask for per request
+const TOPICS_PAGE: i32 = 100;
+
+// Whether `chat` is a supergroup with topics enabled
+pub fn is_forum(chat: &Chat) -> bool {
+    match chat {
+        Chat::Group(group) => matches!(&group.raw, tl::enums::Chat::Channel(channel) if channel.forum),
+        _ => false,
+    }
+}
+
+// The topic `msg` was posted in; only meaningful in forums
+pub fn topic_id(msg: &Message) -> i32 {
+    match msg.reply_header() {
+        Some(tl::enums::MessageReplyHeader::Header(header)) if header.forum_topic => {
+            header.reply_to_top_id.or(header.reply_to_msg_id).unwrap_or(GENERAL_TOPIC)
+        }
+        _ => GENERAL_TOPIC,
+    }
+}
+
+// Whether `msg` created a topic or changed the title of one
+pub fn changes_topics(msg: &Message) -> bool {
+    match msg.action() {
+        Some(tl::enums::MessageAction::TopicCreate(_)) => true,
+        Some(tl::enums::MessageAction::TopicEdit(edit)) => edit.title.is_some(),
+        _ => false,
+    }
+}
+
+// The message to reply to for posting into `topic`; the general topic takes plain messages
+pub fn reply_to(topic: Option<i32>) -> Option<i32> {
+    topic.filter(|&topic| topic != GENERAL_TOPIC)
+}
+
+/* The path below the chat folder of `path`, which is relative to the topic `msg` was posted in.
+Chats without topics, and messages in a topic not listed in `topics`, keep `path` as it is.*/
+pub fn in_topic(topics: &[CachedDir], msg: &Message, path: &str) -> String {
+    let topic = topic_id(msg);
+    match topics.iter().find(|d| d.topic == Some(topic)) {
+        Some(dir) => join(&dir.path, path),
+        None => path.to_string(),
+    }
+}
+
+/* List the topics of `chat` as pairs of id and title.
+Chats that are not forums have no topics.*/
+pub async fn fetch_topics(client: &Client, chat: &Chat) -> Result<Vec<(i32, String)>, InvocationError> {
+    let Some(channel) = chat.pack().try_to_input_channel().filter(|_| is_forum(chat)) else {
+        return Ok(vec![]);
+    };
+
+    let mut topics = vec![];
+    let (mut offset_date, mut offset_id, mut offset_topic) = (0, 0, 0);
+    loop {
+        let request = tl::functions::channels::GetForumTopics {
+            channel: channel.clone(),
+            q: None,
+            offset_date,
+            offset_id,
+            offset_topic,
+            limit: TOPICS_PAGE,
+        };
+        let tl::enums::messages::ForumTopics::Topics(page) = client.invoke(&request).await?;
+        let fetched = page.topics.len();
+        let mut last = None;
+        for topic in page.topics {
+            // Deleted topics only keep their id
+            if let tl::enums::ForumTopic::Topic(topic) = topic {
+                last = Some((topic.id, topic.top_message));
+                topics.push((topic.id, topic.title));
+            }
+        }
+
+        let Some((id, top_message)) = last else { break };
+        if fetched < TOPICS_PAGE as usize || topics.len() >= page.count as usize {
+            break;
+        }
+        // The next page starts after the latest message of the last topic on this one
+        offset_topic = id;
+        offset_id = top_message;
+        offset_date = page
+            .messages
+            .iter()
+            .find_map(|m| match m {
+                tl::enums::Message::Message(m) if m.id == top_message => Some(m.date),
+                tl::enums::Message::Service(m) if m.id == top_message => Some(m.date),
+                _ => None,
+            })
+            .unwrap_or(0);
+    }
+    Ok(topics)
+}
+
+/* The directories for the topics of the chat `chat_id`.
+Titles are made filesystem-safe, and topics with the same title as an older one get their id appended.*/
+pub fn topic_dirs(chat_id: i64, topics: &[(i32, String)], inodes: &mut InodeTable) -> Vec<CachedDir> {
+    let mut topics = topics.to_vec();
+    topics.sort();
+    let mut taken = HashSet::new();
+    let mut dirs = vec![];
+    for (id, title) in &topics {
+        let name = sanitize(title).unwrap_or_else(|| format!("Topic {id}"));
+        let path = match taken.insert(name.clone()) {
+            true => name,
+            false => {
+                let suffix = format!(" ({id})");
+                format!("{}{suffix}", cut(&name, MAX_NAME_LEN - suffix.len()))
+            }
+        };
+        let Some(ino) = inodes.get_or_assign(InodeKey::Dir(chat_id, path.clone())) else {
+            continue;
+        };
+        dirs.push(CachedDir {
+            ino,
+            path,
+            marker: None,
+            topic: Some(*id),
+            mtime: UNIX_EPOCH,
+        });
+    }
+    dirs
+}
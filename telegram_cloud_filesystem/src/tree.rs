@@ -0,0 +1,93 @@
+//! Directories inside chat folders.
+//!
+//! The tree is kept in Telegram, so every mount of the account sees the same one:
+//! a file's directory is stored in the caption of its message (`dir=photos/2024`, see
+//! the `meta` module), and a directory made by `mkdir` is a text message of its own,
+//! a marker whose caption is only the metadata line:
+//!
+//! ```text
+//! tgfs: mkdir=photos/2024
+//! ```
+//!
+//! A directory exists while it has a marker or anything is stored below it; `rmdir`
+//! deletes the marker. Paths are relative to the chat folder, which is the empty path.
+//! In forums, the paths in captions and markers are relative to the directory of the
+//! message's topic instead, see the `topics` module.
+
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+use grammers_client::types::Message;
+
+use crate::inodes::{InodeKey, InodeTable};
+use crate::meta::Meta;
+use crate::{CachedDir, CachedFolder};
+
+// Path of `name` inside the directory at `dir`
+pub fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{dir}/{name}")
+    }
+}
+
+// Split a path into the path of its directory and its last component
+pub fn split(path: &str) -> (&str, &str) {
+    path.rsplit_once('/').unwrap_or(("", path))
+}
+
+// Drop empty, `.` and `..` components, so a path from a caption cannot escape its chat folder
+pub fn normalize(path: &str) -> String {
+    let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty() && *c != "." && *c != "..").collect();
+    components.join("/")
+}
+
+// The directory `msg` marks as made by `mkdir`, if it is a marker
+pub fn marker_path(msg: &Message) -> Option<String> {
+    if msg.media().is_some() {
+        return None;
+    }
+    let meta = Meta::parse(msg.text());
+    let path = normalize(meta.get("mkdir")?);
+    (meta.text.is_empty() && !path.is_empty()).then_some(path)
+}
+
+// Caption of the marker message for the directory at `path`
+pub fn marker_caption(path: &str) -> String {
+    let mut meta = Meta::default();
+    meta.set("mkdir", Some(path));
+    meta.to_caption()
+}
+
+/* Work out the directories of `folder` from its markers, topics and files.
+Directories with a marker and topic directories are kept; every directory holding a file or another
+directory is added, and the ones that no longer hold anything are dropped.
+Each directory is as recent as the latest thing stored below it.*/
+pub fn rebuild_dirs(folder: &mut CachedFolder, inodes: &mut InodeTable) {
+    let mut dirs: BTreeMap<String, CachedDir> = folder
+        .dirs
+        .drain(..)
+        .filter(|d| d.marker.is_some() || d.topic.is_some())
+        .map(|d| (d.path.clone(), d))
+        .collect();
+
+    let mut contents: Vec<(String, SystemTime)> = folder.files.iter().map(|f| (f.dir.clone(), f.attr.mtime)).collect();
+    contents.extend(dirs.values().map(|d| (split(&d.path).0.to_string(), d.mtime)));
+    for (path, mtime) in contents {
+        let mut path = path.as_str();
+        while !path.is_empty() {
+            if !dirs.contains_key(path) {
+                // A directory without an inode is left out, see `InodeTable::allocate`
+                let Some(ino) = inodes.get_or_assign(InodeKey::Dir(folder.chat_id, path.to_string())) else {
+                    break;
+                };
+                dirs.insert(path.to_string(), CachedDir { ino, path: path.to_string(), marker: None, topic: None, mtime });
+            }
+            let dir = dirs.get_mut(path).unwrap();
+            dir.mtime = dir.mtime.max(mtime);
+            path = split(path).0;
+        }
+    }
+    folder.dirs = dirs.into_values().collect();
+}